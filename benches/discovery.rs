@@ -0,0 +1,73 @@
+//! Pattern discovery against a large synthetic agent file.
+//!
+//! Cursor's agent bundles are several megabytes of minified JS, and the
+//! discovery regexes (some with look-around, under a 10M backtrack limit)
+//! scan all of it. The fixture is filler that looks like minified module
+//! code -- full of `.includes(`, `?.shell`, `case`, `new X(` near-misses --
+//! with the shell-detection block discovery looks for placed at the end, so
+//! every pattern has to walk the whole file before it matches.
+//!
+//! Run with `cargo bench --bench discovery`.
+
+use std::fmt::Write as _;
+use std::hint::black_box;
+
+use criterion::{Criterion, Throughput, criterion_group, criterion_main};
+use nupatch::core::{discover_vars, quick_detect};
+
+/// Size of the synthetic file, in line with the real IDE `main.js`.
+const FIXTURE_BYTES: usize = 8 << 20;
+
+/// The shell-detection code discovery anchors on, as minified by esbuild.
+const AGENT_BLOCK: &str = concat!(
+    r#"function Ie(e){try{return(0,m.findActualExecutable)(e,[]).cmd!==e}catch{return!1}}"#,
+    r#"function dst(e){return e.includes("zsh")?O.Zsh:e.includes("bash")?O.Bash:"#,
+    r#"e.includes("pwsh")||e.includes("powershell")?O.PowerShell:Ie("pwsh")?O.PowerShell:O.Naive}"#,
+    r#"function Se(e){switch(e){case O.Zsh:return"/bin/zsh";case O.PowerShell:return Pe();"#,
+    r#"default:return process.env.SHELL||"/bin/sh"}}"#,
+    r#"function ce(t){const s=t?.shell??Se(dst(""));switch(Te(t?.userTerminalHint??"")){"#,
+    r#"case O.Zsh:return new Lz(new Nv(process.cwd(),{shell:s}));default:return null}}"#,
+);
+
+/// Minified-looking module code with the agent block (optionally with the
+/// nu patches applied) at the end.
+fn fixture(patched: bool) -> String {
+    let mut code = String::with_capacity(FIXTURE_BYTES + AGENT_BLOCK.len());
+    let mut i = 0usize;
+    while code.len() < FIXTURE_BYTES {
+        let _ = write!(
+            code,
+            r#"function f{i}(e,t){{if(e.includes("x{i}"))return t?.opts??{{}};switch(e.kind){{case K.A{i}:return new C{i}(e,{{...t,cwd:e.cwd}});case K.B{i}:return t?.shell||null;default:return void 0}}}}var v{i}=f{i}("{i}",{{mode:{}}});"#,
+            i % 7
+        );
+        i += 1;
+    }
+    if patched {
+        code.push_str(
+            &AGENT_BLOCK
+                .replace(r#"e.includes("pwsh")"#, r#"e.includes("nu")?O.Naive:e.includes("pwsh")"#)
+                .replace("t?.shell??", "t?.shell??t?.userTerminalHint??"),
+        );
+    } else {
+        code.push_str(AGENT_BLOCK);
+    }
+    code
+}
+
+fn discovery(c: &mut Criterion) {
+    let mut group = c.benchmark_group("discovery");
+    group.sample_size(10).throughput(Throughput::Bytes(FIXTURE_BYTES as u64));
+    for (name, code) in [("unpatched", fixture(false)), ("patched", fixture(true))] {
+        assert!(discover_vars(&code).is_ok(), "fixture must be recognized as agent code");
+        group.bench_function(format!("discover_vars/{name}"), |b| {
+            b.iter(|| discover_vars(black_box(&code)).is_ok())
+        });
+        group.bench_function(format!("quick_detect/{name}"), |b| {
+            b.iter(|| quick_detect(black_box(&code)).is_some())
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, discovery);
+criterion_main!(benches);
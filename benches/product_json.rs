@@ -0,0 +1,89 @@
+//! product.json checksum loading against a large synthetic install.
+//!
+//! `load_product_checksums` parses all of product.json and then needs only
+//! its `checksums` map. It moves that map out of the parsed value rather
+//! than cloning it (the alternative a streaming or borrowed parser would
+//! improve on), so `parse/take` and `parse/clone` compare the two, and
+//! `verify_checksums` shows what loading costs next to hashing every file.
+//!
+//! Run with `cargo bench --bench product_json`.
+
+use std::fmt::Write as _;
+use std::fs;
+use std::hint::black_box;
+use std::path::PathBuf;
+
+use criterion::{Criterion, Throughput, criterion_group, criterion_main};
+use nupatch::integrity::verify_checksums;
+use serde_json::Value;
+
+/// Checksum entries, several times what a current Cursor ships.
+const ENTRIES: usize = 20_000;
+
+/// product.json with `ENTRIES` checksums (all stale, so verify reports every
+/// one) among the usual top-level keys.
+fn product_json() -> String {
+    let mut checksums = String::new();
+    for i in 0..ENTRIES {
+        let sep = if i == 0 { "" } else { "," };
+        let _ = write!(checksums, "{sep}\n\t\t\"vs/m{}/f{i}.js\": \"{:0>43}\"", i % 64, i);
+    }
+    format!(
+        "{{\n\t\"nameShort\": \"Cursor\",\n\t\"version\": \"1.6.0\",\n\t\"checksums\": {{{checksums}\n\t}},\n\t\
+         \"commit\": \"0123456789abcdef\"\n}}\n"
+    )
+}
+
+/// A Cursor app directory holding product.json and a small file for each
+/// checksum entry. Removed when the bench is done.
+struct App(PathBuf);
+
+impl App {
+    fn new(product: &str) -> Self {
+        let root = std::env::temp_dir().join(format!("nupatch-bench-product-{}", std::process::id()));
+        for i in 0..ENTRIES {
+            let dir = root.join("out").join("vs").join(format!("m{}", i % 64));
+            fs::create_dir_all(&dir).unwrap();
+            fs::write(dir.join(format!("f{i}.js")), format!("module {i}")).unwrap();
+        }
+        fs::write(root.join("product.json"), product).unwrap();
+        Self(root)
+    }
+}
+
+impl Drop for App {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.0);
+    }
+}
+
+fn product(c: &mut Criterion) {
+    let text = product_json();
+    let mut group = c.benchmark_group("product_json");
+    group.sample_size(10).throughput(Throughput::Bytes(text.len() as u64));
+    group.bench_function("parse/take", |b| {
+        b.iter(|| {
+            let mut product: Value = serde_json::from_str(black_box(&text)).unwrap();
+            match product.get_mut("checksums").map(Value::take) {
+                Some(Value::Object(map)) => map.len(),
+                _ => 0,
+            }
+        })
+    });
+    group.bench_function("parse/clone", |b| {
+        b.iter(|| {
+            let product: Value = serde_json::from_str(black_box(&text)).unwrap();
+            product.get("checksums").and_then(Value::as_object).cloned().unwrap_or_default().len()
+        })
+    });
+
+    let app = App::new(&text);
+    let product_json = app.0.join("product.json");
+    group.bench_function("verify_checksums", |b| {
+        b.iter(|| verify_checksums(&product_json, &app.0).unwrap().entries.len())
+    });
+    group.finish();
+}
+
+criterion_group!(benches, product);
+criterion_main!(benches);
@@ -0,0 +1,204 @@
+//! Patching agent files inside a zip or tar(.gz) archive of `resources/app`.
+//!
+//! Entries are read into memory, every `index.js` / `main.js` is run
+//! through the in-memory patch plan (`core::patch_code`), and a new archive
+//! is written with the patched entries swapped in. Everything else is copied
+//! unchanged, with its metadata. The integrity chain isn't applied: it would
+//! have to be rerun after the archive is unpacked into a real install.
+
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
+
+use crate::core::{self, PatchOptions, PatchResult};
+use crate::error::{Error, Result};
+use crate::paths::agent_label;
+
+/// Archive formats `patch --archive` reads and writes, by file extension.
+#[derive(Clone, Copy, PartialEq)]
+pub enum Format {
+    Zip,
+    Tar,
+    TarGz,
+}
+
+const EXTENSIONS: &[(&str, Format)] = &[
+    (".zip", Format::Zip),
+    (".tar.gz", Format::TarGz),
+    (".tgz", Format::TarGz),
+    (".tar", Format::Tar),
+];
+
+impl Format {
+    /// The format of `path` and its file name without the extension.
+    fn split(path: &Path) -> Option<(Self, String)> {
+        let name = path.file_name()?.to_str()?;
+        let lower = name.to_ascii_lowercase();
+        EXTENSIONS
+            .iter()
+            .find(|(ext, _)| lower.ends_with(ext))
+            .map(|&(ext, format)| (format, name[..name.len() - ext.len()].to_string()))
+    }
+
+    pub fn of(path: &Path) -> Option<Self> {
+        Self::split(path).map(|(format, _)| format)
+    }
+}
+
+/// Where a patched copy of `archive` goes by default:
+/// `<name>-patched.<ext>` next to it.
+pub fn default_output(archive: &Path) -> Option<PathBuf> {
+    let (_, stem) = Format::split(archive)?;
+    let ext = &archive.file_name()?.to_str()?[stem.len()..];
+    Some(archive.with_file_name(format!("{stem}-patched{ext}")))
+}
+
+/// The patch result for one agent file in the archive. Files discovery
+/// doesn't recognize as agent code aren't reported.
+pub struct EntryResult {
+    pub name: String,
+    pub label: &'static str,
+    pub result: PatchResult,
+    /// Whether the entry is replaced in the output archive.
+    pub patched: bool,
+}
+
+/// Patch the agent files in `archive` and, unless `opts.dry_run` or nothing
+/// changed, write the result to `out` in the same format.
+pub fn patch_archive(archive: &Path, out: &Path, opts: &PatchOptions) -> Result<Vec<EntryResult>> {
+    let format = Format::of(archive).ok_or_else(|| {
+        Error::Archive(format!("{}: not a .zip, .tar, .tar.gz or .tgz file", archive.display()))
+    })?;
+    let file = File::open(archive).map_err(|e| Error::io("open", archive, e))?;
+    let mut patch = |name: &str, data: &[u8]| -> Option<(EntryResult, Option<Vec<u8>>)> {
+        let label = agent_label(Path::new(name))?;
+        let code = std::str::from_utf8(data).ok()?;
+        let (patched, result) = core::patch_code(label, code, opts);
+        // Not agent code at all: leave it out of the report.
+        if result.steps.iter().any(|s| s.name == "Pattern discovery" && !s.ok) {
+            return None;
+        }
+        let patched = patched.filter(|p| p != code).map(String::into_bytes);
+        let entry = EntryResult { name: name.to_string(), label, result, patched: patched.is_some() };
+        Some((entry, patched))
+    };
+
+    match format {
+        Format::Zip => rewrite_zip(file, archive, out, opts.dry_run, &mut patch),
+        Format::Tar => rewrite_tar(BufReader::new(file), archive, out, opts.dry_run, false, &mut patch),
+        Format::TarGz => {
+            let reader = flate2::read::GzDecoder::new(BufReader::new(file));
+            rewrite_tar(reader, archive, out, opts.dry_run, true, &mut patch)
+        }
+    }
+}
+
+type PatchFn<'a> = dyn FnMut(&str, &[u8]) -> Option<(EntryResult, Option<Vec<u8>>)> + 'a;
+
+fn rewrite_zip(file: File, archive: &Path, out: &Path, dry_run: bool, patch: &mut PatchFn) -> Result<Vec<EntryResult>> {
+    let bad = |e: zip::result::ZipError| Error::Archive(format!("{}: {e}", archive.display()));
+    let mut zip = zip::ZipArchive::new(BufReader::new(file)).map_err(bad)?;
+
+    // First pass: patch in memory, so nothing is written when nothing changes.
+    let mut results = Vec::new();
+    let mut replaced = std::collections::HashMap::new();
+    for i in 0..zip.len() {
+        let mut entry = zip.by_index(i).map_err(bad)?;
+        if entry.is_dir() || agent_label(Path::new(entry.name())).is_none() {
+            continue;
+        }
+        let mut data = Vec::new();
+        entry.read_to_end(&mut data).map_err(|e| Error::io("read", archive, e))?;
+        if let Some((result, patched)) = patch(entry.name(), &data) {
+            if let Some(patched) = patched {
+                replaced.insert(i, patched);
+            }
+            results.push(result);
+        }
+    }
+    if dry_run || replaced.is_empty() {
+        return Ok(results);
+    }
+
+    let dest = File::create(out).map_err(|e| Error::io("create", out, e))?;
+    let mut writer = zip::ZipWriter::new(BufWriter::new(dest));
+    for i in 0..zip.len() {
+        let entry = zip.by_index_raw(i).map_err(bad)?;
+        match replaced.remove(&i) {
+            Some(data) => {
+                let mut options = zip::write::SimpleFileOptions::default()
+                    .compression_method(entry.compression())
+                    .large_file(data.len() as u64 >= u32::MAX as u64);
+                if let Some(modified) = entry.last_modified() {
+                    options = options.last_modified_time(modified);
+                }
+                if let Some(mode) = entry.unix_mode() {
+                    options = options.unix_permissions(mode);
+                }
+                let name = entry.name().to_string();
+                drop(entry);
+                writer.start_file(name, options).map_err(bad)?;
+                writer.write_all(&data).map_err(|e| Error::io("write", out, e))?;
+            }
+            None => writer.raw_copy_file(entry).map_err(bad)?,
+        }
+    }
+    writer.finish().map_err(bad)?;
+    Ok(results)
+}
+
+fn rewrite_tar(
+    reader: impl Read,
+    archive: &Path,
+    out: &Path,
+    dry_run: bool,
+    gzip: bool,
+    patch: &mut PatchFn,
+) -> Result<Vec<EntryResult>> {
+    let read_err = |e| Error::io("read", archive, e);
+    // Tar streams can't be re-read cheaply (gzip), so buffer every entry.
+    let mut entries = Vec::new();
+    let mut results = Vec::new();
+    let mut changed = false;
+    for entry in tar::Archive::new(reader).entries().map_err(read_err)? {
+        let mut entry = entry.map_err(read_err)?;
+        let path = entry.path().map_err(read_err)?.into_owned();
+        let header = entry.header().clone();
+        let mut data = Vec::new();
+        entry.read_to_end(&mut data).map_err(read_err)?;
+        if header.entry_type().is_file()
+            && let Some(name) = path.to_str()
+            && let Some((result, patched)) = patch(name, &data)
+        {
+            if let Some(patched) = patched {
+                data = patched;
+                changed = true;
+            }
+            results.push(result);
+        }
+        entries.push((path, header, data));
+    }
+    if dry_run || !changed {
+        return Ok(results);
+    }
+
+    let write_err = |e| Error::io("write", out, e);
+    let dest = BufWriter::new(File::create(out).map_err(|e| Error::io("create", out, e))?);
+    if gzip {
+        let encoder = flate2::write::GzEncoder::new(dest, flate2::Compression::default());
+        write_tar(encoder, entries).and_then(|gz| gz.finish()).and_then(|mut w| w.flush())
+    } else {
+        write_tar(dest, entries).and_then(|mut w| w.flush())
+    }
+    .map_err(write_err)?;
+    Ok(results)
+}
+
+fn write_tar<W: Write>(dest: W, entries: Vec<(PathBuf, tar::Header, Vec<u8>)>) -> std::io::Result<W> {
+    let mut builder = tar::Builder::new(dest);
+    for (path, mut header, data) in entries {
+        header.set_size(data.len() as u64);
+        builder.append_data(&mut header, &path, data.as_slice())?;
+    }
+    builder.into_inner()
+}
@@ -0,0 +1,138 @@
+//! `bug-report`: everything nupatch can find out about the install, as one
+//! JSON document to attach to an issue.
+//!
+//! Collection never fails: a file that can't be read or code that isn't
+//! recognized shows up as an `error` in the bundle, since those are exactly
+//! the cases a bug report is for.
+//!
+//! Since the bundle is meant to be shared, `redact` can take the user's home
+//! directory (and so their user name) out of it first.
+
+use std::fs;
+use std::path::Path;
+
+use serde_json::{Value, json};
+use sha2::{Digest, Sha256};
+
+use crate::core::{check_status, discover_code, install_info, patch_flags};
+use crate::history;
+use crate::paths::{Channel, CursorPaths, home_dir};
+use crate::report::status_json;
+
+/// Most recent history entries included.
+const HISTORY_TAIL: usize = 20;
+
+/// Collect the bundle for the install at `paths`.
+pub fn collect(paths: &CursorPaths) -> Value {
+    let info = install_info(paths);
+    let status = check_status(paths);
+    json!({
+        "nupatch": env!("CARGO_PKG_VERSION"),
+        "os": info.os,
+        "arch": info.arch,
+        "cursor": {
+            "version": info.cursor.version,
+            "commit": info.cursor.commit,
+            "cli_version": info.cli_version,
+        },
+        "paths": {
+            "cursor_app": paths.cursor_app,
+            "cli_agent_dir": paths.cli_agent_dir,
+            "cli_index": paths.cli_index,
+            "ide_main": paths.ide_main,
+            "ehp": paths.ehp,
+            "product_json": paths.product_json,
+            "packed_asar": paths.packed_asar,
+            "channel": paths.channel.map(Channel::as_str),
+        },
+        "agents": {
+            "cli": agent_json(paths.cli_index.as_deref()),
+            "ide": agent_json(paths.ide_main.as_deref()),
+        },
+        "status": status_json(&status, paths.packed_asar.as_deref()),
+        "history": history_tail(),
+    })
+}
+
+/// Discovery and quick-detection results for one agent file.
+fn agent_json(path: Option<&Path>) -> Value {
+    let Some(path) = path else {
+        return Value::Null;
+    };
+    let code = match fs::read_to_string(path) {
+        Ok(code) => code,
+        Err(e) => return json!({ "error": e.to_string() }),
+    };
+    let discovery = match discover_code(&code) {
+        Ok(vars) => vars.into_iter().map(|(k, v)| (k.to_string(), json!(v))).collect(),
+        Err(e) => json!({ "error": e.to_string() }),
+    };
+    let patches: Option<Value> =
+        patch_flags(&code).map(|flags| flags.into_iter().map(|(k, v)| (k.to_string(), json!(v))).collect());
+    json!({ "size": code.len(), "discovery": discovery, "patches": patches })
+}
+
+/// The last `HISTORY_TAIL` history entries as recorded, oldest first.
+fn history_tail() -> Value {
+    let Some(log) = history::history_path().and_then(|p| history::read(&p).ok()) else {
+        return json!({ "entries": [], "unreadable_lines": 0 });
+    };
+    let skip = log.entries.len().saturating_sub(HISTORY_TAIL);
+    json!({
+        "entries": log.entries.iter().skip(skip).map(|e| e.raw.clone()).collect::<Vec<_>>(),
+        "unreadable_lines": log.unreadable,
+    })
+}
+
+/// Replace the home directory in every string of `bundle` with `~`. With
+/// `hash_versions`, also replace the names of the CLI agent version
+/// directories under `paths.cli_agent_dir` with short hashes (`v-1a2b3c4d`),
+/// which still tell versions apart without naming them.
+pub fn redact(bundle: &mut Value, paths: &CursorPaths, hash_versions: bool) {
+    let mut replacements: Vec<(String, String)> = Vec::new();
+    if let Some(home) = home_dir().and_then(|h| h.to_str().map(|h| h.trim_end_matches(['/', '\\']).to_string()))
+        && !home.is_empty()
+    {
+        replacements.push((home, "~".to_string()));
+    }
+    if hash_versions && let Some(Ok(dirs)) = paths.cli_agent_dir.as_deref().map(fs::read_dir) {
+        for dir in dirs.flatten() {
+            let name = dir.file_name().to_string_lossy().into_owned();
+            let hashed = format!("v-{:x}", Sha256::digest(name.as_bytes()));
+            let hashed = hashed[..10].to_string();
+            replacements.push((name, hashed));
+        }
+    }
+    redact_strings(bundle, &replacements);
+}
+
+fn redact_strings(value: &mut Value, replacements: &[(String, String)]) {
+    match value {
+        Value::String(s) => {
+            for (from, to) in replacements {
+                if s.contains(from.as_str()) {
+                    *s = replace_whole(s, from, to);
+                }
+            }
+        }
+        Value::Array(items) => items.iter_mut().for_each(|v| redact_strings(v, replacements)),
+        Value::Object(map) => map.values_mut().for_each(|v| redact_strings(v, replacements)),
+        _ => {}
+    }
+}
+
+/// Replace `from` in `s` where it is a whole path component run: not
+/// followed by more of a name, so `/home/al` leaves `/home/alice` alone.
+fn replace_whole(s: &str, from: &str, to: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    let mut rest = s;
+    while let Some(at) = rest.find(from) {
+        let end = at + from.len();
+        let whole = !rest[end..].starts_with(|c: char| c.is_alphanumeric() || matches!(c, '.' | '-' | '_'));
+        out.push_str(&rest[..at]);
+        out.push_str(if whole { to } else { from });
+        rest = &rest[end..];
+    }
+    out.push_str(rest);
+    out
+}
@@ -5,17 +5,27 @@
 //! mirroring the Python version's use of the `rich` library.
 
 use std::borrow::Cow;
+use std::fs;
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
 
-use color_eyre::eyre::{Result, eyre};
+use color_eyre::eyre::{Result, WrapErr, eyre};
 use richrs::prelude::*;
 use richrs::segment::Segments;
 use richrs::table::Row;
 
 use crate::core::{
-    PatchResult, StepResult, check_status, patch_cli_agent, patch_ide_agent, revert_all,
+    self, CursorVersion, FailureKind, OverallState, PatchOptions, PatchResult, PatchStatus, StepResult, check_failures,
+    check_status, install_info, patch_cli_agent, patch_ide_agent, read_cursor_version, revert_all,
 };
+use crate::error::Error;
+use crate::history;
 use crate::integrity::{self, update_integrity};
-use crate::paths::detect_paths;
+use crate::manifest::{self, Manifest};
+use crate::paths::{self, CursorPaths, cli_agent_indexes, cli_version_indexes, detect_paths};
+use crate::report::{self, Report};
+use crate::rules::{RuleTarget, load_patch_script, load_rules};
+use crate::version::{Compat, LAST_TESTED_CURSOR, Version, compat_for, is_newer_than_tested};
 
 // ---------------------------------------------------------------------------
 //  help / version
@@ -42,8 +52,41 @@ pub fn cmd_help(version: &str) {
 [bold cyan]patch[/]                Apply nushell patches to Cursor agents.
   [dim]--cli-only[/]          Patch CLI agent only
   [dim]--ide-only[/]          Patch IDE agent only
+  [dim]--cli-all[/]           Patch every CLI agent version (plus the IDE agent)
+  [dim]--cli-recent[/] [cyan]<N>[/]    Patch the N most recent CLI agent versions
+  [dim]--agent-version[/] [cyan]<VER>[/] Patch this CLI agent version instead of the newest
+  [dim]--list-versions[/]     List the installed CLI agent versions
   [dim]-n, --dry-run[/]       Preview changes without applying
-[bold cyan]revert[/]               Restore all patched files from backups.";
+  [dim]--explain[/]           Describe each step and why before it runs
+  [dim]--trace-regex[/]       Log discovery regexes; show code near failed matches
+  [dim]--full-checksums[/]    Recompute every [bold]product.json[/] checksum
+  [dim]--assume-version[/]    Treat Cursor as this version for version checks
+  [dim]--since[/] [cyan]<VER>[/]       Only patch if Cursor is at least this version
+  [dim]--rules[/] [cyan]<FILE>[/]      Add or override patch steps (JSON)
+  [dim]--patch-script[/]      Literal find/replace pairs applied after patching
+  [dim]--no-system-detection[/] Skip the PATH-based nu detection step
+  [dim]--no-user-terminal-hint[/] Ignore the configured terminal in the IDE
+  [dim]--continue-on-error[/] Accept a partial result when steps fail
+  [dim]--force[/]             Write partial results (with --continue-on-error)
+  [dim]--allow-reencode[/]    Write even if the BOM or line endings would change
+  [dim]--file[/] [cyan]<FILE>[/]       Patch a file or stdin ('-') to stdout
+  [dim]--out[/] [cyan]<PATH>[/]        Write the patched agent here, leaving the original
+  [dim]--side-by-side[/]      Write [bold]main.js.patched[/] etc. next to the originals
+  [dim]--diff-tool[/] [cyan]\\[CMD][/]  Open the dry-run change in a diff tool
+  [dim]--emit[/] [cyan]<PATH>[/]       Write the whole dry-run result to a file
+  [dim]--dir[/] [cyan]<DIR>[/]         Patch every index.js / main.js under a directory
+  [dim]--glob[/] [cyan]<PATTERN>[/]    Patch the agent files a glob matches
+  [dim]--archive[/] [cyan]<FILE>[/]    Patch agents in a .zip / .tar(.gz) of resources/app
+  [dim]--notify[/]            Desktop notification when agents are patched
+  [dim]--report[/] [cyan]<FILE>[/]     Save the results and resulting status as JSON
+[bold cyan]revert[/]               Restore all patched files from backups.
+  [dim]-y, --yes[/]           Don't ask for confirmation
+  [dim]--all-versions[/]      Restore every CLI version patched, not just the newest
+[bold cyan]undo[/]                 Reverse the last patch, revert or checksum fix.
+  [dim]-y, --yes[/]           Don't ask for confirmation
+[bold cyan]history[/]              Show the runs that changed the install, newest first.
+  [dim]--limit[/] [cyan]<N>[/]         Show at most N runs
+  [dim]--json[/]              Print the recorded entries as JSON";
     let panel = Panel::new(markup(core))
         .title(markup("[bold]Core Commands[/]"))
         .border_style(Style::parse("cyan").unwrap_or_default());
@@ -52,8 +95,27 @@ pub fn cmd_help(version: &str) {
     // Diagnostics
     let diag = "\
 [bold cyan]status[/]  [dim](s)[/]           Show current patch status for CLI and IDE agents.
+  [dim]--check[/]             Exit-code only (0 = fully patched), for CI
+  [dim]-v, --verbose[/]       Show discovered minified names
+  [dim]--json[/]              Print status as JSON
+  [dim]--watch[/]             Redraw until Ctrl-C ([dim]--interval[/] secs, default 2)
+  [dim]--from-report[/] [cyan]<FILE>[/] Render a saved [dim]--report[/] instead of the install
+[bold cyan]info[/]    [dim](i)[/]           Show nupatch, Cursor, and CLI agent versions.
 [bold cyan]verify[/]  [dim](v)[/]           Verify [bold]product.json[/] checksums against files on disk.
-[bold cyan]fix-checksums[/] [dim](fc)[/]    Recalculate all [bold]product.json[/] checksums.";
+  [dim]--json[/]              Print results as JSON
+  [dim]--cli[/]               Also check the CLI agent against its recorded hash
+  [dim]--include-agents[/]    Add both agents' recorded hashes to the table
+  [dim]--report[/] [cyan]<FILE>[/]     Save the results and current status as JSON
+[bold cyan]diff-checksums[/] [dim](dc)[/]   List only the [bold]product.json[/] checksums that differ.
+[bold cyan]fix-checksums[/] [dim](fc)[/]    Recalculate all [bold]product.json[/] checksums.
+[bold cyan]bug-report[/]           Bundle diagnostics as JSON to attach to an issue.
+  [dim]--out[/] [cyan]<FILE>[/]        Write it to a file instead of stdout
+  [dim]--no-redact[/]         Keep the home directory in paths (default: [bold]~[/])
+  [dim]--hash-versions[/]     Also hash CLI agent version directory names
+[bold cyan]discover[/] [cyan]<FILE>[/]      Show minified names in an agent file ('-' = stdin).
+[bold cyan]compare[/] [cyan]<A> <B>[/]      Compare the patches in two agent files.
+[bold cyan]rules[/]                List the built-in patch steps and what they edit.
+  [dim]--export[/]            Print them as a JSON rules manifest";
     let panel = Panel::new(markup(diag))
         .title(markup("[bold]Diagnostics[/]"))
         .border_style(Style::parse("cyan").unwrap_or_default());
@@ -62,7 +124,13 @@ pub fn cmd_help(version: &str) {
     // Info
     let info = "\
 [bold cyan]--help[/]  [dim](-h)[/]          Display this message and exit.
-[bold cyan]--version[/] [dim](-V)[/]        Display application version.";
+[bold cyan]--version[/] [dim](-V)[/]        Display application version.
+[bold cyan]--trace[/]              Print how long each phase took (any command).
+[bold cyan]--app-path[/] [dim]DIR[/]       Use DIR as Cursor's resources/app, or an extracted
+                     AppImage's squashfs-root (any command).
+                     Else [bold]$NUPATCH_CURSOR_APP[/] if set, else auto-detection.
+[bold cyan]--channel[/] [dim]CH[/]         Only detect [bold]stable[/] or [bold]insiders[/] (Nightly) installs.
+                     Not with --app-path or [bold]$NUPATCH_CURSOR_APP[/].";
     let panel = Panel::new(markup(info))
         .title(markup("[bold]Info[/]"))
         .border_style(Style::parse("cyan").unwrap_or_default());
@@ -98,6 +166,22 @@ fn print_renderable(
     Ok(())
 }
 
+/// Coarse "N units ago" rendering of a timestamp.
+fn relative_time(t: std::time::SystemTime) -> String {
+    let secs = match t.elapsed() {
+        Ok(d) => d.as_secs(),
+        Err(_) => return "just now".to_string(),
+    };
+    let (n, unit) = match secs {
+        0..60 => return "just now".to_string(),
+        60..3600 => (secs / 60, "minute"),
+        3600..86_400 => (secs / 3600, "hour"),
+        _ => (secs / 86_400, "day"),
+    };
+    let plural = if n == 1 { "" } else { "s" };
+    format!("{n} {unit}{plural} ago")
+}
+
 fn step_icon(step: &StepResult) -> &'static str {
     if step.skipped {
         "[dim]SKIP[/]"
@@ -124,19 +208,106 @@ fn display_result(console: &mut Console, title: &str, result: &PatchResult) {
     display_steps(console, &result.steps);
 }
 
+/// `--explain`: what each step of the upcoming run does and why, shown
+/// before it runs.
+fn display_explanation(console: &mut Console, title: &str, steps: &[(Cow<'static, str>, String)]) {
+    let width = console.width();
+    // Wrap by hand to the panel width, less the border and indent.
+    let text_width = width.saturating_sub(8);
+    let mut lines = Vec::new();
+    for (name, text) in steps {
+        lines.push(name.to_string());
+        lines.extend(wrap_text(text, text_width).into_iter().map(|l| format!("  {l}")));
+    }
+    let _ = console.print("");
+    let panel = Panel::new(lines.join("\n"))
+        .title(format!("{title}: what will run"))
+        .border_style(Style::parse("blue").unwrap_or_default());
+    let _ = print_renderable(console, &panel.render(width));
+}
+
+/// `--trace-regex`: the discovery regex log recorded on a step, if any.
+fn display_regex_trace(console: &mut Console, label: &str, steps: &[StepResult]) {
+    let width = console.width();
+    for step in steps {
+        if let Some(trace) = &step.trace {
+            let _ = console.print("");
+            let panel = Panel::new(trace.as_str())
+                .title(format!("{label} regex trace"))
+                .border_style(Style::parse("magenta").unwrap_or_default());
+            let _ = print_renderable(console, &panel.render(width));
+        }
+    }
+}
+
+/// The code around each failed match (a failed step's detail), for
+/// `--trace-regex` outside a dry run, which shows every detail anyway.
+fn display_failure_context(console: &mut Console, steps: &[StepResult]) {
+    let width = console.width();
+    for step in steps {
+        if !step.ok && !step.detail.is_empty() {
+            let _ = console.print("");
+            let panel = Panel::new(step.detail.as_str())
+                .title(format!("{}: expected around here", step.name))
+                .border_style(Style::parse("red").unwrap_or_default());
+            let _ = print_renderable(console, &panel.render(width));
+        }
+    }
+}
+
+/// Find/replace detail for each applied step. User-rule steps are titled
+/// and coloured distinctly from built-in ones.
 fn display_dry_run_detail(console: &mut Console, steps: &[StepResult]) {
     let width = console.width();
     for step in steps {
         if !step.detail.is_empty() && !step.skipped {
+            let (origin, color) = if step.user_rule {
+                ("user rule", "magenta")
+            } else {
+                ("built-in", "yellow")
+            };
             let _ = console.print("");
             let panel = Panel::new(step.detail.as_str())
-                .title(step.name)
-                .border_style(Style::parse("yellow").unwrap_or_default());
+                .title(format!("{} ({origin})", step.name))
+                .border_style(Style::parse(color).unwrap_or_default());
             let _ = print_renderable(console, &panel.render(width));
         }
     }
 }
 
+/// Warn that product.json has no checksums, so nothing was actually verified.
+fn display_empty_checksums_warning(console: &mut Console) {
+    let width = console.width();
+    let panel = Panel::new(markup(
+        "[bold yellow]product.json checksums section is empty or missing[/]\n\
+         Integrity cannot be verified.",
+    ))
+    .title("Warning")
+    .border_style(Style::parse("yellow").unwrap_or_default());
+    let _ = print_renderable(console, &panel.render(width));
+}
+
+/// Warn when the installed Cursor is newer than the last version nupatch was
+/// validated against. Silent when the version is unknown.
+fn display_version_warning(console: &mut Console, cursor: &CursorVersion) {
+    let Some(version) = cursor.version.as_deref() else {
+        return;
+    };
+    if is_newer_than_tested(version) != Some(true) {
+        return;
+    }
+    let _ = console.print("");
+    let width = console.width();
+    let panel = Panel::new(markup(&format!(
+        "[bold yellow]Cursor {version} is newer than the last tested version \
+         ({LAST_TESTED_CURSOR}).[/]\n\
+         Patterns may have drifted. Preview with [bold]nupatch patch --dry-run[/] first."
+    )))
+    .title("Warning")
+    .border_style(Style::parse("yellow").unwrap_or_default());
+    let _ = print_renderable(console, &panel.render(width));
+}
+
 fn display_error_panel(console: &mut Console, message: &str) {
     let width = console.width();
     let panel = Panel::new(markup(&format!("[bold red]{message}[/]")))
@@ -145,29 +316,39 @@ fn display_error_panel(console: &mut Console, message: &str) {
     let _ = print_renderable(console, &panel.render(width));
 }
 
-fn require_cursor_app(
-    console: &mut Console,
-    cursor_app: Option<&std::path::Path>,
-) -> Result<()> {
-    if cursor_app.is_none() {
-        display_error_panel(console, "Could not find Cursor installation.");
-        return Err(eyre!("Could not find Cursor installation."));
+/// Why no Cursor app was found: packed resources get their own message.
+fn missing_app_error(paths: &CursorPaths) -> Error {
+    if let Some(dir) = paths::app_path() {
+        return Error::AppPathInvalid(dir.to_path_buf());
+    }
+    match &paths.packed_asar {
+        Some(asar) => Error::ResourcesPacked(asar.clone()),
+        None => Error::InstallNotFound("Cursor installation"),
+    }
+}
+
+fn require_cursor_app(console: &mut Console, paths: &CursorPaths) -> Result<()> {
+    if paths.cursor_app.is_none() {
+        let err = missing_app_error(paths);
+        display_error_panel(console, &err.to_string());
+        return Err(err.into());
     }
     Ok(())
 }
 
 fn require_paths<'a>(
     console: &mut Console,
-    cursor_app: Option<&'a std::path::Path>,
-    product_json: Option<&'a std::path::Path>,
+    paths: &'a CursorPaths,
 ) -> Result<(&'a std::path::Path, &'a std::path::Path)> {
-    let cursor_app = cursor_app.ok_or_else(|| {
-        display_error_panel(console, "Could not find Cursor installation.");
-        eyre!("Could not find Cursor installation.")
+    let cursor_app = paths.cursor_app.as_deref().ok_or_else(|| {
+        let err = missing_app_error(paths);
+        display_error_panel(console, &err.to_string());
+        err
     })?;
-    let product_json = product_json.ok_or_else(|| {
-        display_error_panel(console, "Could not find product.json.");
-        eyre!("Could not find product.json.")
+    let product_json = paths.product_json.as_deref().ok_or_else(|| {
+        let err = Error::InstallNotFound("product.json");
+        display_error_panel(console, &err.to_string());
+        err
     })?;
     Ok((cursor_app, product_json))
 }
@@ -176,11 +357,221 @@ fn require_paths<'a>(
 //  patch
 // ---------------------------------------------------------------------------
 
-pub fn cmd_patch(cli_only: bool, ide_only: bool, dry_run: bool) -> Result<()> {
-    let mut console = Console::new();
+/// Arguments of the `patch` subcommand.
+pub struct PatchArgs {
+    pub cli_only: bool,
+    pub ide_only: bool,
+    /// `--cli-all`: every CLI agent version instead of the detected one.
+    pub cli_all: bool,
+    /// `--cli-recent`: the N most recent CLI agent versions instead.
+    pub cli_recent: Option<usize>,
+    /// `--agent-version`: this CLI agent version instead.
+    pub agent_version: Option<String>,
+    /// `--list-versions`: list the CLI agent versions and patch nothing.
+    pub list_versions: bool,
+    pub dry_run: bool,
+    pub full_checksums: bool,
+    pub assume_version: Option<String>,
+    /// `--since`: skip installs older than this Cursor version.
+    pub since: Option<Version>,
+    pub rules: Option<PathBuf>,
+    pub patch_script: Option<PathBuf>,
+    pub no_system_detection: bool,
+    pub no_user_terminal_hint: bool,
+    pub continue_on_error: bool,
+    pub force: bool,
+    pub allow_reencode: bool,
+    pub explain: bool,
+    pub trace_regex: bool,
+    pub file: Option<PathBuf>,
+    pub out: Option<PathBuf>,
+    pub side_by_side: bool,
+    /// `--diff-tool`: `Some("")` when given without a command.
+    pub diff_tool: Option<String>,
+    pub dir: Option<PathBuf>,
+    pub glob: Option<String>,
+    pub archive: Option<PathBuf>,
+    pub emit: Option<PathBuf>,
+    pub notify: bool,
+    pub report: Option<PathBuf>,
+}
+
+/// `patch --agent-version`: the `index.js` of CLI agent `version`, or an
+/// error naming the versions there are.
+fn select_cli_version(console: &mut Console, paths: &CursorPaths, version: &str) -> Result<PathBuf> {
+    let Some(cli_dir) = paths.cli_agent_dir.as_deref() else {
+        let err = Error::InstallNotFound("the CLI agent versions directory");
+        display_error_panel(console, &err.to_string());
+        return Err(err.into());
+    };
+    let index = cli_dir.join(version).join("index.js");
+    if index.is_file() && Path::new(version).file_name() == Some(version.as_ref()) {
+        return Ok(index);
+    }
+    let installed: Vec<String> = cli_agent_indexes(cli_dir)
+        .iter()
+        .filter_map(|p| core::cli_agent_version(p))
+        .collect();
+    let what = if cli_dir.join(version).is_dir() { "has no index.js" } else { "isn't installed" };
+    let message = format!(
+        "CLI agent version {version} {what}.\nInstalled: {}",
+        if installed.is_empty() { "none".to_string() } else { installed.join(", ") }
+    );
+    display_error_panel(console, &message);
+    Err(eyre!("CLI agent version {version} {what}"))
+}
+
+/// `patch --list-versions`: every CLI agent version directory, oldest
+/// first, with when it was last modified and which one is detected.
+fn display_cli_versions(console: &mut Console, paths: &CursorPaths) {
+    let _ = console.print("");
+    let Some(cli_dir) = paths.cli_agent_dir.as_deref() else {
+        let _ = console.print(" [yellow]No CLI agent versions directory found.[/]");
+        return;
+    };
+    let _ = console.print(&format!(
+        " [bold]CLI agent versions[/] [dim]in {}[/]",
+        richrs::markup::escape(&cli_dir.display().to_string())
+    ));
+    let indexes = cli_version_indexes(cli_dir);
+    if indexes.is_empty() {
+        let _ = console.print("  [dim]none[/]");
+    }
+    let width = indexes
+        .iter()
+        .filter_map(|p| core::cli_agent_version(p))
+        .map(|v| v.len())
+        .max()
+        .unwrap_or(0);
+    for index in &indexes {
+        let Some(version) = core::cli_agent_version(index) else { continue };
+        let modified = index
+            .parent()
+            .and_then(|dir| dir.metadata().ok())
+            .and_then(|m| m.modified().ok())
+            .map_or_else(|| "unknown".to_string(), relative_time);
+        let detected = if !index.is_file() {
+            "  [yellow]no index.js[/]"
+        } else if paths.cli_index.as_deref() == Some(index.as_path()) {
+            "  [green]detected[/]"
+        } else {
+            ""
+        };
+        let _ = console.print(&format!(
+            "  {:width$}  [dim]modified {modified}[/]{detected}",
+            richrs::markup::escape(&version),
+        ));
+    }
+    let _ = console.print("");
+}
+
+/// Run `patch`. Returns the process exit code: 0 on success, otherwise
+/// `FailureKind::exit_code` of the first failure (1 if uncategorized).
+pub fn cmd_patch(args: PatchArgs) -> Result<i32> {
+    let PatchArgs {
+        cli_only,
+        ide_only,
+        cli_all,
+        cli_recent,
+        agent_version,
+        list_versions,
+        dry_run,
+        full_checksums,
+        assume_version,
+        since,
+        rules,
+        patch_script,
+        no_system_detection,
+        no_user_terminal_hint,
+        continue_on_error,
+        force,
+        allow_reencode,
+        explain,
+        trace_regex,
+        file,
+        out,
+        side_by_side,
+        diff_tool,
+        dir,
+        glob,
+        archive,
+        emit,
+        notify,
+        report,
+    } = args;
+    // With --file the patched code goes to stdout, so everything else
+    // goes to stderr.
+    let mut console = if file.is_some() { Console::stderr() } else { Console::new() };
     let paths = detect_paths();
-    if !cli_only {
-        require_cursor_app(&mut console, paths.cursor_app.as_deref())?;
+    if list_versions {
+        display_cli_versions(&mut console, &paths);
+        return Ok(0);
+    }
+    let batch = dir.is_some() || glob.is_some() || archive.is_some();
+    if out.is_some() && cli_only == ide_only && dir.is_none() {
+        return Err(eyre!("--out needs --cli-only or --ide-only to pick the agent to write"));
+    }
+    if emit.is_some() && cli_only == ide_only {
+        return Err(eyre!("--emit needs --cli-only or --ide-only to pick the agent to preview"));
+    }
+    if cli_recent == Some(0) {
+        return Err(eyre!("--cli-recent needs at least 1 version"));
+    }
+    let agent_index = match agent_version.as_deref() {
+        Some(version) => Some(select_cli_version(&mut console, &paths, version)?),
+        None => None,
+    };
+    if !cli_only && file.is_none() && !batch {
+        require_cursor_app(&mut console, &paths)?;
+    }
+
+    let mut user_rules = Vec::new();
+    if let Some(path) = rules.as_deref() {
+        user_rules.extend(load_rules(path).inspect_err(|e| {
+            display_error_panel(&mut console, &format!("Invalid rules file: {e}"));
+        })?);
+    }
+    if let Some(path) = patch_script.as_deref() {
+        user_rules.extend(load_patch_script(path).inspect_err(|e| {
+            display_error_panel(&mut console, &format!("Invalid patch script: {e}"));
+        })?);
+    }
+    let mut skip = Vec::new();
+    if no_system_detection {
+        skip.push("System nu detection");
+    }
+    if no_user_terminal_hint {
+        skip.push("userTerminalHint");
+    }
+    let opts = PatchOptions {
+        dry_run,
+        rules: user_rules,
+        skip,
+        continue_on_error,
+        force,
+        trace_regex,
+        // With --dir, --out is a directory; `patch_dir` sets this per file.
+        out: out.clone().filter(|_| !batch),
+        emit,
+        allow_reencode,
+    };
+
+    if let Some(file) = file.as_deref() {
+        return patch_input(&mut console, file, cli_only, ide_only, explain, diff_tool.as_deref(), &opts);
+    }
+    let only = match (cli_only, ide_only) {
+        (true, false) => Some("CLI"),
+        (false, true) => Some("IDE"),
+        _ => None,
+    };
+    if let Some(dir) = dir.as_deref() {
+        return patch_dir(&mut console, dir, only, out.as_deref(), side_by_side, opts);
+    }
+    if let Some(pattern) = glob.as_deref() {
+        return patch_glob(&mut console, pattern, only, side_by_side, full_checksums, opts);
+    }
+    if let Some(archive) = archive.as_deref() {
+        return patch_archive(&mut console, archive, out.as_deref(), &opts);
     }
 
     let _ = console.print("");
@@ -189,6 +580,31 @@ pub fn cmd_patch(cli_only: bool, ide_only: bool, dry_run: bool) -> Result<()> {
         .style(Style::parse("bright_cyan").unwrap_or_default());
     let _ = print_renderable(&mut console, &rule.render(width));
 
+    let mut cursor = paths
+        .product_json
+        .as_deref()
+        .map(read_cursor_version)
+        .unwrap_or_default();
+    if let Some(assumed) = assume_version {
+        let _ = console.print(&format!(
+            " [dim]Assuming Cursor version[/] [bold]{assumed}[/] [dim](detected: {})[/]",
+            cursor.version.as_deref().unwrap_or("unknown")
+        ));
+        cursor.version = Some(assumed);
+    }
+    display_version_warning(&mut console, &cursor);
+    if let Some(since) = since {
+        let skip = match cursor.version.as_deref().and_then(Version::parse) {
+            Some(v) if v >= since => None,
+            Some(v) => Some(format!("Cursor {v} < --since {since}")),
+            None => Some(format!("Cursor version unknown, can't check --since {since}")),
+        };
+        if let Some(reason) = skip {
+            let _ = console.print(&format!("\n [yellow]Skipped:[/] {reason}\n"));
+            return Ok(0);
+        }
+    }
+
     if dry_run {
         let _ = console.print("");
         let panel = Panel::new(markup("[yellow]DRY RUN[/] -- no files will be modified"))
@@ -197,58 +613,215 @@ pub fn cmd_patch(cli_only: bool, ide_only: bool, dry_run: bool) -> Result<()> {
     }
 
     let mut ok = true;
+    let mut failures: Vec<String> = Vec::new();
+    let mut first_kind: Option<FailureKind> = None;
+    // Agents written somewhere other than in place (--out, --side-by-side).
+    let mut written_elsewhere: Vec<PathBuf> = Vec::new();
+    let mut integrity_outcome: Option<PatchResult> = None;
+    let mut integrity_changed = false;
+
+    // The agents are independent files: patch them all at once on separate
+    // threads, then report in order. The integrity chain waits for the IDE.
+    // With --cli-all that's every CLI agent version, with --cli-recent the
+    // N newest and with --agent-version the one asked for, each labelled
+    // with its version; otherwise the detected one.
+    let by_version = cli_all || cli_recent.is_some() || agent_index.is_some();
+    let cli_targets: Vec<PathBuf> = match paths.cli_agent_dir.as_deref() {
+        _ if ide_only => Vec::new(),
+        _ if agent_index.is_some() => agent_index.into_iter().collect(),
+        Some(dir) if by_version => {
+            let mut indexes = cli_agent_indexes(dir);
+            let keep = cli_recent.unwrap_or(indexes.len());
+            indexes.drain(..indexes.len().saturating_sub(keep));
+            indexes
+        }
+        _ => paths.cli_index.iter().cloned().collect(),
+    };
+    let ide_target = paths.ide_main.as_deref().filter(|_| !cli_only);
+    let agent_opts = |path: &Path| PatchOptions {
+        out: if side_by_side { Some(core::patched_path(path)) } else { opts.out.clone() },
+        ..opts.clone()
+    };
+    let cli_runs: Vec<(String, &Path, PatchOptions)> = cli_targets
+        .iter()
+        .map(|path| {
+            let label = match core::cli_agent_version(path) {
+                Some(version) if by_version => format!("CLI {version}"),
+                _ => "CLI".to_string(),
+            };
+            (label, path.as_path(), agent_opts(path))
+        })
+        .collect();
+    let ide_opts = ide_target.map(agent_opts);
+    if by_version && !cli_runs.is_empty() {
+        let versions: Vec<&str> = cli_runs.iter().filter_map(|(label, ..)| label.strip_prefix("CLI ")).collect();
+        let _ = console.print(&format!(
+            "\n [dim]CLI agent versions selected:[/] {}",
+            richrs::markup::escape(&versions.join(", "))
+        ));
+    }
+    if explain {
+        if let Some((_, _, o)) = cli_runs.first() {
+            display_explanation(&mut console, "CLI Agent", &core::explain_plan("CLI", o));
+        }
+        if let Some(o) = &ide_opts {
+            display_explanation(&mut console, "IDE Agent", &core::explain_plan("IDE", o));
+        }
+    }
+    let spinner = match (cli_runs.len(), ide_target) {
+        (0, _) => "Patching IDE agent...".to_string(),
+        (1, Some(_)) => "Patching CLI and IDE agents...".to_string(),
+        (1, None) => "Patching CLI agent...".to_string(),
+        (n, Some(_)) => format!("Patching {n} CLI agents and the IDE agent..."),
+        (n, None) => format!("Patching {n} CLI agents..."),
+    };
+    // What the install looked like before this run, for `undo`: every file
+    // the run may write, taken before any of them is.
+    let snapshot = if !dry_run && out.is_none() && !side_by_side {
+        let mut files: Vec<&Path> = cli_runs.iter().map(|&(_, path, _)| path).chain(ide_target).collect();
+        if ide_target.is_some() {
+            files.extend(paths.ehp.as_deref());
+            files.extend(paths.product_json.as_deref());
+        }
+        history::Snapshot::take(&files)
+    } else {
+        None
+    };
+    // With --cli-all, back up every agent before any is written: the patches
+    // run on separate threads, so a backup failing inside one of them could
+    // come after another agent was already written. Here it stops the run
+    // with nothing changed, and `run_patch` finds each `.bak` in place.
+    // (The manifest records patched hashes, so it is only updated after.)
+    if cli_all && !dry_run && out.is_none() && !side_by_side {
+        let agents = cli_runs.iter().map(|&(_, path, _)| path).chain(ide_target);
+        if let Some(e) = agents.map(integrity::backup).find_map(Result::err) {
+            let message = format!("{} -- nothing was patched.", e.chain());
+            display_error_panel(&mut console, &richrs::markup::escape(&message));
+            return Ok(e.kind().exit_code());
+        }
+    }
+    let (cli_results, ide_result) = Status::new(spinner).run(|| {
+        std::thread::scope(|s| {
+            let cli: Vec<_> = cli_runs
+                .iter()
+                .map(|(_, path, o)| s.spawn(move || patch_cli_agent(path, o)))
+                .collect();
+            let ide = ide_target.zip(ide_opts.as_ref()).map(|(path, o)| patch_ide_agent(path, o));
+            let cli: Vec<PatchResult> =
+                cli.into_iter().map(|h| h.join().expect("CLI patch thread panicked")).collect();
+            (cli, ide)
+        })
+    });
+
+    // CLI Agent(s)
+    for ((label, cli_index, cli_opts), cli_result) in cli_runs.iter().zip(&cli_results) {
+        if let Some(out) = cli_opts.out.clone().filter(|_| cli_result.written()) {
+            written_elsewhere.push(out);
+        }
 
-    // CLI Agent
-    if !ide_only {
-        if let Some(ref cli_index) = paths.cli_index {
-            let cli_result = Status::new("Patching CLI agent...")
-                .run(|| patch_cli_agent(cli_index, dry_run));
-
-            display_result(&mut console, "CLI Agent", &cli_result);
-            if dry_run {
-                display_dry_run_detail(&mut console, &cli_result.steps);
+        let title = match label.strip_prefix("CLI ") {
+            Some(version) => format!("CLI Agent {version}"),
+            None => "CLI Agent".to_string(),
+        };
+        display_result(&mut console, &title, cli_result);
+        display_regex_trace(&mut console, label, &cli_result.steps);
+        if dry_run {
+            display_dry_run_detail(&mut console, &cli_result.steps);
+            if let Some(tool) = diff_tool.as_deref() {
+                diff_agent_file(&mut console, tool, "CLI", cli_index, cli_opts);
             }
-            if !cli_result.success {
-                ok = false;
+        } else if trace_regex {
+            display_failure_context(&mut console, &cli_result.steps);
+        }
+        if !cli_result.success {
+            ok = false;
+            if failures.is_empty() {
+                first_kind = cli_result.failure_kind();
             }
-        } else {
-            let _ = console.print("\n [dim]CLI agent not found, skipping.[/]");
+            failures.push(describe_failure(label, cli_result));
         }
     }
+    if cli_runs.is_empty() && !ide_only {
+        let _ = console.print("\n [dim]CLI agent not found, skipping.[/]");
+    }
 
     // IDE Agent
-    if !cli_only {
-        if let Some(ref ide_main) = paths.ide_main {
-            let ide_result = Status::new("Patching IDE agent...")
-                .run(|| patch_ide_agent(ide_main, dry_run));
-
-            display_result(&mut console, "IDE Agent", &ide_result);
-            if dry_run {
-                display_dry_run_detail(&mut console, &ide_result.steps);
+    if let (Some(ide_main), Some(ide_result), Some(ide_opts)) = (ide_target, &ide_result, &ide_opts) {
+        if let Some(out) = ide_opts.out.clone().filter(|_| ide_result.written()) {
+            written_elsewhere.push(out);
+        }
+
+        display_result(&mut console, "IDE Agent", ide_result);
+        display_regex_trace(&mut console, "IDE", &ide_result.steps);
+        if dry_run {
+            display_dry_run_detail(&mut console, &ide_result.steps);
+            if let Some(tool) = diff_tool.as_deref() {
+                diff_agent_file(&mut console, tool, "IDE", ide_main, ide_opts);
             }
+        } else if trace_regex {
+            display_failure_context(&mut console, &ide_result.steps);
+        }
 
-            if ide_result.success && !dry_run {
-                let integrity_result = Status::new("Updating integrity hashes...")
-                    .run(|| {
-                        update_integrity(
-                            ide_main,
-                            paths.ehp.as_deref(),
-                            paths.product_json.as_deref(),
-                            paths.cursor_app.as_deref(),
-                            dry_run,
-                        )
-                    });
-
-                display_result(&mut console, "Integrity Chain", &integrity_result);
-                if !integrity_result.success {
-                    ok = false;
-                }
-            } else if !ide_result.success {
+        if !ide_result.success {
+            ok = false;
+            if failures.is_empty() {
+                first_kind = ide_result.failure_kind();
+            }
+            failures.push(describe_failure("IDE", ide_result));
+        }
+
+        // A forced partial write still needs its hashes updated.
+        if let Some(out) = ide_opts.out.as_deref().filter(|_| ide_result.written()) {
+            let _ = console.print(&format!(
+                "\n [dim]Integrity chain skipped: product.json hashes the installed agent, \
+                 not {}.[/]",
+                richrs::markup::escape(&out.display().to_string())
+            ));
+        } else if (ide_result.success || ide_result.written()) && !dry_run {
+            if explain {
+                display_explanation(
+                    &mut console,
+                    "Integrity Chain",
+                    &[("Integrity chain".into(), integrity::INTEGRITY_EXPLANATION.to_string())],
+                );
+            }
+            let chain_hashes = || {
+                [paths.ehp.as_deref(), paths.product_json.as_deref()]
+                    .map(|p| p.and_then(|p| integrity::sha256_hex(p).ok()))
+            };
+            let before = chain_hashes();
+            let integrity_result = Status::new("Updating integrity hashes...")
+                .run(|| {
+                    update_integrity(
+                        ide_main,
+                        paths.product_json.as_deref(),
+                        paths.cursor_app.as_deref(),
+                        dry_run,
+                        full_checksums,
+                    )
+                });
+
+            display_result(&mut console, "Integrity Chain", &integrity_result);
+            if !integrity_result.success {
                 ok = false;
+                if failures.is_empty() {
+                    first_kind = integrity_result.failure_kind();
+                }
+                // The agent is already on disk with stale hashes: the
+                // half-state Cursor reports as a corrupt install.
+                failures.push(format!(
+                    "{}\n[bold yellow]The IDE agent was already written.[/] Cursor may \
+                     report a corrupt install until you run [bold]nupatch fix-checksums[/] \
+                     or [bold]nupatch revert[/].",
+                    describe_failure("Integrity", &integrity_result)
+                ));
             }
-        } else {
-            let _ = console.print("\n [dim]IDE agent not found, skipping.[/]");
+            // The chain is rewritten on every run; only a change is history.
+            integrity_changed = !integrity_result.success || chain_hashes() != before;
+            integrity_outcome = Some(integrity_result);
         }
+    } else if !cli_only {
+        let _ = console.print("\n [dim]IDE agent not found, skipping.[/]");
     }
 
     // Summary
@@ -259,14 +832,45 @@ pub fn cmd_patch(cli_only: bool, ide_only: bool, dry_run: bool) -> Result<()> {
             "[bold green]Patching complete![/]".to_string(),
             String::new(),
         ];
-        if !ide_only {
+        if out.is_some() || side_by_side {
+            // Full paths are too long for the panel; list them above it.
+            for path in &written_elsewhere {
+                let _ = console.print(&format!(
+                    " [cyan]Written:[/] {}",
+                    richrs::markup::escape(&path.display().to_string())
+                ));
+            }
+            if !written_elsewhere.is_empty() {
+                let _ = console.print("");
+            }
+            lines.push("[cyan]Installed agents are unchanged[/]".to_string());
+            if side_by_side && !written_elsewhere.is_empty() {
+                lines.push(
+                    "Review the [bold].patched[/] files, then swap them in and run \
+                     [bold]nupatch patch --ide-only[/]"
+                        .to_string(),
+                );
+            }
+        } else if !ide_only && !no_system_detection {
             lines.push(
                 "[cyan]CLI:[/] Nushell auto-detected from PATH -- \
                  no [bold]$env:SHELL[/] needed"
                     .to_string(),
             );
+        } else if !ide_only {
+            lines.push(
+                "[cyan]CLI:[/] Nushell detected from the configured shell only \
+                 (PATH detection disabled)"
+                    .to_string(),
+            );
         }
-        if !cli_only {
+        if !cli_only && out.is_none() && !side_by_side {
+            if !no_user_terminal_hint {
+                lines.push(
+                    "[cyan]IDE:[/] Respects your configured default terminal"
+                        .to_string(),
+                );
+            }
             lines.push(
                 "[cyan]IDE:[/] Full quit + relaunch Cursor \
                  (not just Reload Window)"
@@ -278,82 +882,1247 @@ pub fn cmd_patch(cli_only: bool, ide_only: bool, dry_run: bool) -> Result<()> {
             .title("Next Steps")
             .border_style(Style::parse("green").unwrap_or_default());
         let _ = print_renderable(&mut console, &panel.render(width));
+        if notify {
+            let results: Vec<(&str, Option<&PatchResult>)> = cli_runs
+                .iter()
+                .zip(&cli_results)
+                .map(|((label, ..), r)| (label.as_str(), Some(r)))
+                .chain([("IDE", ide_result.as_ref())])
+                .collect();
+            notify_patched(&mut console, &results);
+        }
     } else {
-        let panel = Panel::new(markup(
-            "[bold red]Some patches failed.[/]  See errors above.",
-        ))
-        .title("Error")
-        .border_style(Style::parse("red").unwrap_or_default());
+        let mut content = format!(
+            "[bold red]Some patches failed.[/]  See errors above.\n\n{}",
+            failures.join("\n")
+        );
+        if let Some(kind) = first_kind {
+            content.push_str(&format!("\n\n{}", failure_hint(kind)));
+        }
+        let panel = Panel::new(markup(&content))
+            .title("Error")
+            .border_style(Style::parse("red").unwrap_or_default());
         let _ = print_renderable(&mut console, &panel.render(width));
-        return Err(eyre!("Some patches failed"));
     }
 
-    Ok(())
+    let in_place = |r: &PatchResult| r.written() && out.is_none() && !side_by_side;
+    let cli_written: Vec<&Path> = cli_runs
+        .iter()
+        .zip(&cli_results)
+        .filter(|(_, r)| in_place(r))
+        .map(|(&(_, path, _), _)| path)
+        .collect();
+    let ide_written = ide_result.as_ref().is_some_and(in_place);
+    if !cli_written.is_empty() || ide_written || integrity_changed {
+        let mut files = cli_written.clone();
+        files.extend(ide_target.filter(|_| ide_written));
+        if integrity_changed {
+            files.extend(paths.ehp.as_deref());
+            files.extend(paths.product_json.as_deref());
+        }
+        let results: Vec<(&str, &PatchResult)> = cli_runs
+            .iter()
+            .zip(&cli_results)
+            .map(|((label, ..), r)| (label.as_str(), r))
+            .chain([("IDE", &ide_result), ("Integrity", &integrity_outcome)].into_iter().filter_map(
+                |(label, result)| Some((label, result.as_ref()?)),
+            ))
+            .collect();
+        history::record("patch", ok, &files, &results, snapshot);
+    }
+    // Keep the recorded hashes of the agents current, including when they
+    // were already patched and nothing was written.
+    let ide_ok = ide_result.as_ref().is_some_and(|r| r.success);
+    if !dry_run && out.is_none() && !side_by_side && (ide_ok || cli_results.iter().any(|r| r.success)) {
+        let mut manifest = Manifest::load();
+        for ((_, cli_index, _), _) in cli_runs.iter().zip(&cli_results).filter(|(_, r)| r.success) {
+            manifest.record(cli_index, "CLI");
+        }
+        if let Some(ide_main) = ide_target.filter(|_| ide_ok) {
+            manifest.record(ide_main, "IDE");
+        }
+        manifest.save();
+    }
+    if !dry_run && !ide_only {
+        display_newer_unpatched(&mut console, core::newer_unpatched_cli(&paths).as_deref());
+        let patched: Vec<&Path> = cli_runs.iter().map(|&(_, path, _)| path).collect();
+        let lost = core::lost_cli_patches(&patched);
+        if !lost.is_empty() {
+            let _ = console.print(&format!(
+                " [bold yellow]CLI agent version(s) patched before now look unpatched:[/] {} -- \
+                 patch them too with [bold]nupatch patch --cli-all[/].",
+                richrs::markup::escape(&lost.join(", "))
+            ));
+        }
+    }
+
+    if let Some(path) = report.as_deref() {
+        let mut saved = Report::new("patch", check_status(&paths), paths.packed_asar.clone());
+        saved.results = cli_runs
+            .iter()
+            .map(|(label, ..)| label.clone())
+            .zip(cli_results)
+            .chain(
+                [("IDE", ide_result), ("Integrity", integrity_outcome)]
+                    .into_iter()
+                    .filter_map(|(label, result)| Some((label.to_string(), result?))),
+            )
+            .collect();
+        saved.write(path).wrap_err("Could not save the report")?;
+        let _ = console.print(&format!(
+            " [dim]Report written to {}[/]",
+            richrs::markup::escape(&path.display().to_string())
+        ));
+    }
+
+    Ok(if ok { 0 } else { first_kind.map_or(1, FailureKind::exit_code) })
+}
+
+/// Warn about a CLI agent version newer than any nupatch patched.
+fn display_newer_unpatched(console: &mut Console, version: Option<&str>) {
+    if let Some(version) = version {
+        let _ = console.print(&format!(
+            " [bold yellow]A newer CLI agent version ({}) is present and unpatched[/] -- \
+             run [bold]nupatch patch[/] again.",
+            richrs::markup::escape(version)
+        ));
+    }
+}
+
+/// `patch --notify`: tell the desktop which agents were written in place.
+/// Nothing to say when everything was already patched.
+fn notify_patched(console: &mut Console, results: &[(&str, Option<&PatchResult>)]) {
+    if !crate::notify::AVAILABLE {
+        let _ = console.print(" [dim]--notify: this build has no notification support (feature `notify`).[/]");
+        return;
+    }
+    let patched: Vec<&str> = results
+        .iter()
+        .filter(|(_, r)| r.is_some_and(PatchResult::written))
+        .map(|&(label, _)| label)
+        .collect();
+    if patched.is_empty() {
+        return;
+    }
+    crate::notify::send(
+        "nupatch: nushell patch applied",
+        &format!("Patched the {} agent{}.", patched.join(" and "), if patched.len() > 1 { "s" } else { "" }),
+    );
+}
+
+/// What to do about a failure of the given kind.
+fn failure_hint(kind: FailureKind) -> &'static str {
+    match kind {
+        FailureKind::Io => "[dim]A file couldn't be read or written. Quit Cursor and check permissions.[/]",
+        FailureKind::Discovery => {
+            "[dim]The agent code wasn't recognized; this Cursor version may not be supported yet.[/]"
+        }
+        FailureKind::PatternNotFound => {
+            "[dim]A patch anchor has drifted (see[/] [bold]nupatch rules[/][dim]).\n\
+             A[/] [bold]--rules[/] [dim]override can bridge the gap until a fix is released.[/]"
+        }
+        FailureKind::Validation => "[dim]Fix the rules file and re-run.[/]",
+        FailureKind::InvalidFile => "[dim]A file is malformed (see above); Cursor may need reinstalling.[/]",
+    }
+}
+
+/// One-line account of how far a failed run got, e.g. "IDE: 2 step(s)
+/// applied, then 'userTerminalHint' failed -- changes not written".
+fn describe_failure(label: &str, result: &PatchResult) -> String {
+    let applied = result
+        .steps
+        .iter()
+        .filter(|s| s.ok && !s.skipped && !matches!(s.name.as_ref(), "Pattern discovery" | "Write"))
+        .count();
+    let Some(failed) = result.steps.iter().find(|s| !s.ok) else {
+        return format!("[cyan]{label}:[/] failed");
+    };
+    let outcome = if label == "Integrity" {
+        ""
+    } else if result.written() {
+        " -- partial result written"
+    } else {
+        " -- changes not written"
+    };
+    let failed_count = result.steps.iter().filter(|s| !s.ok).count();
+    if failed_count > 1 {
+        let names: Vec<String> = result
+            .steps
+            .iter()
+            .filter(|s| !s.ok)
+            .map(|s| format!("'{}'", s.name))
+            .collect();
+        return format!(
+            "[cyan]{label}:[/] {applied} step(s) applied, {failed_count} failed ({}){outcome}",
+            names.join(", ")
+        );
+    }
+    format!(
+        "[cyan]{label}:[/] {applied} step(s) applied, then '{}' failed{outcome}",
+        failed.name
+    )
+}
+
+// ---------------------------------------------------------------------------
+//  revert
+// ---------------------------------------------------------------------------
+
+/// `revert`. With `all_versions`, every CLI agent version is restored, not
+/// just the detected one.
+pub fn cmd_revert(yes: bool, all_versions: bool) -> Result<()> {
+    let mut console = Console::new();
+    let paths = detect_paths();
+    let cli_targets = core::revert_cli_targets(&paths, all_versions);
+
+    let _ = console.print(
+        "\n[yellow]This will revert all patches and restore from backups.[/]",
+    );
+    if all_versions {
+        let _ = console.print(&format!(
+            "[yellow]That includes every CLI agent version nupatch patched ({}).[/]",
+            cli_targets.len()
+        ));
+    }
+
+    if !yes && !Confirm::new("Continue?").default(false).ask()? {
+        let _ = console.print("[dim]Aborted.[/]");
+        return Ok(());
+    }
+
+    let targets: Vec<&Path> = cli_targets
+        .iter()
+        .chain([&paths.ide_main, &paths.ehp, &paths.product_json].into_iter().flatten())
+        .map(PathBuf::as_path)
+        .collect();
+    let snapshot = history::Snapshot::take(&targets);
+    let result = Status::new("Reverting patches...")
+        .run(|| revert_all(&paths, all_versions));
+
+    let _ = console.print("");
+    for f in &result.files {
+        if f.restored {
+            let _ = console.print(&format!(
+                "  [bold green]  OK[/]  Restored: {}",
+                f.filename
+            ));
+        } else if let Some(e) = &f.error {
+            let _ = console.print(&format!(
+                "  [bold red]FAIL[/]  {}: {e}",
+                f.filename
+            ));
+        } else if f.gone {
+            let _ = console.print(&format!(
+                "  [dim]SKIP[/]  {} no longer present, skipped",
+                f.filename
+            ));
+        } else if let Some(reason) = f.skipped {
+            let _ = console.print(&format!(
+                "  [dim]SKIP[/]  {reason}: {}",
+                f.filename
+            ));
+        } else {
+            let _ = console.print(&format!(
+                "  [dim]SKIP[/]  No backup: {}",
+                f.filename
+            ));
+        }
+    }
+
+    let touched: Vec<_> = result.files.iter().filter(|f| f.restored || f.error.is_some()).collect();
+    if !touched.is_empty() {
+        let steps = touched
+            .iter()
+            .map(|f| match &f.error {
+                Some(e) => StepResult::fail(f.filename.clone(), e.to_string()),
+                None => StepResult::ok(f.filename.clone(), "Restored from backup"),
+            })
+            .collect::<Vec<_>>();
+        let reverted = PatchResult { success: steps.iter().all(|s| s.ok), steps };
+        history::record("revert", reverted.success, &targets, &[("Revert", &reverted)], snapshot);
+        manifest::forget_changed(&targets);
+    }
+
+    let _ = console.print("");
+    let width = console.width();
+    let mut done = "[bold green]Revert complete.[/]\nRestart Cursor to apply.".to_string();
+    let gone = result.files.iter().filter(|f| f.gone).count();
+    if gone > 0 {
+        done.push_str(&format!(
+            "\n[dim]Skipped {gone} patched CLI version(s) no longer present (removed by an update).[/]"
+        ));
+    }
+    let panel = Panel::new(markup(&done))
+        .title("Done")
+        .border_style(Style::parse("green").unwrap_or_default());
+    let _ = print_renderable(&mut console, &panel.render(width));
+
+    Ok(())
+}
+
+// ---------------------------------------------------------------------------
+//  history
+// ---------------------------------------------------------------------------
+
+pub fn cmd_history(limit: Option<usize>, json: bool) -> Result<()> {
+    let mut console = Console::new();
+    let Some(path) = history::history_path() else {
+        return Err(eyre!("No home directory to find the history log in"));
+    };
+    let log = history::read(&path)?;
+    let entries: Vec<&history::Entry> =
+        log.entries.iter().rev().take(limit.unwrap_or(usize::MAX)).collect();
+
+    if json {
+        let raw: Vec<&serde_json::Value> = entries.iter().map(|e| &e.raw).collect();
+        println!("{}", serde_json::to_string_pretty(&raw)?);
+        return Ok(());
+    }
+
+    let _ = console.print("");
+    if entries.is_empty() {
+        let _ = console.print(&format!(
+            " [dim]No history yet ({}).[/]",
+            richrs::markup::escape(&path.display().to_string())
+        ));
+    } else {
+        let mut table = Table::new()
+            .title("History")
+            .border_style(Style::parse("bright_cyan").unwrap_or_default())
+            .header_style(Style::parse("bold magenta").unwrap_or_default());
+        table.add_column(Column::new("When").style(Style::parse("dim").unwrap_or_default()));
+        table.add_column(Column::new("Command").style(Style::new().bold()));
+        table.add_column(Column::new("Files").style(Style::parse("cyan").unwrap_or_default()));
+        table.add_column(Column::new("Outcome"));
+        for entry in &entries {
+            let files: Vec<String> = entry
+                .files
+                .iter()
+                .map(|f| f.file_name().unwrap_or(f.as_os_str()).to_string_lossy().into_owned())
+                .collect();
+            table.add_row(Row::new([
+                markup(&entry.time.map_or_else(|| "unknown".to_string(), relative_time)),
+                markup(&richrs::markup::escape(&entry.command)),
+                markup(&richrs::markup::escape(&files.join(", "))),
+                markup(if entry.success { "[bold green]OK[/]" } else { "[bold red]FAILED[/]" }),
+            ]));
+        }
+        let width = console.width();
+        let _ = print_renderable(&mut console, &table.render(width));
+    }
+    if log.unreadable > 0 {
+        let _ = console.print(&format!(
+            " [yellow]Skipped {} unreadable line(s) in {}[/]",
+            log.unreadable,
+            richrs::markup::escape(&path.display().to_string())
+        ));
+    }
+    Ok(())
+}
+
+// ---------------------------------------------------------------------------
+//  undo
+// ---------------------------------------------------------------------------
+
+pub fn cmd_undo(yes: bool) -> Result<()> {
+    let mut console = Console::new();
+    let Some(path) = history::history_path() else {
+        return Err(eyre!("No home directory to find the history log in"));
+    };
+    let log = history::read(&path)?;
+    let Some(entry) = log.last_undoable() else {
+        let _ = console.print("\n[dim]Nothing to undo.[/]");
+        return Ok(());
+    };
+    let when = entry.time.map_or_else(|| "at an unknown time".to_string(), relative_time);
+    let Some(id) = entry.snapshot_id.as_deref().filter(|_| {
+        entry.snapshot.iter().all(|(_, copy)| copy.as_ref().is_none_or(|c| c.is_file()))
+    }) else {
+        let message = format!(
+            "The last {} ({when}) has no snapshot left to undo it from. Use nupatch revert instead.",
+            entry.command
+        );
+        display_error_panel(&mut console, &message);
+        return Err(eyre!(message));
+    };
+
+    let _ = console.print(&format!(
+        "\n[yellow]This will undo the last [bold]{}[/] ({when}), restoring:[/]",
+        richrs::markup::escape(&entry.command)
+    ));
+    for (file, _) in entry.snapshot.iter().filter(|(file, copy)| copy.is_some() || file.exists()) {
+        let _ = console.print(&format!("  {}", richrs::markup::escape(&file.display().to_string())));
+    }
+    if !yes && !Confirm::new("Continue?").default(false).ask()? {
+        let _ = console.print("[dim]Aborted.[/]");
+        return Ok(());
+    }
+
+    let restored = Status::new("Undoing...").run(|| history::restore_snapshot(entry));
+    let _ = console.print("");
+    let mut steps = Vec::new();
+    for (file, removed, outcome) in &restored {
+        let name = file.file_name().unwrap_or(file.as_os_str()).to_string_lossy().into_owned();
+        match outcome {
+            Ok(()) if *removed => {
+                let _ = console.print(&format!("  [bold green]  OK[/]  Removed: {name}"));
+                steps.push(StepResult::ok(name, "Removed (created by the undone run)"));
+            }
+            Ok(()) => {
+                let _ = console.print(&format!("  [bold green]  OK[/]  Restored: {name}"));
+                steps.push(StepResult::ok(name, "Restored from snapshot"));
+            }
+            Err(e) => {
+                let _ = console.print(&format!("  [bold red]FAIL[/]  {name}: {e}"));
+                steps.push(StepResult::fail(name, e.to_string()).with_kind(e.kind()));
+            }
+        }
+    }
+    let undone = PatchResult { success: steps.iter().all(|s| s.ok), steps };
+    let files: Vec<&Path> = restored.iter().map(|(file, ..)| *file).filter(|f| f.exists()).collect();
+    history::record_undo(id, undone.success, &files, &[("Undo", &undone)]);
+    manifest::forget_changed(&files);
+
+    let _ = console.print("");
+    let width = console.width();
+    let panel = if undone.success {
+        history::discard_snapshot(id);
+        Panel::new(markup("[bold green]Undo complete.[/]\nRestart Cursor to apply."))
+            .title("Done")
+            .border_style(Style::parse("green").unwrap_or_default())
+    } else {
+        Panel::new(markup("[bold red]Some files could not be restored.[/]  See errors above."))
+            .title("Error")
+            .border_style(Style::parse("red").unwrap_or_default())
+    };
+    let _ = print_renderable(&mut console, &panel.render(width));
+    if !undone.success {
+        return Err(eyre!("Undo failed: some files could not be restored"));
+    }
+    Ok(())
+}
+
+// ---------------------------------------------------------------------------
+//  bug-report
+// ---------------------------------------------------------------------------
+
+pub fn cmd_bug_report(out: Option<&Path>, redact: bool, hash_versions: bool) -> Result<()> {
+    let paths = detect_paths();
+    let mut bundle = Status::new("Collecting diagnostics...").run(|| crate::bugreport::collect(&paths));
+    if redact {
+        crate::bugreport::redact(&mut bundle, &paths, hash_versions);
+    }
+    let text = serde_json::to_string_pretty(&bundle)?;
+    let Some(out) = out else {
+        println!("{text}");
+        return Ok(());
+    };
+    fs::write(out, text + "\n").map_err(|e| Error::io("write bug report", out, e))?;
+    let mut console = Console::new();
+    let _ = console.print(&format!(
+        "\n [green]Bug report written to[/] [bold]{}[/] [dim]-- attach it to your issue.[/]",
+        richrs::markup::escape(&out.display().to_string())
+    ));
+    Ok(())
+}
+
+// ---------------------------------------------------------------------------
+//  info
+// ---------------------------------------------------------------------------
+
+pub fn cmd_info(version: &str) -> Result<()> {
+    let mut console = Console::new();
+    let paths = detect_paths();
+    let info = install_info(&paths);
+
+    let unknown = "[dim]unknown[/]";
+    let lines = [
+        format!("nupatch:       [bold]v{version}[/]"),
+        format!("Cursor:        {}", info.cursor.describe()),
+        match (&info.cursor_app, &paths.packed_asar) {
+            (Some(app), _) => format!("Cursor app:    {app}"),
+            (None, Some(asar)) => format!(
+                "Cursor app:    [yellow]packed in {} (unsupported)[/]",
+                asar.display()
+            ),
+            (None, None) => "Cursor app:    [dim]not found[/]".to_string(),
+        },
+        format!(
+            "CLI agent:     {}",
+            info.cli_version.as_deref().unwrap_or(unknown)
+        ),
+        format!("Platform:      {}/{}", info.os, info.arch),
+    ];
+
+    let _ = console.print("");
+    let width = console.width();
+    let panel = Panel::new(markup(&lines.join("\n")))
+        .title(markup("[bold]Info[/]"))
+        .border_style(Style::parse("bright_cyan").unwrap_or_default());
+    let _ = print_renderable(&mut console, &panel.render(width));
+
+    Ok(())
+}
+
+// ---------------------------------------------------------------------------
+//  rules
+// ---------------------------------------------------------------------------
+
+pub fn cmd_rules(export: bool) -> Result<()> {
+    if export {
+        let rules: Vec<serde_json::Value> = core::BUILTIN_RULES
+            .iter()
+            .map(|r| {
+                serde_json::json!({
+                    "name": r.name,
+                    "target": r.target.as_str(),
+                    "find": r.find.replace("{shell}", core::TARGET_SHELL),
+                    "regex": r.regex,
+                    "mode": r.mode.as_str(),
+                    "replace": r.template.replace("{shell}", core::TARGET_SHELL),
+                    "description": r.description,
+                })
+            })
+            .collect();
+        let out = serde_json::json!({ "rules": rules });
+        println!("{}", serde_json::to_string_pretty(&out)?);
+        return Ok(());
+    }
+
+    let mut console = Console::new();
+    let width = console.width();
+    let mut table = Table::new()
+        .title("Built-in Patch Rules")
+        .border_style(Style::parse("bright_cyan").unwrap_or_default())
+        .header_style(Style::parse("bold magenta").unwrap_or_default());
+    table.add_column(Column::new("Step").style(Style::new().bold()));
+    table.add_column(Column::new("Plans"));
+    table.add_column(Column::new("Searches for").style(Style::parse("cyan").unwrap_or_default()));
+    table.add_column(Column::new("Edit"));
+
+    // Tables don't wrap cells, so wrap the two free-text columns by hand
+    // into what's left after the name/plan columns and the borders.
+    let name_width = core::BUILTIN_RULES.iter().map(|r| r.name.len()).max().unwrap_or(0);
+    let text_width = width.saturating_sub(name_width + "CLI, IDE".len() + 13) / 2;
+
+    for r in core::BUILTIN_RULES {
+        let plans = match r.target {
+            RuleTarget::Cli => "CLI",
+            RuleTarget::Ide => "IDE",
+            RuleTarget::Both => "CLI, IDE",
+        };
+        let kind = if r.regex { "regex" } else { "literal" };
+        let find = wrap_text(&format!("{} ({kind})", r.find), text_width);
+        let edit = wrap_text(&format!("{}: {}", r.mode.as_str(), r.description), text_width);
+        // Plain text: regex brackets in find patterns aren't markup.
+        for i in 0..find.len().max(edit.len()) {
+            let (name, plans) = if i == 0 { (r.name, plans) } else { ("", "") };
+            table.add_row(Row::new([
+                Text::from(name),
+                Text::from(plans),
+                Text::from(find.get(i).map_or("", String::as_str)),
+                Text::from(edit.get(i).map_or("", String::as_str)),
+            ]));
+        }
+        table.add_section();
+    }
+
+    let _ = console.print("");
+    let _ = print_renderable(&mut console, &table.render(width));
+    let _ = console.print(
+        "\n[dim]Placeholders like[/] [bold]{enum_var}[/] [dim]are minified names discovered \
+         per file. Use[/] [bold]--export[/] [dim]for the full templates as JSON.[/]",
+    );
+    Ok(())
+}
+
+// ---------------------------------------------------------------------------
+//  status
+// ---------------------------------------------------------------------------
+
+/// Discovery dump for `status -v`: the minified names found in each live
+/// agent file, or the discovery error.
+fn display_discovery(console: &mut Console, paths: &CursorPaths) {
+    for (label, path) in [("CLI", &paths.cli_index), ("IDE", &paths.ide_main)] {
+        let Some(path) = path else { continue };
+        display_discovery_result(console, label, &core::discover_file(path));
+    }
+}
+
+/// One discovery panel: the discovered names, or the error and the code
+/// near the anchor that wasn't found.
+fn display_discovery_result(
+    console: &mut Console,
+    label: &str,
+    result: &std::result::Result<Vec<(&'static str, String)>, Error>,
+) {
+    let width = console.width();
+    let content = match result {
+        Ok(vars) => {
+            let pad = vars.iter().map(|(k, _)| k.len()).max().unwrap_or(0);
+            vars.iter()
+                .map(|(k, v)| format!("{k:pad$}  [bold]{v}[/]"))
+                .collect::<Vec<_>>()
+                .join("\n")
+        }
+        Err(e) => {
+            let mut content = format!("[bold red]Discovery failed:[/] {e}");
+            if let Error::PatternNotFound { near: Some(near), .. } = e {
+                content.push_str(&format!("\n\n{}", richrs::markup::escape(near)));
+            }
+            content
+        }
+    };
+    let _ = console.print("");
+    let panel = Panel::new(markup(&content))
+        .title(markup(&format!("[bold]{label} discovery[/]")))
+        .border_style(Style::parse("magenta").unwrap_or_default());
+    let _ = print_renderable(console, &panel.render(width));
+}
+
+/// `patch --dir`: patch every agent file under `dir` (`index.js` with the
+/// CLI plan, `main.js` with the IDE plan, or only those of `only`).
+/// `out` is a directory mirroring `dir`. The integrity chain isn't run:
+/// these files don't belong to the detected install.
+fn patch_dir(
+    console: &mut Console,
+    dir: &Path,
+    only: Option<&str>,
+    out: Option<&Path>,
+    side_by_side: bool,
+    opts: PatchOptions,
+) -> Result<i32> {
+    let files: Vec<(PathBuf, &str)> = crate::paths::find_agent_files(dir)
+        .map_err(|e| Error::io("search", dir, e))?
+        .into_iter()
+        .filter_map(|f| crate::paths::agent_label(&f).map(|label| (f, label)))
+        .filter(|(_, label)| only.is_none_or(|o| o == *label))
+        .collect();
+    if files.is_empty() {
+        return Err(eyre!("No index.js or main.js found under {}", dir.display()));
+    }
+    let title = format!("Batch patch: {}", dir.display());
+    let batch = patch_batch(console, &title, Some(dir), &files, out, side_by_side, &[], opts)?;
+    let code = batch.code;
+    batch.record(Vec::new(), None);
+    Ok(code)
+}
+
+/// `patch --glob`: patch the files `pattern` matches, each with the plan
+/// `agent_label` infers (or `only`'s). A match that is the detected IDE
+/// agent, patched in place, gets the integrity chain like a normal run.
+/// Matches that aren't agent code are skipped and left as they are, with
+/// no `.bak` next to them.
+fn patch_glob(
+    console: &mut Console,
+    pattern: &str,
+    only: Option<&str>,
+    side_by_side: bool,
+    full_checksums: bool,
+    opts: PatchOptions,
+) -> Result<i32> {
+    let paths = detect_paths();
+    let matches = glob::glob(pattern).wrap_err_with(|| format!("Invalid glob pattern {pattern:?}"))?;
+    // The same file can match through different spellings (`./`, `..`,
+    // symlinks); key by canonical path and keep the first spelling.
+    let mut seen = std::collections::HashSet::new();
+    let mut files: Vec<(PathBuf, &str)> = Vec::new();
+    for path in matches {
+        let path = path.wrap_err("Failed to read a glob match")?;
+        if !path.is_file() || !seen.insert(fs::canonicalize(&path).unwrap_or_else(|_| path.clone())) {
+            continue;
+        }
+        match only.or_else(|| crate::paths::agent_label(&path)) {
+            Some(label) => files.push((path, label)),
+            None => {
+                let _ = console.print(&format!(
+                    " [yellow]Skipping {}: can't tell CLI from IDE (use --cli-only or --ide-only)[/]",
+                    richrs::markup::escape(&path.display().to_string())
+                ));
+            }
+        }
+    }
+    if files.is_empty() {
+        return Err(eyre!("Glob {pattern:?} matched no agent files"));
+    }
+
+    let dry_run = opts.dry_run;
+    let canonical = |p: &Path| fs::canonicalize(p).ok();
+    let installed_ide = paths.ide_main.as_deref().and_then(canonical);
+    // The integrity chain may rewrite these too; snapshot them with the agents.
+    let chain: Vec<&Path> = if !side_by_side && files.iter().any(|(p, _)| canonical(p) == installed_ide) {
+        paths.ehp.iter().chain(&paths.product_json).map(PathBuf::as_path).collect()
+    } else {
+        Vec::new()
+    };
+    let title = format!("Glob patch: {pattern}");
+    let mut batch = patch_batch(console, &title, None, &files, None, side_by_side, &chain, opts)?;
+
+    let mut integrity = None;
+    if let Some(ide_main) = paths.ide_main.as_deref()
+        && !dry_run
+        && installed_ide.is_some()
+        && batch.in_place.iter().any(|p| canonical(p) == installed_ide)
+    {
+        let result = Status::new("Updating integrity hashes...").run(|| {
+            update_integrity(
+                ide_main,
+                paths.product_json.as_deref(),
+                paths.cursor_app.as_deref(),
+                false,
+                full_checksums,
+            )
+        });
+        display_result(console, "Integrity Chain", &result);
+        if !result.success && batch.code == 0 {
+            batch.code = result.failure_kind().map_or(1, |k| k.exit_code());
+        }
+        integrity = Some(result);
+    }
+    let code = batch.code;
+    let changed = integrity.as_ref().is_some_and(|r| r.steps.iter().any(|s| s.ok && !s.skipped));
+    batch.record(if changed { chain } else { Vec::new() }, integrity.as_ref());
+    Ok(code)
+}
+
+/// `patch --archive`: patch the agent files inside a zip or tar(.gz) of
+/// `resources/app` and write a patched copy of the archive: `out`, or
+/// `<name>-patched.<ext>` next to it.
+fn patch_archive(console: &mut Console, archive: &Path, out: Option<&Path>, opts: &PatchOptions) -> Result<i32> {
+    let default_out = crate::archive::default_output(archive)
+        .ok_or_else(|| eyre!("{}: not a .zip, .tar, .tar.gz or .tgz file", archive.display()))?;
+    let out = out.map_or(default_out, Path::to_path_buf);
+    if out == archive {
+        return Err(eyre!("--out must differ from the archive being patched"));
+    }
+
+    let snapshot = if opts.dry_run { None } else { history::Snapshot::take(&[&out]) };
+    let entries = Status::new("Patching archive...").run(|| crate::archive::patch_archive(archive, &out, opts))?;
+    if entries.is_empty() {
+        return Err(eyre!("No agent code found in {}", archive.display()));
+    }
+    let mut first_kind = None;
+    for entry in &entries {
+        display_result(console, &format!("{} Agent: {}", entry.label, entry.name), &entry.result);
+        if !entry.result.success && first_kind.is_none() {
+            first_kind = Some(entry.result.failure_kind());
+        }
+    }
+
+    let _ = console.print("");
+    let out_name = richrs::markup::escape(&out.display().to_string());
+    if !entries.iter().any(|e| e.patched) {
+        let _ = console.print(" [dim]Nothing to change; no archive written.[/]");
+    } else if opts.dry_run {
+        let _ = console.print(&format!(" [yellow]Would write[/] {out_name}"));
+    } else {
+        let _ = console.print(&format!(" [bold green]Written:[/] {out_name}"));
+        let _ = console.print(
+            " [dim]The integrity chain isn't applied inside archives; run[/] \
+             [bold]nupatch patch --ide-only[/] [dim]once it's unpacked into an install.[/]",
+        );
+    }
+    if out.is_file() && entries.iter().any(|e| e.patched) && !opts.dry_run {
+        let labels: Vec<String> = entries.iter().map(|e| format!("{} {}", e.label, e.name)).collect();
+        let results: Vec<(&str, &PatchResult)> =
+            labels.iter().map(String::as_str).zip(entries.iter().map(|e| &e.result)).collect();
+        history::record("patch", first_kind.is_none(), &[&out], &results, snapshot);
+    }
+    Ok(first_kind.map_or(0, |k| k.map_or(1, |k| k.exit_code())))
+}
+
+/// What a `patch_batch` run did, for the history log.
+struct Batch {
+    code: i32,
+    /// Agents patched in place.
+    in_place: Vec<PathBuf>,
+    /// Every file written: in place, under `--out` or `.patched`.
+    written: Vec<PathBuf>,
+    /// Each agent file that wasn't skipped, labelled with its path.
+    results: Vec<(String, PatchResult)>,
+    snapshot: Option<history::Snapshot>,
+}
+
+impl Batch {
+    /// Append the run to the history log, with `extra` files (and result)
+    /// written after the batch, or drop its snapshot if nothing was written.
+    fn record(self, extra: Vec<&Path>, extra_result: Option<&PatchResult>) {
+        let mut files: Vec<&Path> = self.written.iter().map(PathBuf::as_path).collect();
+        files.extend(extra);
+        if files.is_empty() {
+            return;
+        }
+        let mut results: Vec<(&str, &PatchResult)> = self.results.iter().map(|(l, r)| (l.as_str(), r)).collect();
+        results.extend(extra_result.map(|r| ("Integrity", r)));
+        history::record("patch", self.code == 0, &files, &results, self.snapshot);
+    }
+}
+
+/// Patch `files` (path and plan label) one by one and show a result table.
+/// Files discovery can't read are skipped, not failed, and left untouched
+/// (no `.bak` is made for them). `out` is a
+/// directory mirroring `base`; `side_by_side` writes `<name>.patched`.
+/// Everything the run may write, plus `extra`, is snapshotted first.
+#[allow(clippy::too_many_arguments)]
+fn patch_batch(
+    console: &mut Console,
+    title: &str,
+    base: Option<&Path>,
+    files: &[(PathBuf, &str)],
+    out: Option<&Path>,
+    side_by_side: bool,
+    extra: &[&Path],
+    mut opts: PatchOptions,
+) -> Result<Batch> {
+    let _ = console.print("");
+    let mut table = Table::new()
+        .title(title)
+        .border_style(Style::parse("bright_cyan").unwrap_or_default())
+        .header_style(Style::parse("bold magenta").unwrap_or_default());
+    table.add_column(Column::new("File").style(Style::parse("cyan").unwrap_or_default()));
+    table.add_column(Column::new("Agent"));
+    table.add_column(Column::new("Result"));
+    table.add_column(Column::new("Detail"));
+
+    let target = |file: &Path| -> Option<PathBuf> {
+        let rel = base.and_then(|b| file.strip_prefix(b).ok()).unwrap_or(file);
+        if side_by_side { Some(core::patched_path(file)) } else { out.map(|o| o.join(rel)) }
+    };
+    let snapshot = if opts.dry_run {
+        None
+    } else {
+        let targets: Vec<PathBuf> = files.iter().map(|(f, _)| target(f).unwrap_or_else(|| f.clone())).collect();
+        let mut snapshot_files: Vec<&Path> = targets.iter().map(PathBuf::as_path).collect();
+        snapshot_files.extend(extra);
+        history::Snapshot::take(&snapshot_files)
+    };
+
+    let (mut patched, mut skipped) = (0, 0);
+    let mut first_kind: Option<FailureKind> = None;
+    let mut failed = 0;
+    let (mut in_place, mut written, mut results) = (Vec::new(), Vec::new(), Vec::new());
+    for (file, label) in files {
+        let rel = base.and_then(|b| file.strip_prefix(b).ok()).unwrap_or(file);
+        opts.out = target(file);
+        if let Some(parent) = opts.out.as_deref().and_then(Path::parent)
+            && !opts.dry_run
+        {
+            fs::create_dir_all(parent).map_err(|e| Error::io("create", parent, e))?;
+        }
+        let result = Status::new(format!("Patching {}...", rel.display()))
+            .run(|| if *label == "CLI" { patch_cli_agent(file, &opts) } else { patch_ide_agent(file, &opts) });
+
+        let failed_step = result.steps.iter().find(|s| !s.ok);
+        let not_agent = failed_step.is_some_and(|step| step.name == "Pattern discovery");
+        let changed = result
+            .steps
+            .iter()
+            .any(|s| s.ok && !s.skipped && !matches!(s.name.as_ref(), "Pattern discovery" | "Write"));
+        let (status, detail) = match failed_step {
+            Some(_) if not_agent => {
+                skipped += 1;
+                ("[dim]SKIP[/]", "not agent code".to_string())
+            }
+            Some(step) => {
+                failed += 1;
+                first_kind = first_kind.or(result.failure_kind());
+                ("[bold red]FAIL[/]", format!("{}: {}", step.name, step.message))
+            }
+            None if !changed => ("[green]OK[/]", "already patched".to_string()),
+            None if opts.dry_run => {
+                patched += 1;
+                ("[yellow]WOULD PATCH[/]", String::new())
+            }
+            None => {
+                patched += 1;
+                let written = match (&opts.out, side_by_side) {
+                    (Some(_), true) => "written to .patched",
+                    (Some(_), false) => "written under --out",
+                    (None, _) => {
+                        in_place.push(file.clone());
+                        "in place"
+                    }
+                };
+                ("[bold green]PATCHED[/]", written.to_string())
+            }
+        };
+        table.add_row(Row::new([
+            markup(&richrs::markup::escape(&rel.display().to_string())),
+            markup(label),
+            markup(status),
+            markup(&richrs::markup::escape(&detail)),
+        ]));
+        if result.written() {
+            written.push(opts.out.clone().unwrap_or_else(|| file.clone()));
+        }
+        if !not_agent {
+            results.push((format!("{label} {}", rel.display()), result));
+        }
+    }
+    let width = console.width();
+    let _ = print_renderable(console, &table.render(width));
+    let _ = console.print(&format!(
+        " {} file(s): [bold]{patched}[/] {}, [bold]{skipped}[/] skipped, [bold]{failed}[/] failed",
+        files.len(),
+        if opts.dry_run { "would be patched" } else { "patched" },
+    ));
+    if let Some(out) = out.filter(|_| !opts.dry_run && patched > 0) {
+        let _ = console.print(&format!(" [dim]Output:[/] {}", richrs::markup::escape(&out.display().to_string())));
+    }
+    record_cli_agents(&in_place);
+    let code = if failed == 0 { 0 } else { first_kind.map_or(1, |k| k.exit_code()) };
+    Ok(Batch { code, in_place, written, results, snapshot })
+}
+
+/// Add the installed CLI agents among `written` to the manifest, so
+/// `revert --all-versions` knows about versions patched by a batch run.
+fn record_cli_agents(written: &[PathBuf]) {
+    let Some(cli_dir) = detect_paths().cli_agent_dir else { return };
+    let agents: Vec<&PathBuf> = written
+        .iter()
+        .filter(|f| f.starts_with(&cli_dir) && paths::agent_label(f) == Some("CLI"))
+        .collect();
+    if agents.is_empty() {
+        return;
+    }
+    let mut manifest = Manifest::load();
+    for agent in agents {
+        manifest.record(agent, "CLI");
+    }
+    manifest.save();
 }
 
-// ---------------------------------------------------------------------------
-//  revert
-// ---------------------------------------------------------------------------
+/// `--diff-tool` for an installed agent: patch it again in memory (as the
+/// dry run just did) and open the change in the diff tool.
+fn diff_agent_file(console: &mut Console, tool: &str, label: &str, path: &Path, opts: &PatchOptions) {
+    let code = match fs::read_to_string(path) {
+        Ok(code) => code,
+        Err(e) => {
+            let e = Error::io("read", path, e);
+            let _ = console.print(&format!("\n [yellow]Diff tool skipped: {}[/]", richrs::markup::escape(&e.chain())));
+            return;
+        }
+    };
+    match core::patch_code(label, &code, opts).0 {
+        Some(patched) => launch_diff_tool(console, tool, path, &code, &patched),
+        None => {
+            let _ = console.print(&format!("\n [dim]{label}: nothing to diff (no change would be written).[/]"));
+        }
+    }
+}
 
-pub fn cmd_revert() -> Result<()> {
-    let mut console = Console::new();
-    let paths = detect_paths();
+/// Write `original` and `patched` to temp files named after `path` and run
+/// the diff tool on them, waiting for it to exit. `tool` is a command line
+/// split on whitespace; empty means `$DIFFTOOL`, else `git difftool
+/// --no-index`. The temp files are removed afterwards.
+fn launch_diff_tool(console: &mut Console, tool: &str, path: &Path, original: &str, patched: &str) {
+    let tool = match tool {
+        "" => std::env::var("DIFFTOOL")
+            .ok()
+            .filter(|t| !t.trim().is_empty())
+            .unwrap_or_else(|| "git difftool --no-index".to_string()),
+        t => t.to_string(),
+    };
+    let mut argv = tool.split_whitespace();
+    let Some(program) = argv.next() else {
+        return;
+    };
 
-    let _ = console.print(
-        "\n[yellow]This will revert all patches and restore from backups.[/]",
-    );
+    let dir = std::env::temp_dir().join(format!("nupatch-diff-{}", std::process::id()));
+    let name = path.file_name().unwrap_or_default().to_string_lossy();
+    let before = dir.join(format!("{name}.orig"));
+    let after = dir.join(format!("{name}.patched"));
+    let written = fs::create_dir_all(&dir)
+        .and_then(|()| fs::write(&before, original))
+        .and_then(|()| fs::write(&after, patched));
+    let outcome = match written {
+        Err(e) => Err(Error::io("write", &dir, e).chain()),
+        Ok(()) => {
+            let _ = console.print(&format!("\n [dim]Opening {name} in[/] [bold]{}[/][dim]...[/]", richrs::markup::escape(&tool)));
+            // Diff tools exit non-zero just for "files differ", so only a
+            // failure to start counts.
+            std::process::Command::new(program)
+                .args(argv)
+                .arg(&before)
+                .arg(&after)
+                .status()
+                .map(drop)
+                .map_err(|e| format!("could not run {program}: {e}"))
+        }
+    };
+    let _ = fs::remove_dir_all(&dir);
+    if let Err(e) = outcome {
+        let _ = console.print(&format!("\n [yellow]Diff tool failed: {}[/]", richrs::markup::escape(&e)));
+    }
+}
 
-    let confirmed = Confirm::new("Continue?")
-        .default(false)
-        .ask()?;
+/// Read an agent file, or stdin for `-`.
+fn read_agent_input(path: &Path) -> std::result::Result<String, Error> {
+    if path == Path::new("-") {
+        let mut code = String::new();
+        std::io::stdin()
+            .read_to_string(&mut code)
+            .map_err(|e| Error::Io(e).context("failed to read stdin"))?;
+        return Ok(code);
+    }
+    crate::util::read_text(path).map_err(|e| Error::read_text("read", path, e))
+}
 
-    if !confirmed {
-        let _ = console.print("[dim]Aborted.[/]");
-        return Ok(());
+/// `patch --file`: patch an agent file (or stdin, `-`) in memory and write
+/// the result to stdout (or `--out`). The input file is never touched.
+fn patch_input(
+    console: &mut Console,
+    file: &Path,
+    cli_only: bool,
+    ide_only: bool,
+    explain: bool,
+    diff_tool: Option<&str>,
+    opts: &PatchOptions,
+) -> Result<i32> {
+    let (label, title) = match (cli_only, ide_only) {
+        (true, false) => ("CLI", "CLI Agent"),
+        (false, true) => ("IDE", "IDE Agent"),
+        _ => return Err(eyre!("--file needs --cli-only or --ide-only to pick the patch plan")),
+    };
+    let code = read_agent_input(file)?;
+    if explain {
+        display_explanation(console, title, &core::explain_plan(label, opts));
+    }
+    let (patched, result) = core::patch_code(label, &code, opts);
+
+    display_result(console, title, &result);
+    display_regex_trace(console, label, &result.steps);
+    if opts.dry_run {
+        display_dry_run_detail(console, &result.steps);
+        if let (Some(tool), Some(patched)) = (diff_tool, patched.as_deref()) {
+            launch_diff_tool(console, tool, file, &code, patched);
+        }
+    } else if opts.trace_regex {
+        display_failure_context(console, &result.steps);
+    }
+    if let Some(emit) = opts.emit.as_deref()
+        && let Some(patched) = patched.as_deref()
+    {
+        fs::write(emit, patched).map_err(|e| Error::io("write preview to", emit, e))?;
+        let _ = console.print(&format!(
+            "\n [dim]Preview written:[/] {}",
+            richrs::markup::escape(&emit.display().to_string())
+        ));
     }
+    if let Some(out) = opts.out.as_deref() {
+        if let Some(patched) = patched.filter(|_| !opts.dry_run) {
+            let snapshot = history::Snapshot::take(&[out]);
+            fs::write(out, patched).map_err(|e| Error::io("write", out, e))?;
+            history::record("patch", result.success, &[out], &[(label, &result)], snapshot);
+        }
+    } else if let Some(patched) = patched.filter(|_| !opts.dry_run) {
+        std::io::stdout()
+            .write_all(patched.as_bytes())
+            .map_err(|e| Error::Io(e).context("failed to write stdout"))?;
+    }
+    Ok(if result.success { 0 } else { result.failure_kind().map_or(1, |k| k.exit_code()) })
+}
 
-    let result = Status::new("Reverting patches...")
-        .run(|| revert_all(&paths));
+/// `discover`: run pattern discovery on any agent file (or stdin, `-`)
+/// without a Cursor install.
+pub fn cmd_discover(file: &Path) -> Result<()> {
+    let mut console = Console::new();
+    let code = read_agent_input(file)?;
+    let result = core::discover_code(&code);
+    let label = if file == Path::new("-") { "stdin".to_string() } else { file.display().to_string() };
+    display_discovery_result(&mut console, &label, &result);
+    result.map(drop).map_err(Into::into)
+}
+
+/// `compare`: which patches each of two agent files has, then a token
+/// diff of their detectShellType regions. Either file may be `-` (stdin).
+pub fn cmd_compare(file_a: &Path, file_b: &Path) -> Result<()> {
+    let mut console = Console::new();
+    let code_a = read_agent_input(file_a)?;
+    let code_b = read_agent_input(file_b)?;
+    let flags_a = core::patch_flags(&code_a);
+    let flags_b = core::patch_flags(&code_b);
+    let name = |p: &Path| {
+        if p == Path::new("-") { "stdin".to_string() } else { p.display().to_string() }
+    };
 
     let _ = console.print("");
-    for f in &result.files {
-        if f.restored {
-            let _ = console.print(&format!(
-                "  [bold green]  OK[/]  Restored: {}",
-                f.filename
-            ));
-        } else {
+    let mut table = Table::new()
+        .title("Patch Comparison")
+        .border_style(Style::parse("bright_cyan").unwrap_or_default())
+        .header_style(Style::parse("bold magenta").unwrap_or_default());
+    table.add_column(Column::new("Patch").style(Style::new().bold()));
+    table.add_column(Column::new("A"));
+    table.add_column(Column::new("B"));
+    let flags = flags_a.as_ref().or(flags_b.as_ref()).cloned().unwrap_or_default();
+    for (patch, _) in &flags {
+        let cell = |f: &Option<Vec<(&str, bool)>>| match f {
+            None => "[dim]--[/]",
+            Some(f) if f.iter().any(|(n, has)| n == patch && *has) => "[bold green]present[/]",
+            Some(_) => "[bold red]absent[/]",
+        };
+        let differs = flags_a.is_some() && flags_b.is_some() && cell(&flags_a) != cell(&flags_b);
+        let label = if differs { format!("{patch} [yellow]*[/]") } else { patch.to_string() };
+        table.add_row(Row::new([markup(&label), markup(cell(&flags_a)), markup(cell(&flags_b))]));
+    }
+    let width = console.width();
+    let _ = print_renderable(&mut console, &table.render(width));
+    let _ = console.print(&format!(
+        " [dim]A:[/] {}\n [dim]B:[/] {}",
+        richrs::markup::escape(&name(file_a)),
+        richrs::markup::escape(&name(file_b))
+    ));
+    for (file, flags) in [(file_a, &flags_a), (file_b, &flags_b)] {
+        if flags.is_none() {
             let _ = console.print(&format!(
-                "  [dim]SKIP[/]  No backup: {}",
-                f.filename
+                " [yellow]{} is not recognizable agent code (no zsh check).[/]",
+                richrs::markup::escape(&name(file))
             ));
         }
     }
 
+    let (Some(region_a), Some(region_b)) =
+        (core::shell_type_region(&code_a), core::shell_type_region(&code_b))
+    else {
+        return Ok(());
+    };
     let _ = console.print("");
-    let width = console.width();
-    let panel = Panel::new(markup(
-        "[bold green]Revert complete.[/]\nRestart Cursor to apply.",
-    ))
-    .title("Done")
-    .border_style(Style::parse("green").unwrap_or_default());
-    let _ = print_renderable(&mut console, &panel.render(width));
-
+    let rule = Rule::with_title(markup("[bold]detectShellType region[/]"))
+        .style(Style::parse("bright_cyan").unwrap_or_default());
+    let _ = print_renderable(&mut console, &rule.render(width));
+    let diff = core::diff_regions(region_a, region_b);
+    if diff.iter().all(|d| matches!(d, core::RegionDiff::Same(_))) {
+        let _ = console.print(" [green]Identical[/]");
+        return Ok(());
+    }
+    // wdiff style, so the diff still reads without colour.
+    let text: String = diff
+        .iter()
+        .map(|d| match d {
+            core::RegionDiff::Same(t) => richrs::markup::escape(t),
+            core::RegionDiff::Removed(t) => format!("[bold red]\\[-{}-\\][/]", richrs::markup::escape(t)),
+            core::RegionDiff::Added(t) => format!("[bold green]{{+{}+}}[/]", richrs::markup::escape(t)),
+        })
+        .collect();
+    let _ = console.print(&format!("...{text}..."));
+    let _ = console.print(r" [dim]\[-removed in B-\] {+added in B+}[/]");
     Ok(())
 }
 
-// ---------------------------------------------------------------------------
-//  status
-// ---------------------------------------------------------------------------
+/// `status --json`: print the full status and return the overall state's
+/// exit code.
+pub fn cmd_status_json() -> Result<i32> {
+    let paths = detect_paths();
+    let st = check_status(&paths);
+    let state = st.overall();
+    let out = report::status_json(&st, paths.packed_asar.as_deref());
+    println!("{}", serde_json::to_string_pretty(&out)?);
+    Ok(state.exit_code())
+}
+
+/// `status --check`: silent unless `verbose`, returns the exit code (0 when
+/// fully patched and integrity-clean, otherwise the OR of failing bits).
+pub fn cmd_status_check(verbose: bool) -> i32 {
+    let st = check_status(&detect_paths());
+    let failures = check_failures(&st);
+    if verbose {
+        let mut console = Console::new();
+        for f in &failures {
+            let _ = console.print(&format!(
+                "[bold red]FAIL[/]  {} [dim](exit bit {})[/]",
+                f.describe(),
+                *f as i32
+            ));
+        }
+        if failures.is_empty() {
+            let _ = console.print("[bold green]  OK[/]  Fully patched, integrity clean");
+        }
+        let _ = console.print(&format!("[dim]state: {}[/]", st.overall().as_str()));
+    }
+    failures.iter().fold(0, |code, f| code | *f as i32)
+}
 
-pub fn cmd_status() -> Result<()> {
+pub fn cmd_status(verbose: bool) -> Result<()> {
     let mut console = Console::new();
     let paths = detect_paths();
 
     let st = Status::new("Checking status...")
         .run(|| check_status(&paths));
+    display_status(&mut console, &paths, &st, verbose);
+    Ok(())
+}
+
+/// `status --watch`: clear and redraw the status every `interval` seconds
+/// until interrupted. Read-only; paths are re-detected on every pass so a
+/// new CLI version or reinstalled app shows up.
+pub fn cmd_status_watch(verbose: bool, interval: u64) -> Result<()> {
+    let mut console = Console::new();
+    loop {
+        let paths = detect_paths();
+        let st = check_status(&paths);
+        let _ = console.clear();
+        display_status(&mut console, &paths, &st, verbose);
+        let _ = console.print(&format!(
+            " [dim]Watching -- refreshing every {interval}s. Press Ctrl-C to exit.[/]"
+        ));
+        std::thread::sleep(std::time::Duration::from_secs(interval));
+    }
+}
+
+/// `status --from-report`: render a saved report -- the run's results, then
+/// the status as it was when the report was written. Touches nothing live.
+pub fn cmd_status_from_report(file: &Path) -> Result<()> {
+    let mut console = Console::new();
+    let saved = Report::read(file).wrap_err("Could not load the report")?;
+    let _ = console.print("");
+    let _ = console.print(&format!(
+        " [dim]Report of[/] [bold]nupatch {}[/] [dim]from {} (nupatch {})[/]",
+        saved.command,
+        relative_time(saved.created),
+        saved.nupatch
+    ));
+    for (label, result) in &saved.results {
+        let title = match label.as_str() {
+            "CLI" => "CLI Agent",
+            "IDE" => "IDE Agent",
+            "Integrity" => "Integrity Chain",
+            other => other,
+        };
+        display_result(&mut console, title, result);
+    }
+    if let Some(verify) = &saved.verify {
+        // The verdict is part of the rendering here, not an error.
+        let _ = display_verify(&mut console, verify, &[]);
+    }
+    let paths = CursorPaths { packed_asar: saved.packed_asar.clone(), ..CursorPaths::default() };
+    display_status(&mut console, &paths, &saved.status, false);
+    Ok(())
+}
 
+fn display_status(console: &mut Console, paths: &CursorPaths, st: &PatchStatus, verbose: bool) {
     let _ = console.print("");
+    let compat = match st.cursor.version.as_deref().and_then(compat_for) {
+        Some(Compat::Works) => " -- [green]known to work[/]".to_string(),
+        Some(Compat::Partial(note)) => format!(" -- [yellow]partially supported:[/] [dim]{note}[/]"),
+        Some(Compat::Broken(note)) => format!(" -- [red]known broken:[/] [dim]{note}[/]"),
+        None => String::new(),
+    };
+    let _ = console.print(&format!(" [dim]Cursor {}[/]{compat}", st.cursor.describe()));
+    if let (Some(channel), Some(other)) = (paths.channel, paths.other_channel) {
+        let _ = console.print(&format!(
+            " [dim]Using the {} install; {} is installed too (pick one with --channel).[/]",
+            channel.as_str(),
+            other.as_str()
+        ));
+    }
+    display_version_warning(console, &st.cursor);
+    if paths.packed_asar.is_some() {
+        let _ = console.print("");
+        let width = console.width();
+        let panel = Panel::new(missing_app_error(paths).to_string())
+            .title("Packed resources")
+            .border_style(Style::parse("yellow").unwrap_or_default());
+        let _ = print_renderable(console, &panel.render(width));
+    }
 
     // Table
     let mut table = Table::new()
@@ -365,11 +2134,12 @@ pub fn cmd_status() -> Result<()> {
     table.add_column(Column::new("Backup"));
     table.add_column(Column::new("Patches").min_width(30));
     table.add_column(Column::new("Status"));
+    table.add_column(Column::new("Last patched"));
 
     // Collect all patch names to find the longest for padding.
     let all_names: Vec<&String> = [&st.cli, &st.ide]
         .iter()
-        .flat_map(|c| c.patches.keys())
+        .flat_map(|c| c.patches.keys().chain(&c.skipped))
         .collect();
     let max_name_len = all_names.iter().map(|n| n.len()).max().unwrap_or(0);
 
@@ -388,6 +2158,10 @@ pub fn cmd_status() -> Result<()> {
                 };
                 patch_parts.push(format!("{padded_name}: {tag}"));
             }
+            for name in &comp.skipped {
+                let padded_name = format!("{:width$}", name, width = max_name_len);
+                patch_parts.push(format!("{padded_name}: [dim]SKIPPED[/]"));
+            }
             let patches_str = if patch_parts.is_empty() {
                 "[dim]unknown[/]".to_string()
             } else {
@@ -406,18 +2180,24 @@ pub fn cmd_status() -> Result<()> {
             };
 
             let file_name = comp.path.as_deref().unwrap_or("N/A");
-            let backup_str = if comp.backup_exists {
-                "[green]exists[/]"
-            } else {
-                "[dim]none[/]"
+            let backup_str = match (comp.backup_exists, comp.backup_pristine) {
+                (false, _) => "[dim]none[/]",
+                (true, Some(true)) => "[green]pristine[/]",
+                (true, Some(false)) => "[bold yellow]patched![/]",
+                (true, None) => "[green]exists[/]",
             };
 
+            let last_patched = comp
+                .last_patched
+                .map_or_else(|| "[dim]unknown[/]".to_string(), relative_time);
+
             table.add_row(Row::new([
                 markup(label),
                 markup(file_name),
                 markup(backup_str),
                 markup(&patches_str),
                 markup(status_str),
+                markup(&last_patched),
             ]));
         } else {
             table.add_row(Row::new([
@@ -426,12 +2206,33 @@ pub fn cmd_status() -> Result<()> {
                 markup(""),
                 markup(""),
                 markup(""),
+                markup(""),
             ]));
         }
     }
 
     let width = console.width();
-    let _ = print_renderable(&mut console, &table.render(width));
+    let _ = print_renderable(console, &table.render(width));
+
+    for (label, comp) in [("CLI", &st.cli), ("IDE", &st.ide)] {
+        if comp.backup_pristine == Some(false) {
+            let _ = console.print(&format!(
+                " [bold yellow]{label} backup already contains patches[/] -- \
+                 revert will not restore the original."
+            ));
+        }
+    }
+    display_newer_unpatched(console, st.cli.newer_unpatched.as_deref());
+    if st.cli.exists && st.cli.recorded_hash_matches == Some(false) {
+        let _ = console.print(
+            " [bold yellow]CLI agent changed since nupatch patched it[/] -- \
+             Cursor probably updated it; run [bold]nupatch patch[/] again.",
+        );
+    }
+
+    if verbose {
+        display_discovery(console, paths);
+    }
 
     // Integrity
     let mut int_lines: Vec<String> = Vec::new();
@@ -443,11 +2244,11 @@ pub fn cmd_status() -> Result<()> {
         };
         int_lines.push(format!("EHP hash matches main.js:  {icon}"));
     }
-    if let Some(matches) = st.integrity.product_checksums_match {
-        let icon = if matches {
-            "[green]ALL MATCH[/]"
-        } else {
-            "[red]MISMATCH[/]"
+    if st.integrity.product_json_found {
+        let icon = match st.integrity.product_checksums_match {
+            Some(true) => "[green]ALL MATCH[/]",
+            Some(false) => "[red]MISMATCH[/]",
+            None => "[yellow]UNKNOWN[/] [dim](no checksums section)[/]",
         };
         int_lines.push(format!("product.json checksums:    {icon}"));
     }
@@ -458,55 +2259,182 @@ pub fn cmd_status() -> Result<()> {
         let panel = Panel::new(markup(&content))
             .title("Integrity")
             .border_style(Style::parse("blue").unwrap_or_default());
-        let _ = print_renderable(&mut console, &panel.render(width));
+        let _ = print_renderable(console, &panel.render(width));
     }
 
     // Overall
-    let cli_ok =
-        st.cli.exists && !st.cli.patches.is_empty() && st.cli.patches.values().all(|&v| v);
-    let ide_ok =
-        st.ide.exists && !st.ide.patches.is_empty() && st.ide.patches.values().all(|&v| v);
-
-    let overall = if cli_ok && ide_ok {
-        "[bold green]PATCHED[/] (both CLI + IDE)"
-    } else if ide_ok {
-        "[bold green]PATCHED[/] (IDE only)"
-    } else if cli_ok {
-        "[bold green]PATCHED[/] (CLI only)"
-    } else {
-        "[dim]ORIGINAL[/] (no patches applied)"
+    let cli_ok = st.cli.fully_patched();
+    let ide_ok = st.ide.fully_patched();
+
+    let overall = match st.overall() {
+        OverallState::FullyPatched if cli_ok && ide_ok => "[bold green]PATCHED[/] (both CLI + IDE)",
+        OverallState::FullyPatched if ide_ok => "[bold green]PATCHED[/] (IDE only)",
+        OverallState::FullyPatched => "[bold green]PATCHED[/] (CLI only)",
+        OverallState::PartiallyPatched if ide_ok => "[bold yellow]PARTIAL[/] (IDE only)",
+        OverallState::PartiallyPatched if cli_ok => "[bold yellow]PARTIAL[/] (CLI only)",
+        OverallState::PartiallyPatched => "[bold yellow]PARTIAL[/]",
+        OverallState::IntegrityMismatch => "[bold red]INTEGRITY MISMATCH[/] (run fix-checksums)",
+        OverallState::Original => "[dim]ORIGINAL[/] (no patches applied)",
+        OverallState::NotFound => "[dim]NOT FOUND[/] (no Cursor agents detected)",
     };
 
     let _ = console.print("");
     let overall_title = format!("Overall: {overall}");
     let rule = Rule::with_title(markup(&overall_title))
         .style(Style::parse("bright_cyan").unwrap_or_default());
-    let _ = print_renderable(&mut console, &rule.render(width));
+    let _ = print_renderable(console, &rule.render(width));
     let _ = console.print("");
-
-    Ok(())
 }
 
 // ---------------------------------------------------------------------------
 //  verify
 // ---------------------------------------------------------------------------
 
-pub fn cmd_verify() -> Result<()> {
+/// An agent checked against the manifest by `verify --include-agents`:
+/// label, path, and the check (`None` if nothing is recorded).
+type AgentCheck<'a> = (&'static str, &'a Path, Option<manifest::Check>);
+
+/// How an agent compares to what the manifest recorded, as in
+/// `verify --json`'s `agents`.
+fn agent_state(check: Option<&manifest::Check>) -> &'static str {
+    match check {
+        None => "unrecorded",
+        Some(c) if c.matches() => "match",
+        Some(c) if c.is_original() => "original",
+        Some(_) => "changed",
+    }
+}
+
+/// Print verify results as JSON, with the CLI agent's hash check under
+/// `cli` and the `--include-agents` checks under `agents`.
+fn print_verify_json(
+    result: &integrity::VerifyResult,
+    cli: Option<(&Path, &manifest::Check)>,
+    agents: &[AgentCheck],
+) -> Result<()> {
+    println!("{}", serde_json::to_string_pretty(&verify_json(result, cli, agents))?);
+    Ok(())
+}
+
+/// The `verify --json` document: [`report::verify_json`] plus the CLI and
+/// agent checks. Hashes are full-length, unlike the table's shortened form.
+fn verify_json(
+    result: &integrity::VerifyResult,
+    cli: Option<(&Path, &manifest::Check)>,
+    agents: &[AgentCheck],
+) -> serde_json::Value {
+    let mut out = report::verify_json(result);
+    if let Some((path, check)) = cli {
+        out["cli"] = check.to_json(path);
+    }
+    if !agents.is_empty() {
+        out["agents"] = agents
+            .iter()
+            .map(|(label, path, check)| {
+                let mut agent = check.as_ref().map_or_else(|| serde_json::json!({ "path": path }), |c| c.to_json(path));
+                agent["agent"] = label.to_string().into();
+                agent["state"] = agent_state(check.as_ref()).into();
+                agent
+            })
+            .collect();
+    }
+    out
+}
+
+/// `verify`. With `cli`, also check the CLI agent against the hash recorded
+/// when nupatch patched it; with `include_agents`, both agents, as rows of
+/// the verify table. With `report`, the results and the resulting status
+/// are also saved there (`status --from-report`).
+pub fn cmd_verify(json: bool, cli: bool, include_agents: bool, report: Option<&Path>) -> Result<()> {
     let mut console = Console::new();
     let paths = detect_paths();
-    let (cursor_app, product_json) = require_paths(
-        &mut console,
-        paths.cursor_app.as_deref(),
-        paths.product_json.as_deref(),
-    )?;
+    let (cursor_app, product_json) = require_paths(&mut console, &paths)?;
+    let cli_index = paths.cli_index.as_deref().filter(|_| cli);
+    let cli_check = cli_index.and_then(manifest::check);
+    let cli_changed = cli_check.as_ref().is_some_and(|c| !c.matches());
+    let agents: Vec<AgentCheck> = [("IDE", paths.ide_main.as_deref()), ("CLI", paths.cli_index.as_deref())]
+        .into_iter()
+        .filter(|_| include_agents)
+        .filter_map(|(label, path)| Some((label, path?, path.and_then(manifest::check))))
+        .collect();
+    let agents_changed = agents.iter().any(|(_, _, check)| agent_state(check.as_ref()) == "changed");
+
+    if json {
+        let result = integrity::verify_checksums(product_json, cursor_app)
+            .wrap_err("Checksum verification failed")?;
+        print_verify_json(&result, cli_index.zip(cli_check.as_ref()), &agents)?;
+        let all_match = result.all_match;
+        if let Some(path) = report {
+            save_verify_report(path, &paths, result)?;
+        }
+        if !all_match {
+            return Err(eyre!("Checksum mismatch found"));
+        }
+        if cli_changed || agents_changed {
+            return Err(eyre!("Agent changed since it was patched"));
+        }
+        return Ok(());
+    }
 
     let result = Status::new("Verifying checksums...")
         .run(|| {
             integrity::verify_checksums(product_json, cursor_app)
-        })?;
+        })
+        .wrap_err("Checksum verification failed")?;
+    let outcome = display_verify(&mut console, &result, &agents);
+    if cli {
+        display_cli_check(&mut console, cli_index, cli_check.as_ref());
+    }
+    if let Some(path) = report {
+        save_verify_report(path, &paths, result)?;
+        let _ = console.print(&format!(
+            " [dim]Report written to {}[/]",
+            richrs::markup::escape(&path.display().to_string())
+        ));
+    }
+    if (cli_changed || agents_changed) && outcome.is_ok() {
+        return Err(eyre!("Agent changed since it was patched"));
+    }
+    outcome
+}
+
+/// `verify --cli`: the CLI agent against its recorded hash.
+fn display_cli_check(console: &mut Console, cli_index: Option<&Path>, check: Option<&manifest::Check>) {
+    let line = match (cli_index, check) {
+        (None, _) => "[dim]CLI agent not found.[/]".to_string(),
+        (Some(_), None) => "[dim]No hash recorded for the CLI agent; run[/] [bold]nupatch patch[/] [dim]to record one.[/]"
+            .to_string(),
+        (Some(_), Some(c)) if c.matches() => {
+            format!("[bold green]CLI AGENT MATCHES[/] [dim]the hash recorded when it was patched ({})[/]", short_hash(&c.recorded))
+        }
+        (Some(_), Some(c)) => format!(
+            "[bold red]CLI AGENT CHANGED[/] since it was patched [dim](recorded {}, now {})[/]\n\
+             Cursor probably updated the agent; run [bold]nupatch patch[/] again.",
+            short_hash(&c.recorded),
+            c.actual.as_deref().map_or("missing", short_hash)
+        ),
+    };
+    let _ = console.print(&format!(" {line}"));
+    let _ = console.print("");
+}
 
+fn save_verify_report(path: &Path, paths: &CursorPaths, result: integrity::VerifyResult) -> Result<()> {
+    let mut report = Report::new("verify", check_status(paths), paths.packed_asar.clone());
+    report.verify = Some(result);
+    report.write(path).wrap_err("Could not save the report")
+}
+
+/// The verify table and verdict, with an "Agents" section for `agents`.
+/// Errors when checksums are stale or mismatched, so `verify` exits
+/// non-zero.
+fn display_verify(console: &mut Console, result: &integrity::VerifyResult, agents: &[AgentCheck]) -> Result<()> {
     let _ = console.print("");
 
+    if result.entries.is_empty() {
+        display_empty_checksums_warning(console);
+        return Ok(());
+    }
+
     let mut table = Table::new()
         .title("Checksum Verification")
         .border_style(Style::parse("bright_cyan").unwrap_or_default())
@@ -521,6 +2449,8 @@ pub fn cmd_verify() -> Result<()> {
             "[bold red]MISSING[/]"
         } else if entry.matches {
             "[bold green]MATCH[/]"
+        } else if entry.stale {
+            "[bold yellow]STALE[/]"
         } else {
             "[bold red]MISMATCH[/]"
         };
@@ -543,10 +2473,56 @@ pub fn cmd_verify() -> Result<()> {
             markup(status_str),
         ]));
     }
+    if !agents.is_empty() {
+        table.add_row(Row::new([markup("[bold]Agents[/]"), markup(""), markup(""), markup("")]));
+    }
+    for (label, path, check) in agents {
+        let name = path.file_name().map_or_else(|| path.to_string_lossy(), |n| n.to_string_lossy());
+        let status_str = match agent_state(check.as_ref()) {
+            "match" => "[bold green]MATCH[/]",
+            "original" => "[bold green]ORIGINAL[/]",
+            "changed" => "[bold red]CHANGED[/]",
+            _ => "[dim]NOT RECORDED[/]",
+        };
+        let expected = check.as_ref().map_or("", |c| short_hash(&c.recorded));
+        let actual = match check {
+            Some(c) => c.actual.as_deref().map_or("missing", short_hash),
+            None => "",
+        };
+        table.add_row(Row::new([
+            markup(&format!("{name} ({label})")),
+            markup(expected),
+            markup(actual),
+            markup(status_str),
+        ]));
+    }
 
     let width = console.width();
-    let _ = print_renderable(&mut console, &table.render(width));
+    let _ = print_renderable(console, &table.render(width));
     let _ = console.print("");
+    for (a, b) in &result.case_clashes {
+        let _ = console.print(&format!(
+            " [yellow]Keys differ only in case and name the same file here:[/] {}, {}",
+            richrs::markup::escape(a),
+            richrs::markup::escape(b)
+        ));
+    }
+    if !result.backslash_keys.is_empty() {
+        let _ = console.print(&format!(
+            " [yellow]{} key(s) use \\ instead of / as the separator[/] [dim](e.g. {}); \
+             resolved anyway, but the tool that wrote product.json may be at fault.[/]",
+            result.backslash_keys.len(),
+            richrs::markup::escape(&result.backslash_keys[0])
+        ));
+    }
+    if !result.case_clashes.is_empty() || !result.backslash_keys.is_empty() {
+        let _ = console.print("");
+    }
+
+    let only_stale = result
+        .entries
+        .iter()
+        .all(|e| e.matches || e.stale);
 
     if result.all_match {
         let panel = Panel::new(markup(
@@ -554,20 +2530,115 @@ pub fn cmd_verify() -> Result<()> {
              corruption warning will not appear",
         ))
         .border_style(Style::parse("green").unwrap_or_default());
-        let _ = print_renderable(&mut console, &panel.render(width));
+        let _ = print_renderable(console, &panel.render(width));
+    } else if only_stale {
+        let panel = Panel::new(markup(
+            "[bold yellow]STALE CHECKSUMS[/]  --  \
+             files changed after product.json, run [bold]nupatch fix-checksums[/]",
+        ))
+        .border_style(Style::parse("yellow").unwrap_or_default());
+        let _ = print_renderable(console, &panel.render(width));
+        return Err(eyre!("Stale checksums found"));
     } else {
         let panel = Panel::new(markup(
             "[bold red]MISMATCH FOUND[/]  --  \
              corruption warning will appear",
         ))
         .border_style(Style::parse("red").unwrap_or_default());
-        let _ = print_renderable(&mut console, &panel.render(width));
+        let _ = print_renderable(console, &panel.render(width));
         return Err(eyre!("Checksum mismatch found"));
     }
 
     Ok(())
 }
 
+// ---------------------------------------------------------------------------
+//  diff-checksums
+// ---------------------------------------------------------------------------
+
+/// Split `s` into lines of at most `width` chars, breaking at spaces where
+/// possible (minified patterns have none, so those are cut mid-token).
+fn wrap_text(s: &str, width: usize) -> Vec<String> {
+    let width = width.max(8);
+    let mut lines = Vec::new();
+    let mut line = String::new();
+    for word in s.split(' ') {
+        let sep = usize::from(!line.is_empty());
+        if line.chars().count() + sep + word.chars().count() > width && !line.is_empty() {
+            lines.push(std::mem::take(&mut line));
+        }
+        if !line.is_empty() {
+            line.push(' ');
+        }
+        line.push_str(word);
+        while line.chars().count() > width {
+            let split = line.char_indices().nth(width).map_or(line.len(), |(i, _)| i);
+            let rest = line.split_off(split);
+            lines.push(std::mem::replace(&mut line, rest));
+        }
+    }
+    if !line.is_empty() {
+        lines.push(line);
+    }
+    lines
+}
+
+/// First 8 characters of a hash for compact display.
+fn short_hash(hash: &str) -> &str {
+    hash.get(..8).unwrap_or(hash)
+}
+
+pub fn cmd_diff_checksums() -> Result<()> {
+    let mut console = Console::new();
+    let paths = detect_paths();
+    let (cursor_app, product_json) = require_paths(&mut console, &paths)?;
+
+    let result = Status::new("Comparing checksums...")
+        .run(|| {
+            integrity::verify_checksums(product_json, cursor_app)
+        })
+        .wrap_err("Checksum verification failed")?;
+
+    let _ = console.print("");
+
+    if result.entries.is_empty() {
+        display_empty_checksums_warning(&mut console);
+        return Ok(());
+    }
+
+    let mut differing = 0usize;
+    for entry in result.entries.iter().filter(|e| !e.matches) {
+        differing += 1;
+        if entry.missing {
+            let _ = console.print(&format!(
+                "  [bold red]MISS[/]  {}: stored {} → [dim]file missing[/]",
+                entry.rel_path,
+                short_hash(&entry.expected)
+            ));
+        } else {
+            let _ = console.print(&format!(
+                "  [bold yellow]DIFF[/]  {}: stored {} → actual {}",
+                entry.rel_path,
+                short_hash(&entry.expected),
+                short_hash(&entry.actual)
+            ));
+        }
+    }
+
+    if differing == 0 {
+        let _ = console.print("  [dim]No differences.[/]");
+        let _ = console.print("");
+        return Ok(());
+    }
+
+    let _ = console.print(&format!(
+        "\n  [bold]{differing}[/] of {} checksum(s) differ.",
+        result.entries.len()
+    ));
+    let _ = console.print("");
+    Err(eyre!("{differing} checksum(s) differ"))
+}
+
 // ---------------------------------------------------------------------------
 //  fix-checksums
 // ---------------------------------------------------------------------------
@@ -575,19 +2646,22 @@ pub fn cmd_verify() -> Result<()> {
 pub fn cmd_fix_checksums() -> Result<()> {
     let mut console = Console::new();
     let paths = detect_paths();
-    let (cursor_app, product_json) = require_paths(
-        &mut console,
-        paths.cursor_app.as_deref(),
-        paths.product_json.as_deref(),
-    )?;
+    let (cursor_app, product_json) = require_paths(&mut console, &paths)?;
 
+    let snapshot = history::Snapshot::take(&[product_json]);
     let result = Status::new("Fixing checksums...")
         .run(|| {
             integrity::fix_checksums(product_json, cursor_app)
-        })?;
+        });
+    let result = result.wrap_err("Checksum fix failed")?;
 
     let _ = console.print("");
 
+    if result.entries.is_empty() {
+        display_empty_checksums_warning(&mut console);
+        return Ok(());
+    }
+
     for entry in &result.entries {
         match entry.status {
             integrity::FixStatus::Ok => {
@@ -615,9 +2689,25 @@ pub fn cmd_fix_checksums() -> Result<()> {
     let width = console.width();
 
     if result.changed_count > 0 {
+        let updated: Vec<&str> = result
+            .entries
+            .iter()
+            .filter(|e| matches!(e.status, integrity::FixStatus::Updated))
+            .map(|e| e.rel_path.as_str())
+            .collect();
+        let fixed = PatchResult {
+            success: true,
+            steps: vec![
+                StepResult::ok("Product checksums", format!("Updated {} checksum(s)", result.changed_count))
+                    .with_detail(updated.join("\n")),
+            ],
+        };
+        history::record("fix-checksums", true, &[product_json], &[("Integrity", &fixed)], snapshot);
+
         let content = format!(
-            "[bold green]Updated {} checksum(s).[/]\nRestart Cursor to apply.",
-            result.changed_count
+            "[bold green]Updated {} checksum(s).[/]{}\nRestart Cursor to apply.",
+            result.changed_count,
+            crate::util::retried_note(result.write_retries)
         );
         let panel = Panel::new(markup(&content))
             .title("Done")
@@ -631,3 +2721,65 @@ pub fn cmd_fix_checksums() -> Result<()> {
 
     Ok(())
 }
+
+/// `--trace`: the recorded phase timings, nested, on stderr.
+pub fn display_timings() {
+    let timings = crate::timing::take();
+    if timings.is_empty() {
+        return;
+    }
+    let mut console = Console::stderr();
+    let lines: Vec<String> = timings
+        .iter()
+        .map(|(depth, label, elapsed)| {
+            format!(
+                "{:>10.2} ms  {}{}",
+                elapsed.as_secs_f64() * 1000.0,
+                "  ".repeat(*depth),
+                richrs::markup::escape(label)
+            )
+        })
+        .collect();
+    let _ = console.print("");
+    let width = console.width();
+    let panel = Panel::new(markup(&lines.join("\n")))
+        .title(markup("[bold]Timings[/]"))
+        .border_style(Style::parse("blue").unwrap_or_default());
+    let _ = print_renderable(&mut console, &panel.render(width));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::TempDir;
+
+    #[test]
+    fn verify_json_hashes_are_full_length() {
+        let app = TempDir::new("verify-json");
+        app.write("out/vs/a.js", "patched");
+        let stale = "x".repeat(43);
+        let product_json = app.write("product.json", format!("{{\"checksums\": {{\"vs/a.js\": \"{stale}\"}}}}"));
+
+        let result = integrity::verify_checksums(&product_json, &app.0).unwrap();
+        let out = verify_json(&result, None, &[]);
+        let entry = &out["entries"][0];
+        assert_eq!(entry["matches"], false);
+        assert_eq!(entry["expected"], stale.as_str());
+        let actual = entry["actual"].as_str().unwrap();
+        assert_eq!(actual.len(), 43);
+        assert!(!actual.contains("..."));
+    }
+
+    #[test]
+    fn verify_json_flags_missing_checksums() {
+        let app = TempDir::new("verify-json-empty");
+        for product in [r#"{"nameShort": "Cursor"}"#, r#"{"checksums": {}}"#] {
+            let product_json = app.write("product.json", product);
+            let result = integrity::verify_checksums(&product_json, &app.0).unwrap();
+            let out = verify_json(&result, None, &[]);
+            assert_eq!(out["all_match"], serde_json::Value::Null, "{product}");
+            assert_eq!(out["checksums_missing"], true, "{product}");
+            assert!(out["warning"].is_string(), "{product}");
+        }
+    }
+}
@@ -6,12 +6,22 @@
 use std::borrow::Cow;
 use std::collections::HashMap;
 use std::fs;
-use std::path::Path;
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use std::sync::{LazyLock, Mutex};
+use std::time::SystemTime;
 
-use crate::util::{lazy_re, re};
+use fancy_regex::{Captures, Regex};
 
-use crate::integrity::{backup, bak_path, checksums_all_match, restore_from_backup, sha256_hex};
-use crate::paths::CursorPaths;
+use crate::util::{
+    contains_in_file, is_backtrack_limit, lazy_re, re, re_for, read_text, retried_note, scan_file, write_retrying,
+};
+
+use crate::error::Error;
+use crate::integrity::{backup, bak_path, checksums_all_match, restore_from_backup, sha256_hex, stored_original};
+use crate::manifest::{self, Manifest};
+use crate::paths::{CursorPaths, cli_agent_indexes, cli_version_key, sort_cli_indexes};
+use crate::rules::{RuleMode, RuleTarget, UserRule};
 
 /// Safe display name for a path -- falls back to full path if `file_name()` is `None`.
 fn display_name(path: &Path) -> Cow<'_, str> {
@@ -27,27 +37,106 @@ fn display_name(path: &Path) -> Cow<'_, str> {
 /// Result of a single patch step.
 #[derive(Debug)]
 pub struct StepResult {
-    pub name: &'static str,
+    pub name: Cow<'static, str>,
     pub ok: bool,
     pub message: String,
     pub skipped: bool,
     pub detail: String,
+    /// Produced by a user rule (`--rules` / `--patch-script`) rather than a
+    /// built-in step.
+    pub user_rule: bool,
+    /// Why the step failed, when known. `None` for successful steps.
+    pub kind: Option<FailureKind>,
+    /// Regex-by-regex discovery log (`--trace-regex`), on the discovery step.
+    pub trace: Option<String>,
+    /// The byte range of the code it was given that a step replaced (empty
+    /// for an insertion). Everything before and after it must be unchanged.
+    pub edit: Option<Range<usize>>,
+}
+
+/// Category of a failed step, so callers can branch on the failure without
+/// matching message text. `patch` exits with `exit_code()` of the first
+/// failure:
+///
+/// | Kind              | Exit code |
+/// |-------------------|-----------|
+/// | (uncategorized)   | 1         |
+/// | `Io`              | 2         |
+/// | `Discovery`       | 3         |
+/// | `PatternNotFound` | 4         |
+/// | `Validation`      | 5         |
+/// | `InvalidFile`     | 6         |
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FailureKind {
+    /// Reading, writing, backing up or hashing a file failed.
+    Io,
+    /// Minified names couldn't be discovered: the file layout isn't recognized.
+    Discovery,
+    /// A patch anchor or embedded hash wasn't found where expected.
+    PatternNotFound,
+    /// User rules don't fit this file, or the patched code would change
+    /// its encoding.
+    Validation,
+    /// A file exists but is malformed (e.g. product.json fails to parse, an
+    /// agent isn't UTF-8).
+    InvalidFile,
+}
+
+impl FailureKind {
+    pub fn exit_code(self) -> i32 {
+        match self {
+            Self::Io => 2,
+            Self::Discovery => 3,
+            Self::PatternNotFound => 4,
+            Self::Validation => 5,
+            Self::InvalidFile => 6,
+        }
+    }
 }
 
 impl StepResult {
-    pub fn ok(name: &'static str, msg: impl Into<String>) -> Self {
-        Self { name, ok: true, message: msg.into(), skipped: false, detail: String::new() }
+    fn new(name: Cow<'static, str>, ok: bool, message: String, skipped: bool) -> Self {
+        Self {
+            name,
+            ok,
+            message,
+            skipped,
+            detail: String::new(),
+            user_rule: false,
+            kind: None,
+            trace: None,
+            edit: None,
+        }
+    }
+    pub fn ok(name: impl Into<Cow<'static, str>>, msg: impl Into<String>) -> Self {
+        Self::new(name.into(), true, msg.into(), false)
     }
-    pub fn fail(name: &'static str, msg: impl Into<String>) -> Self {
-        Self { name, ok: false, message: msg.into(), skipped: false, detail: String::new() }
+    pub fn fail(name: impl Into<Cow<'static, str>>, msg: impl Into<String>) -> Self {
+        Self::new(name.into(), false, msg.into(), false)
     }
-    pub fn skipped(name: &'static str, msg: impl Into<String>) -> Self {
-        Self { name, ok: true, message: msg.into(), skipped: true, detail: String::new() }
+    pub fn skipped(name: impl Into<Cow<'static, str>>, msg: impl Into<String>) -> Self {
+        Self::new(name.into(), true, msg.into(), true)
     }
     pub fn with_detail(mut self, detail: impl Into<String>) -> Self {
         self.detail = detail.into();
         self
     }
+    pub fn with_kind(mut self, kind: FailureKind) -> Self {
+        self.kind = Some(kind);
+        self
+    }
+    fn with_trace(mut self, trace: Option<String>) -> Self {
+        self.trace = trace;
+        self
+    }
+    fn with_edit(mut self, edit: Range<usize>) -> Self {
+        self.edit = Some(edit);
+        self
+    }
+    fn mark_user_rule(mut self) -> Self {
+        self.user_rule = true;
+        self
+    }
 }
 
 /// Result of a patch / integrity operation.
@@ -57,34 +146,196 @@ pub struct PatchResult {
     pub steps: Vec<StepResult>,
 }
 
+impl PatchResult {
+    /// Kind of the first failed step, if it was categorized.
+    pub fn failure_kind(&self) -> Option<FailureKind> {
+        self.steps.iter().find(|s| !s.ok).and_then(|s| s.kind)
+    }
+
+    /// Whether the patched file was written, even if the run failed
+    /// (`--continue-on-error --force`).
+    pub fn written(&self) -> bool {
+        self.steps.iter().any(|s| s.name == "Write" && s.ok && !s.skipped)
+    }
+}
+
 /// Status of a single component (CLI or IDE).
 #[derive(Default)]
 pub struct ComponentStatus {
     pub path: Option<String>,
     pub exists: bool,
     pub backup_exists: bool,
+    /// Whether the `.bak` is an unpatched original. `Some(false)` means the
+    /// backup already contains patches, so reverting to it is a no-op.
+    pub backup_pristine: Option<bool>,
     pub patches: HashMap<String, bool>,
+    /// Built-in steps disabled on the last patch run (`--no-*` flags). Not
+    /// included in `patches`.
+    pub skipped: Vec<String>,
+    /// When the file was last written by nupatch (its mtime while patched
+    /// with a backup present). `None` if unknown.
+    pub last_patched: Option<SystemTime>,
+    /// Whether the file still has the hash nupatch recorded after patching
+    /// it (see `manifest`). `None` if nothing is recorded; CLI only.
+    pub recorded_hash_matches: Option<bool>,
+    /// A CLI agent version newer than every one nupatch patched, present
+    /// and unpatched (see `newer_unpatched_cli`). CLI only.
+    pub newer_unpatched: Option<String>,
+}
+
+impl ComponentStatus {
+    /// Whether the component exists and every known patch is applied.
+    pub fn fully_patched(&self) -> bool {
+        self.exists && !self.patches.is_empty() && self.patches.values().all(|&v| v)
+    }
 }
 
 /// Status of integrity checks.
 #[derive(Default)]
 pub struct IntegrityStatus {
     pub ehp_hash_matches: Option<bool>,
+    /// `None` when product.json is unreadable or has no checksums to verify.
     pub product_checksums_match: Option<bool>,
+    pub product_json_found: bool,
 }
 
 /// Overall patch status.
 #[derive(Default)]
 pub struct PatchStatus {
+    pub cursor: CursorVersion,
     pub cli: ComponentStatus,
     pub ide: ComponentStatus,
     pub integrity: IntegrityStatus,
 }
 
+/// Cursor build identity read from product.json.
+#[derive(Default, Clone)]
+pub struct CursorVersion {
+    pub version: Option<String>,
+    pub commit: Option<String>,
+}
+
+impl CursorVersion {
+    /// Human-readable form, e.g. `1.6.42 (deadbeef)` or `unknown version`.
+    pub fn describe(&self) -> String {
+        match (&self.version, &self.commit) {
+            (Some(v), Some(c)) => format!("{v} ({c})"),
+            (Some(v), None) => v.clone(),
+            (None, _) => "unknown version".to_string(),
+        }
+    }
+}
+
+/// Environment summary for the `info` command.
+pub struct InstallInfo {
+    pub cursor: CursorVersion,
+    pub cursor_app: Option<String>,
+    /// CLI agent version (name of the selected version directory).
+    pub cli_version: Option<String>,
+    pub os: &'static str,
+    pub arch: &'static str,
+}
+
+/// Overall install state derived from `PatchStatus`, shared by the status
+/// table, `status --json`, and the exit code of `status --json`.
+///
+/// | State               | Exit code |
+/// |---------------------|-----------|
+/// | `FullyPatched`      | 0         |
+/// | `PartiallyPatched`  | 1         |
+/// | `Original`          | 2         |
+/// | `IntegrityMismatch` | 3         |
+/// | `NotFound`          | 4         |
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OverallState {
+    /// Every detected component has all patches applied.
+    FullyPatched,
+    /// Some, but not all, patches are applied.
+    PartiallyPatched,
+    /// No patches are applied.
+    Original,
+    /// The IDE agent is patched but its integrity chain is out of date.
+    IntegrityMismatch,
+    /// Neither the CLI nor the IDE agent was found.
+    NotFound,
+}
+
+impl OverallState {
+    pub fn exit_code(self) -> i32 {
+        match self {
+            Self::FullyPatched => 0,
+            Self::PartiallyPatched => 1,
+            Self::Original => 2,
+            Self::IntegrityMismatch => 3,
+            Self::NotFound => 4,
+        }
+    }
+
+    /// Stable machine-readable name used in JSON output.
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::FullyPatched => "fully_patched",
+            Self::PartiallyPatched => "partially_patched",
+            Self::Original => "original",
+            Self::IntegrityMismatch => "integrity_mismatch",
+            Self::NotFound => "not_found",
+        }
+    }
+}
+
+impl PatchStatus {
+    pub fn overall(&self) -> OverallState {
+        let comps = [&self.cli, &self.ide];
+        if comps.iter().all(|c| !c.exists) {
+            return OverallState::NotFound;
+        }
+        let any_patched = comps.iter().any(|c| c.patches.values().any(|&v| v));
+        let integrity_bad = self.integrity.ehp_hash_matches == Some(false)
+            || self.integrity.product_checksums_match == Some(false);
+        if self.ide.patches.values().any(|&v| v) && integrity_bad {
+            OverallState::IntegrityMismatch
+        } else if comps.iter().filter(|c| c.exists).all(|c| c.fully_patched()) {
+            OverallState::FullyPatched
+        } else if any_patched {
+            OverallState::PartiallyPatched
+        } else {
+            OverallState::Original
+        }
+    }
+}
+
+/// A dimension that failed `status --check`. Each maps to a distinct
+/// exit-code bit; the process exits with the OR of all failing bits.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CheckFailure {
+    CliNotPatched = 1,
+    IdeNotPatched = 2,
+    EhpHashMismatch = 4,
+    ProductChecksumsMismatch = 8,
+}
+
+impl CheckFailure {
+    pub fn describe(self) -> &'static str {
+        match self {
+            Self::CliNotPatched => "CLI agent is not fully patched",
+            Self::IdeNotPatched => "IDE agent is not fully patched",
+            Self::EhpHashMismatch => "extensionHostProcess.js hash does not match main.js",
+            Self::ProductChecksumsMismatch => "product.json checksums do not match",
+        }
+    }
+}
+
 /// Result of reverting a single file.
 pub struct RevertFileResult {
     pub filename: String,
     pub restored: bool,
+    /// Why the backup couldn't be restored (e.g. it is corrupt).
+    pub error: Option<Error>,
+    /// Why a file with a backup was left alone.
+    pub skipped: Option<&'static str>,
+    /// The file no longer exists: a CLI version an update removed since
+    /// nupatch patched it.
+    pub gone: bool,
 }
 
 /// Result of the revert operation.
@@ -97,9 +348,11 @@ pub struct RevertResult {
 // ---------------------------------------------------------------------------
 
 /// Discovered minified variable names.
-struct DiscoveredVars {
+pub struct DiscoveredVars {
     hint_var: String,
     enum_var: String,
+    /// `enum_var` regex-escaped, for the patterns built around it.
+    enum_escaped: String,
     lazy_exec: Option<String>,
     naive_exec: Option<String>,
     /// Minified name of the "command exists on PATH" helper (Ie / Qe).
@@ -107,6 +360,14 @@ struct DiscoveredVars {
     /// Full `(0,<mod>.findActualExecutable)` call pattern for constructing
     /// PATH-based shell resolution.
     find_exec_call: Option<String>,
+    /// Options variable of the executor factory, from
+    /// `switch(<fn>(<opts>?.userTerminalHint`. Defaults to `t`.
+    opts_var: String,
+    /// Variable in the shell resolution function's `<var>?.shell??`.
+    shell_opts_var: Option<String>,
+    /// PowerShell path resolver from `case <enum>.PowerShell:return <fn>()`
+    /// in `getShellExecutablePath`.
+    ps_path_fn: Option<String>,
     has_naive_case: bool,
     has_nu_detection: bool,
     /// System-level `<cmdExists>("nu")` check in detectShellType.
@@ -114,25 +375,159 @@ struct DiscoveredVars {
     has_user_terminal_hint: bool,
 }
 
+/// Regex results recorded by `discover_vars` for `--trace-regex`: each
+/// anchor/capture regex with the byte offset, matched text and groups of
+/// its match, or "no match". Records nothing unless enabled, except the
+/// searches that gave up at their backtrack limit, which are always kept.
+#[derive(Default)]
+struct RegexTrace {
+    lines: Option<Vec<String>>,
+    /// Labels of searches that hit their backtrack limit.
+    limit_hits: Vec<String>,
+}
+
+impl RegexTrace {
+    fn enabled() -> Self {
+        Self { lines: Some(Vec::new()), ..Self::default() }
+    }
+
+    /// `regex.captures(hay)`, recorded under `label`. `base` is the offset
+    /// of `hay` within the file, for searches over a region.
+    fn captures<'t>(&mut self, label: &str, regex: &Regex, hay: &'t str, base: usize) -> Option<Captures<'t>> {
+        let (caps, limit_hit) = match regex.captures(hay) {
+            Ok(caps) => (caps, false),
+            Err(e) => (None, is_backtrack_limit(&e)),
+        };
+        if limit_hit {
+            self.limit_hits.push(label.to_string());
+        }
+        if let Some(lines) = &mut self.lines {
+            lines.push(format!("{label}  /{}/", regex.as_str()));
+            lines.push(match &caps {
+                None if limit_hit => "  backtrack limit hit".to_string(),
+                Some(c) => {
+                    let m = c.get(0).expect("group 0 always participates");
+                    let groups: Vec<String> = (1..c.len())
+                        .map(|i| format!("${i}={}", c.get(i).map_or("-", |g| g.as_str())))
+                        .collect();
+                    format!("  @{}  {}  {}", base + m.start(), clip(m.as_str(), 80), groups.join(" "))
+                }
+                None => "  no match".to_string(),
+            });
+        }
+        caps
+    }
+
+    fn note(&mut self, line: &str) {
+        if let Some(lines) = &mut self.lines {
+            lines.push(line.to_string());
+        }
+    }
+
+    fn into_text(self) -> Option<String> {
+        self.lines.map(|lines| lines.join("\n"))
+    }
+}
+
+/// At most `max` chars of `s` on one line, with an ellipsis when cut.
+fn clip(s: &str, max: usize) -> String {
+    let one_line: String = s.chars().map(|c| if c.is_control() { ' ' } else { c }).collect();
+    match one_line.char_indices().nth(max) {
+        Some((i, _)) => format!("{}...", &one_line[..i]),
+        None => one_line,
+    }
+}
+
+/// Bytes of code shown around a failed match.
+const CONTEXT_WINDOW: usize = 320;
+/// Bytes per line of a context dump.
+const CONTEXT_LINE: usize = 64;
+
+/// Hexdump-style view of `code` centred on byte `at`: an offset column
+/// and up to `CONTEXT_LINE` bytes of text per line, control characters
+/// shown as `.`, `CONTEXT_WINDOW` bytes in all.
+fn context_at(code: &str, at: usize) -> String {
+    let floor = |i: usize| code.floor_char_boundary(i);
+    let mut start = floor(at.saturating_sub(CONTEXT_WINDOW / 2));
+    let end = floor(start + CONTEXT_WINDOW);
+    let mut lines = Vec::new();
+    while start < end {
+        let mut stop = floor(start + CONTEXT_LINE).min(end);
+        if stop == start {
+            // A multi-byte char straddles the line width; take it whole.
+            stop = code[start..].chars().next().map_or(end, |c| start + c.len_utf8());
+        }
+        let text: String = code[start..stop]
+            .chars()
+            .map(|c| if c.is_control() { '.' } else { c })
+            .collect();
+        lines.push(format!("{start:08x}  {text}"));
+        start = stop;
+    }
+    lines.join("\n")
+}
+
+/// `context_at` the first of `landmarks` found in `code`: where a pattern
+/// that can't be found would normally sit.
+fn context_near(code: &str, landmarks: &[&str]) -> String {
+    match landmarks.iter().find_map(|l| code.find(l).map(|i| (l, i))) {
+        Some((landmark, at)) => format!("Near `{landmark}` (byte {at}):\n{}", context_at(code, at)),
+        None => format!(
+            "None of {} occur in the file.",
+            landmarks.iter().map(|l| format!("`{l}`")).collect::<Vec<_>>().join(", ")
+        ),
+    }
+}
+
+/// `code` with `code[at..remove_to]` replaced by `insertion`, or `None` if
+/// either offset falls inside a multi-byte character. Offsets found by
+/// searching are always on character boundaries; offsets computed from them
+/// might not be once a string literal nearby holds non-ASCII text, and
+/// slicing there would panic.
+fn splice(code: &str, at: usize, remove_to: usize, insertion: &str) -> Option<String> {
+    if at > remove_to || !code.is_char_boundary(at) || !code.is_char_boundary(remove_to) {
+        return None;
+    }
+    let mut new_code = String::with_capacity(code.len() - (remove_to - at) + insertion.len());
+    new_code.push_str(&code[..at]);
+    new_code.push_str(insertion);
+    new_code.push_str(&code[remove_to..]);
+    Some(new_code)
+}
+
+/// The step failure for a `splice` refused at byte `at`.
+fn split_char_failure(name: impl Into<Cow<'static, str>>, at: usize) -> StepResult {
+    StepResult::fail(name, format!("Insertion point (byte {at}) is inside a multi-byte character"))
+        .with_kind(FailureKind::Discovery)
+}
+
+/// Landmarks for the `includes("zsh")?<enum>.Zsh` discovery anchor.
+const ZSH_LANDMARKS: &[&str] = &[r#"includes("zsh")"#, r#""zsh""#, ".Zsh"];
+
 /// Discover minified variable names from structural patterns.
-fn discover_vars(code: &str) -> Result<DiscoveredVars, String> {
+pub fn discover_vars(code: &str) -> Result<DiscoveredVars, Error> {
+    discover_vars_traced(code, &mut RegexTrace::default())
+}
+
+/// `discover_vars`, recording every regex result in `trace`.
+fn discover_vars_traced(code: &str, trace: &mut RegexTrace) -> Result<DiscoveredVars, Error> {
     // 1. hintVar + enumVar from: <hint>.includes("zsh")?<enum>.Zsh
     let re1 = lazy_re!(r#"(\w+)\.includes\("zsh"\)\?(\w+)\.Zsh"#);
-    let caps = re1
-        .captures(code)
-        .ok()
-        .flatten()
-        .ok_or_else(|| r#"Cannot find includes("zsh")?<enum>.Zsh pattern"#.to_string())?;
+    let caps = trace
+        .captures("hint_var/enum_var", re1, code, 0)
+        .ok_or_else(|| Error::PatternNotFound {
+            step: "Pattern discovery".to_string(),
+            pattern: r#"includes("zsh")?<enum>.Zsh pattern"#.to_string(),
+            near: Some(context_near(code, ZSH_LANDMARKS)),
+        })?;
     let hint_var = caps.get(1).map(|m| m.as_str()).unwrap_or("").to_string();
     let enum_var = caps.get(2).map(|m| m.as_str()).unwrap_or("").to_string();
-    let enum_escaped = fancy_regex::escape(&enum_var);
+    let enum_escaped = fancy_regex::escape(&enum_var).into_owned();
 
     // 2. LazyExecutor from: case <enum>.Zsh:...new <LazyExec>(
-    let re2 = re(&format!(r"case\s*{enum_escaped}\.Zsh\s*:.*?new\s+(\w+)\(")).map_err(|e| e.to_string())?;
-    let lazy_exec = re2
-        .captures(code)
-        .ok()
-        .flatten()
+    let re2 = re_for(&format!(r"case\s*{enum_escaped}\.Zsh\s*:.*?new\s+(\w+)\("), code.len())?;
+    let lazy_exec = trace
+        .captures("lazy_exec", &re2, code, 0)
         .and_then(|c| c.get(1).map(|m| m.as_str().to_string()));
 
     // 3. NaiveTerminalExecutor -- multiple methods
@@ -141,10 +536,11 @@ fn discover_vars(code: &str) -> Result<DiscoveredVars, String> {
     // Method A: from existing case Naive
     let naive_case_str = format!("case {enum_var}.Naive:");
     if code.contains(&naive_case_str) {
-        let re_a = re(&format!(
-            r"case\s*{enum_escaped}\.Naive\s*:.*?new\s+\w+\(.*?new\s+(\w+)\("
-        )).map_err(|e| e.to_string())?;
-        if let Some(c) = re_a.captures(code).ok().flatten() {
+        let re_a = re_for(
+            &format!(r"case\s*{enum_escaped}\.Naive\s*:.*?new\s+\w+\(.*?new\s+(\w+)\("),
+            code.len(),
+        )?;
+        if let Some(c) = trace.captures("naive_exec (existing case)", &re_a, code, 0) {
             naive_exec = Some(c.get(1).map(|m| m.as_str()).unwrap_or("").to_string());
         }
     }
@@ -152,7 +548,7 @@ fn discover_vars(code: &str) -> Result<DiscoveredVars, String> {
     // Method C: from new <X>(process.cwd(), {shell:
     if naive_exec.is_none() {
         let re_c = lazy_re!(r"new\s+(\w+)\(process\.cwd\(\)\s*,\s*\{shell:");
-        if let Some(c) = re_c.captures(code).ok().flatten() {
+        if let Some(c) = trace.captures("naive_exec (process.cwd)", re_c, code, 0) {
             naive_exec = Some(c.get(1).map(|m| m.as_str()).unwrap_or("").to_string());
         }
     }
@@ -160,7 +556,7 @@ fn discover_vars(code: &str) -> Result<DiscoveredVars, String> {
     // Method D: from new <X>(n, {...e, shell:r})
     if naive_exec.is_none() {
         let re_d = lazy_re!(r"new\s+(\w+)\(\w+,\s*\{\.\.\.\w+\s*,\s*shell\s*:");
-        if let Some(c) = re_d.captures(code).ok().flatten() {
+        if let Some(c) = trace.captures("naive_exec (spread opts)", re_d, code, 0) {
             naive_exec = Some(c.get(1).map(|m| m.as_str()).unwrap_or("").to_string());
         }
     }
@@ -170,7 +566,7 @@ fn discover_vars(code: &str) -> Result<DiscoveredVars, String> {
     let re_cmd = lazy_re!(
         r"function\s+(\w+)\(\w+\)\{try\{return(\(0,\w+\.\w+\))\(\w+,\[\]\)\.cmd!==\w+\}"
     );
-    let (cmd_exists_fn, find_exec_call) = match re_cmd.captures(code).ok().flatten() {
+    let (cmd_exists_fn, find_exec_call) = match trace.captures("cmd_exists_fn/find_exec_call", re_cmd, code, 0) {
         Some(c) => (
             c.get(1).map(|m| m.as_str().to_string()),
             c.get(2).map(|m| m.as_str().to_string()),
@@ -178,6 +574,35 @@ fn discover_vars(code: &str) -> Result<DiscoveredVars, String> {
         None => (None, None),
     };
 
+    // 4b. Names used by the executor factory and shell path patches
+    let re_opts = lazy_re!(r"switch\(\w+\((\w+)\?\.userTerminalHint");
+    let opts_var = trace
+        .captures("opts_var", re_opts, code, 0)
+        .and_then(|c| c.get(1).map(|m| m.as_str().to_string()))
+        .unwrap_or_else(|| "t".to_string());
+
+    // Simple regex without negative lookahead to avoid backtrack-limit
+    // issues on large (4 MB+) minified files.
+    let re_shell = lazy_re!(r"(\w+)\?\.shell\?\?");
+    let shell_opts_var = trace
+        .captures("shell_opts_var", re_shell, code, 0)
+        .and_then(|c| c.get(1).map(|m| m.as_str().to_string()));
+
+    // The PowerShell resolver sits just before the `/bin/sh` default case.
+    let ps_path_fn = match code.find(SHELL_PATH_DEFAULT) {
+        Some(idx) => {
+            let start = code.floor_char_boundary(idx.saturating_sub(500));
+            let re_ps = re_for(&format!(r"case {enum_escaped}\.PowerShell:return (\w+)\(\)"), idx - start)?;
+            trace
+                .captures("ps_path_fn", &re_ps, &code[start..idx], start)
+                .and_then(|c| c.get(1).map(|m| m.as_str().to_string()))
+        }
+        None => {
+            trace.note(&format!("ps_path_fn  anchor `{SHELL_PATH_DEFAULT}` not found"));
+            None
+        }
+    };
+
     // 5. State flags
     let has_naive_case = code.contains(&naive_case_str);
     let nu_detection_str = format!(r#".includes("nu")?{enum_var}.Naive"#);
@@ -199,15 +624,19 @@ fn discover_vars(code: &str) -> Result<DiscoveredVars, String> {
     // The trailing ?? distinguishes this from the original .userTerminalHint
     // usage in the switch(Te(e?.userTerminalHint...)) statement.
     let re_uth = lazy_re!(r"\.shell\?\?\w+\?\.userTerminalHint\?\?");
-    let has_user_terminal_hint = re_uth.is_match(code).unwrap_or(false);
+    let has_user_terminal_hint = trace.captures("has_user_terminal_hint", re_uth, code, 0).is_some();
 
     Ok(DiscoveredVars {
         hint_var,
         enum_var,
+        enum_escaped,
         lazy_exec,
         naive_exec,
         cmd_exists_fn,
         find_exec_call,
+        opts_var,
+        shell_opts_var,
+        ps_path_fn,
         has_naive_case,
         has_nu_detection,
         has_system_nu,
@@ -216,14 +645,31 @@ fn discover_vars(code: &str) -> Result<DiscoveredVars, String> {
 }
 
 /// Lightweight patch detection without full discover_vars output.
-struct QuickDetect {
+#[derive(Clone, Copy)]
+pub struct QuickDetect {
     has_nu: bool,
     has_system_nu: bool,
     has_naive_case: bool,
     has_uth: bool,
 }
 
-fn quick_detect(code: &str) -> Option<QuickDetect> {
+impl QuickDetect {
+    /// Whether the built-in step `name` is detected. `None` for steps
+    /// quick detection doesn't track.
+    fn has(&self, name: &str) -> Option<bool> {
+        match name {
+            "Nu detection" => Some(self.has_nu),
+            "System nu detection" => Some(self.has_system_nu),
+            "Naive case" => Some(self.has_naive_case),
+            "userTerminalHint" => Some(self.has_uth),
+            _ => None,
+        }
+    }
+}
+
+/// Whether each built-in patch is present, or `None` when `code` isn't
+/// agent code.
+pub fn quick_detect(code: &str) -> Option<QuickDetect> {
     let re1 = lazy_re!(r#"(\w+)\.includes\("zsh"\)\?(\w+)\.Zsh"#);
     let caps = re1.captures(code).ok().flatten()?;
     let _hint_var = caps.get(1)?.as_str();
@@ -260,6 +706,220 @@ fn quick_detect(code: &str) -> Option<QuickDetect> {
     })
 }
 
+/// Longest match the streamed detection regexes need to see whole.
+const DETECT_OVERLAP: usize = 4096;
+
+/// `quick_detect` on the file at `path`, streamed through `scan_file` rather
+/// than read whole: one pass for the anchors the markers are built from,
+/// then one for the markers themselves.
+fn quick_detect_file(path: &Path) -> Option<QuickDetect> {
+    let re1 = lazy_re!(r#"(\w+)\.includes\("zsh"\)\?(\w+)\.Zsh"#);
+    let re_uth = lazy_re!(r"\.shell\?\?\w+\?\.userTerminalHint\?\?");
+    let re_cmd = lazy_re!(
+        r"function\s+(\w+)\(\w+\)\{try\{return\(0,\w+\.\w+\)\(\w+,\[\]\)\.cmd!==\w+\}"
+    );
+    let first = |regex: &Regex, text: &str| {
+        regex.captures(text).ok().flatten().map(|c| {
+            let group = |i| c.get(i).map_or(String::new(), |m| m.as_str().to_string());
+            (group(1), group(2))
+        })
+    };
+    let (mut enum_var, mut cmd_fn, mut has_uth) = (None, None, false);
+    scan_file(path, DETECT_OVERLAP, |text| {
+        if enum_var.is_none() {
+            enum_var = first(re1, text).map(|(_, e)| e);
+        }
+        if cmd_fn.is_none() {
+            cmd_fn = first(re_cmd, text).map(|(f, _)| f);
+        }
+        has_uth = has_uth || re_uth.is_match(text).unwrap_or(false);
+        enum_var.is_some() && cmd_fn.is_some() && has_uth
+    })
+    .ok()?;
+    let enum_var = enum_var?;
+
+    let nu = format!(r#".includes("nu")?{enum_var}.Naive"#);
+    let naive_case = format!("case {enum_var}.Naive:");
+    let system_nu = cmd_fn.map(|f| format!(r#"?{enum_var}.PowerShell:{f}("nu")?{enum_var}.Naive:"#));
+    let mut needles = vec![nu.as_str(), naive_case.as_str()];
+    needles.extend(system_nu.as_deref());
+    let found = contains_in_file(path, &needles).ok()?;
+    Some(QuickDetect {
+        has_nu: found[0],
+        has_system_nu: found.get(2).copied().unwrap_or(false),
+        has_naive_case: found[1],
+        has_uth,
+    })
+}
+
+/// Size and mtime of a file, to tell whether a memoized detection is stale.
+type FileStamp = (u64, Option<SystemTime>);
+
+fn file_stamp(path: &Path) -> Option<FileStamp> {
+    let meta = fs::metadata(path).ok()?;
+    Some((meta.len(), meta.modified().ok()))
+}
+
+/// `quick_detect` results by file for the rest of the run, so status and
+/// patch decisions in one command share a single scan of each file. An
+/// entry is only reused while the file's size and mtime are unchanged.
+static DETECTED: LazyLock<Mutex<HashMap<PathBuf, Detected>>> = LazyLock::new(Default::default);
+
+/// A memoized `quick_detect` and the file state it was made against.
+type Detected = (FileStamp, Option<QuickDetect>);
+
+/// The memoized detection for `path`, if the file hasn't changed since.
+fn memoized_detect(path: &Path) -> Option<Option<QuickDetect>> {
+    let stamp = file_stamp(path)?;
+    let detected = DETECTED.lock().unwrap_or_else(|e| e.into_inner());
+    match detected.get(path) {
+        Some(&(at, det)) if at == stamp => Some(det),
+        _ => None,
+    }
+}
+
+fn remember_detect(path: &Path, stamp: Option<FileStamp>, det: Option<QuickDetect>) {
+    if let Some(stamp) = stamp {
+        DETECTED.lock().unwrap_or_else(|e| e.into_inner()).insert(path.to_path_buf(), (stamp, det));
+    }
+}
+
+/// `quick_detect_file` on `path`, unless it has already been scanned in
+/// its current state.
+fn detect_file(path: &Path) -> Option<QuickDetect> {
+    if let Some(det) = memoized_detect(path) {
+        return det;
+    }
+    let stamp = file_stamp(path);
+    let det = quick_detect_file(path);
+    remember_detect(path, stamp, det);
+    det
+}
+
+/// `quick_detect` on `code`, just read from `path`, reusing an earlier scan
+/// of the same file when it hasn't changed since.
+fn detect_read(path: &Path, code: &str) -> Option<QuickDetect> {
+    if let Some(det) = memoized_detect(path) {
+        return det;
+    }
+    let stamp = file_stamp(path);
+    let det = quick_detect(code);
+    remember_detect(path, stamp, det);
+    det
+}
+
+// ---------------------------------------------------------------------------
+//  Built-in rule templates
+// ---------------------------------------------------------------------------
+
+/// Structured description of a built-in patch step. The patch functions
+/// expand `template` to build their insertions, and `rules --export` dumps
+/// these in the `--rules` manifest format, so the two can't drift.
+///
+/// `find` and `template` use the same `{placeholder}` names as user rules,
+/// plus `{shell}` for the shell being wired in (built-ins only: user rules
+/// keep it as text, since JS shorthand like `{shell}` is common), and go
+/// through the same `expand_template` path.
+/// `find` is the anchor as a standalone rule would locate it; the patch
+/// functions apply extra context checks on top.
+pub struct BuiltinRule {
+    pub name: &'static str,
+    pub target: RuleTarget,
+    pub find: &'static str,
+    pub regex: bool,
+    pub mode: RuleMode,
+    pub template: &'static str,
+    pub description: &'static str,
+    /// Why the step is needed, in plain language (`patch --explain`).
+    pub why: &'static str,
+}
+
+const NU_DETECTION: BuiltinRule = BuiltinRule {
+    name: "Nu detection",
+    target: RuleTarget::Both,
+    find: r#"{hint_var}.includes("pwsh")"#,
+    regex: false,
+    mode: RuleMode::InsertBefore,
+    template: r#"{hint_var}.includes("{shell}")?{enum_var}.Naive:"#,
+    description: "Detect nushell from the shell hint before the PowerShell check",
+    why: "Teaches detectShellType to map a terminal hint containing \"nu\" to the Naive \
+          executor; without it a nu terminal falls through to PowerShell",
+};
+
+const SYSTEM_NU_DETECTION: BuiltinRule = BuiltinRule {
+    name: "System nu detection",
+    target: RuleTarget::Both,
+    find: r#"{hint_var}\.includes\("zsh"\)[\s\S]*?\?{enum_var}\.PowerShell:"#,
+    regex: true,
+    mode: RuleMode::InsertAfter,
+    template: r#"{cmd_exists_fn}("{shell}")?{enum_var}.Naive:"#,
+    description: "Detect nushell on PATH before the system-level PowerShell checks",
+    why: "When there is no usable hint, picks nu if it is on PATH instead of \
+          defaulting to PowerShell",
+};
+
+const USER_TERMINAL_HINT: BuiltinRule = BuiltinRule {
+    name: "userTerminalHint",
+    target: RuleTarget::Ide,
+    find: "{shell_opts_var}?.shell??",
+    regex: false,
+    mode: RuleMode::Replace,
+    template: "{shell_opts_var}?.shell??{shell_opts_var}?.userTerminalHint??",
+    description: "Fall back to the user's configured terminal when resolving the shell",
+    why: "The IDE otherwise ignores the terminal configured in settings.json when \
+          choosing the shell the agent runs commands in",
+};
+
+const NAIVE_CASE: BuiltinRule = BuiltinRule {
+    name: "Naive case",
+    target: RuleTarget::Cli,
+    find: r"case {enum_var}\.Zsh:[\s\S]{0,10000}?(?=default:)",
+    regex: true,
+    mode: RuleMode::InsertAfter,
+    template: "case {enum_var}.Naive:{const _np={find_exec_call}(\"{shell}\",[]).cmd;\
+               return new {lazy_exec}(Promise.resolve(\
+               new {naive_exec}(process.cwd(),{shell:{opts_var}?.userTerminalHint\
+               ||(_np!==\"{shell}\"?_np:void 0)\
+               ||process.env.SHELL||\"/bin/sh\",...{opts_var}})))}",
+    description: "Route ShellType.Naive to NaiveTerminalExecutor in the executor factory",
+    why: "The CLI's executor factory has no Naive case, so detecting nu alone would \
+          still not run commands through `nu -c`",
+};
+
+/// Original `getShellExecutablePath` default case replaced by the shell
+/// path fallback patch.
+const SHELL_PATH_DEFAULT: &str = r#"default:return process.env.SHELL||"/bin/sh""#;
+
+const SHELL_PATH_FALLBACK: BuiltinRule = BuiltinRule {
+    name: "Shell path fallback",
+    target: RuleTarget::Ide,
+    find: SHELL_PATH_DEFAULT,
+    regex: false,
+    mode: RuleMode::Replace,
+    template: "case {enum_var}.Naive:{const _np={find_exec_call}(\"{shell}\",[]).cmd;\
+               if(_np!==\"{shell}\")return _np}\
+               default:return process.env.SHELL||(\"win32\"===process.platform?{ps_path_fn}():\"/bin/sh\")",
+    description: "Resolve nu from PATH for ShellType.Naive; use PowerShell, not /bin/sh, on Windows",
+    why: "getShellExecutablePath returns /bin/sh for unrecognized shells, which \
+          doesn't exist on Windows",
+};
+
+/// All built-in patch steps, in plan order.
+pub const BUILTIN_RULES: &[BuiltinRule] = &[
+    NU_DETECTION,
+    SYSTEM_NU_DETECTION,
+    USER_TERMINAL_HINT,
+    NAIVE_CASE,
+    SHELL_PATH_FALLBACK,
+];
+
+/// Expand a built-in template, mapping a missing placeholder to a failed step.
+fn expand_builtin(rule: &BuiltinRule, v: &DiscoveredVars) -> Result<String, Box<StepResult>> {
+    expand_template(rule.template, v, Some(TARGET_SHELL), false).map_err(|e| {
+        Box::new(StepResult::fail(rule.name, format!("Template {e}")).with_kind(FailureKind::Discovery))
+    })
+}
+
 // ---------------------------------------------------------------------------
 //  Patch: Nu detection in detectShellType
 // ---------------------------------------------------------------------------
@@ -279,12 +939,13 @@ fn patch_nu_detection<'a>(code: &'a str, v: &DiscoveredVars) -> (Cow<'a, str>, S
         None => {
             return (
                 Cow::Borrowed(code),
-                StepResult::fail("Nu detection", "Cannot locate detectShellType region"),
+                StepResult::fail("Nu detection", "Cannot locate detectShellType region")
+                    .with_detail(context_near(code, ZSH_LANDMARKS)),
             );
         }
     };
 
-    let region_end = (zsh_idx + 2000).min(code.len());
+    let region_end = code.floor_char_boundary(zsh_idx + 2000);
     let region = &code[zsh_idx..region_end];
 
     // Insert BEFORE the PowerShell includes check
@@ -294,16 +955,17 @@ fn patch_nu_detection<'a>(code: &'a str, v: &DiscoveredVars) -> (Cow<'a, str>, S
         None => {
             return (
                 Cow::Borrowed(code),
-                StepResult::fail("Nu detection", format!("Cannot find {ps_includes} in detectShellType")),
+                StepResult::fail("Nu detection", format!("Cannot find {ps_includes} in detectShellType"))
+                    .with_detail(context_at(code, zsh_idx)),
             );
         }
     };
 
     let insert_at = zsh_idx + ps_inc_idx;
-    let insertion = format!(
-        r#"{}.includes("nu")?{}.Naive:"#,
-        v.hint_var, v.enum_var
-    );
+    let insertion = match expand_builtin(&NU_DETECTION, v) {
+        Ok(i) => i,
+        Err(step) => return (Cow::Borrowed(code), *step),
+    };
 
     if code[insert_at..].starts_with(&insertion) {
         return (
@@ -312,13 +974,12 @@ fn patch_nu_detection<'a>(code: &'a str, v: &DiscoveredVars) -> (Cow<'a, str>, S
         );
     }
 
-    let mut new_code = String::with_capacity(code.len() + insertion.len());
-    new_code.push_str(&code[..insert_at]);
-    new_code.push_str(&insertion);
-    new_code.push_str(&code[insert_at..]);
+    let Some(new_code) = splice(code, insert_at, insert_at, &insertion) else {
+        return (Cow::Borrowed(code), split_char_failure("Nu detection", insert_at));
+    };
 
-    let ctx_start = insert_at.saturating_sub(40);
-    let ctx_end = (insert_at + insertion.len() + 60).min(new_code.len());
+    let ctx_start = new_code.floor_char_boundary(insert_at.saturating_sub(40));
+    let ctx_end = new_code.ceil_char_boundary(insert_at + insertion.len() + 60);
     let detail = format!(
         "Insertion: {}\nContext:   ...{}...",
         insertion,
@@ -327,7 +988,9 @@ fn patch_nu_detection<'a>(code: &'a str, v: &DiscoveredVars) -> (Cow<'a, str>, S
 
     (
         Cow::Owned(new_code),
-        StepResult::ok("Nu detection", "Inserted before PowerShell check").with_detail(detail),
+        StepResult::ok("Nu detection", "Inserted before PowerShell check")
+            .with_detail(detail)
+            .with_edit(insert_at..insert_at),
     )
 }
 
@@ -352,18 +1015,15 @@ fn patch_system_nu_detection<'a>(code: &'a str, v: &DiscoveredVars) -> (Cow<'a,
         );
     }
 
-    let cmd_exists = match &v.cmd_exists_fn {
-        Some(f) => f,
-        None => {
-            return (
-                Cow::Borrowed(code),
-                StepResult::fail(
-                    "System nu detection",
-                    "Cannot find commandExists function (Ie/Qe)",
-                ),
-            );
-        }
-    };
+    if v.cmd_exists_fn.is_none() {
+        return (
+            Cow::Borrowed(code),
+            StepResult::fail(
+                "System nu detection",
+                "Cannot find commandExists function (Ie/Qe)",
+            ),
+        );
+    }
 
     // Find the detectShellType region (starts at the includes("zsh") pattern).
     let zsh_pattern = format!(r#"{}.includes("zsh")"#, v.hint_var);
@@ -375,7 +1035,8 @@ fn patch_system_nu_detection<'a>(code: &'a str, v: &DiscoveredVars) -> (Cow<'a,
                 StepResult::fail(
                     "System nu detection",
                     "Cannot find detectShellType region",
-                ),
+                )
+                .with_detail(context_near(code, ZSH_LANDMARKS)),
             );
         }
     };
@@ -392,24 +1053,24 @@ fn patch_system_nu_detection<'a>(code: &'a str, v: &DiscoveredVars) -> (Cow<'a,
                 StepResult::fail(
                     "System nu detection",
                     format!("Cannot find `{ps_marker}` in detectShellType"),
-                ),
+                )
+                .with_detail(context_at(code, zsh_idx)),
             );
         }
     };
 
     let insert_at = zsh_idx + ps_offset + ps_marker.len();
-    let insertion = format!(
-        r#"{cmd_exists}("nu")?{ev}.Naive:"#,
-        ev = v.enum_var
-    );
+    let insertion = match expand_builtin(&SYSTEM_NU_DETECTION, v) {
+        Ok(i) => i,
+        Err(step) => return (Cow::Borrowed(code), *step),
+    };
 
-    let mut new_code = String::with_capacity(code.len() + insertion.len());
-    new_code.push_str(&code[..insert_at]);
-    new_code.push_str(&insertion);
-    new_code.push_str(&code[insert_at..]);
+    let Some(new_code) = splice(code, insert_at, insert_at, &insertion) else {
+        return (Cow::Borrowed(code), split_char_failure("System nu detection", insert_at));
+    };
 
-    let ctx_start = insert_at.saturating_sub(40);
-    let ctx_end = (insert_at + insertion.len() + 40).min(new_code.len());
+    let ctx_start = new_code.floor_char_boundary(insert_at.saturating_sub(40));
+    let ctx_end = new_code.ceil_char_boundary(insert_at + insertion.len() + 40);
     let detail = format!(
         "Insertion: {}\nContext:   ...{}...",
         insertion,
@@ -419,7 +1080,8 @@ fn patch_system_nu_detection<'a>(code: &'a str, v: &DiscoveredVars) -> (Cow<'a,
     (
         Cow::Owned(new_code),
         StepResult::ok("System nu detection", "Inserted PATH-based nu check after hint-based PowerShell")
-            .with_detail(detail),
+            .with_detail(detail)
+            .with_edit(insert_at..insert_at),
     )
 }
 
@@ -442,37 +1104,36 @@ fn patch_user_terminal_hint<'a>(code: &'a str, v: &DiscoveredVars) -> (Cow<'a, s
         );
     }
 
-    // Use a simple regex without negative lookahead to avoid backtrack-limit
-    // issues on large (4 MB+) minified files.
-    let re = lazy_re!(r"(\w+)\?\.shell\?\?");
-    let caps = match re.captures(code).ok().flatten() {
-        Some(c) => c,
-        None => {
-            return (
-                Cow::Borrowed(code),
-                StepResult::fail("userTerminalHint", "Cannot find ?.shell?? pattern"),
-            );
-        }
+    if v.shell_opts_var.is_none() {
+        return (
+            Cow::Borrowed(code),
+            StepResult::fail("userTerminalHint", "Cannot find ?.shell?? pattern")
+                .with_detail(context_near(code, &["?.shell", "userTerminalHint"])),
+        );
+    }
+    let (find, replace) = match (
+        expand_template(USER_TERMINAL_HINT.find, v, Some(TARGET_SHELL), false),
+        expand_builtin(&USER_TERMINAL_HINT, v),
+    ) {
+        (Ok(f), Ok(r)) => (f, r),
+        (Err(e), _) => return (Cow::Borrowed(code), StepResult::fail("userTerminalHint", e)),
+        (_, Err(step)) => return (Cow::Borrowed(code), *step),
     };
-
-    let shell_var = caps.get(1).map(|m| m.as_str()).unwrap_or("").to_string();
-    let find = format!("{shell_var}?.shell??");
-    let already_patched = format!("{shell_var}?.shell??{shell_var}?.userTerminalHint??");
-    if code.contains(&already_patched) {
+    if code.contains(&replace) {
         return (
             Cow::Borrowed(code),
             StepResult::skipped("userTerminalHint", "Already present, skipped"),
         );
     }
-    let replace = already_patched;
 
     let new_code = code.replacen(&find, &replace, 1);
     let detail = format!("Find:    {find}\nReplace: {replace}");
+    let mut step = StepResult::ok("userTerminalHint", format!("{find} -> {replace}")).with_detail(detail);
+    if let Some(at) = code.find(&find) {
+        step = step.with_edit(at..at + find.len());
+    }
 
-    (
-        Cow::Owned(new_code),
-        StepResult::ok("userTerminalHint", format!("{find} -> {replace}")).with_detail(detail),
-    )
+    (Cow::Owned(new_code), step)
 }
 
 // ---------------------------------------------------------------------------
@@ -492,68 +1153,34 @@ fn patch_naive_case<'a>(code: &'a str, v: &DiscoveredVars) -> (Cow<'a, str>, Ste
         );
     }
 
-    let lazy_exec = match &v.lazy_exec {
-        Some(le) => le,
-        None => {
-            return (
-                Cow::Borrowed(code),
-                StepResult::fail("Naive case", format!(
-                    "Cannot construct Naive case (lazy_exec={:?}, naive_exec={:?})",
-                    v.lazy_exec, v.naive_exec
-                )),
-            );
-        }
-    };
-
-    let naive_exec = match &v.naive_exec {
-        Some(ne) => ne,
-        None => {
-            return (
-                Cow::Borrowed(code),
-                StepResult::fail("Naive case", format!(
-                    "Cannot construct Naive case (lazy_exec={:?}, naive_exec={:?})",
-                    v.lazy_exec, v.naive_exec
-                )),
-            );
-        }
-    };
-
-    let find_exec = match &v.find_exec_call {
-        Some(f) => f,
-        None => {
-            return (
-                Cow::Borrowed(code),
-                StepResult::fail(
-                    "Naive case",
-                    "Cannot find findActualExecutable call pattern",
-                ),
-            );
-        }
-    };
+    if v.lazy_exec.is_none() || v.naive_exec.is_none() {
+        return (
+            Cow::Borrowed(code),
+            StepResult::fail("Naive case", format!(
+                "Cannot construct Naive case (lazy_exec={:?}, naive_exec={:?})",
+                v.lazy_exec, v.naive_exec
+            )),
+        );
+    }
 
-    // Find the opts variable from switch context
-    let re_opts = lazy_re!(r"switch\(\w+\((\w+)\?\.userTerminalHint");
-    let opts_var = re_opts
-        .captures(code)
-        .ok()
-        .flatten()
-        .and_then(|c| c.get(1).map(|m| m.as_str().to_string()))
-        .unwrap_or_else(|| "t".to_string());
+    if v.find_exec_call.is_none() {
+        return (
+            Cow::Borrowed(code),
+            StepResult::fail(
+                "Naive case",
+                "Cannot find findActualExecutable call pattern",
+            ),
+        );
+    }
 
     // PATH-based shell resolution: try userTerminalHint first, then
     // findActualExecutable("nu") for auto-discovery, then env fallbacks.
     // findActualExecutable returns {cmd: "nu"} when NOT found (cmd === input),
     // so we check _np !== "nu" to distinguish found vs not-found.
-    let naive_case = format!(
-        "case {ev}.Naive:{{const _np={fex}(\"nu\",[]).cmd;\
-         return new {lazy_exec}(Promise.resolve(\
-         new {naive_exec}(process.cwd(),{{shell:{ov}?.userTerminalHint\
-         ||(_np!==\"nu\"?_np:void 0)\
-         ||process.env.SHELL||\"/bin/sh\",...{ov}}})))}}",
-        ev = v.enum_var,
-        fex = find_exec,
-        ov = opts_var,
-    );
+    let naive_case = match expand_builtin(&NAIVE_CASE, v) {
+        Ok(i) => i,
+        Err(step) => return (Cow::Borrowed(code), *step),
+    };
 
     // Find insertion point: after Zsh case
     let zsh_case = format!("case {}.Zsh:", v.enum_var);
@@ -562,7 +1189,8 @@ fn patch_naive_case<'a>(code: &'a str, v: &DiscoveredVars) -> (Cow<'a, str>, Ste
         None => {
             return (
                 Cow::Borrowed(code),
-                StepResult::fail("Naive case", "Cannot find executor factory"),
+                StepResult::fail("Naive case", "Cannot find executor factory")
+                    .with_detail(context_near(code, &[".Zsh:", "userTerminalHint"])),
             );
         }
     };
@@ -580,19 +1208,20 @@ fn patch_naive_case<'a>(code: &'a str, v: &DiscoveredVars) -> (Cow<'a, str>, Ste
         } else {
             return (
                 Cow::Borrowed(code),
-                StepResult::fail("Naive case", "Cannot find insertion point for Naive case"),
+                StepResult::fail("Naive case", "Cannot find insertion point for Naive case")
+                    .with_detail(context_at(code, search_from)),
             );
         };
 
-    let mut new_code = String::with_capacity(code.len() + naive_case.len());
-    new_code.push_str(&code[..target_idx]);
-    new_code.push_str(&naive_case);
-    new_code.push_str(&code[target_idx..]);
+    let Some(new_code) = splice(code, target_idx, target_idx, &naive_case) else {
+        return (Cow::Borrowed(code), split_char_failure("Naive case", target_idx));
+    };
 
     (
         Cow::Owned(new_code),
         StepResult::ok("Naive case", format!("Inserted {insert_label}"))
-            .with_detail(format!("Insertion: {naive_case}")),
+            .with_detail(format!("Insertion: {naive_case}"))
+            .with_edit(target_idx..target_idx),
     )
 }
 
@@ -631,8 +1260,7 @@ fn patch_shell_path_fallback<'a>(code: &'a str, v: &DiscoveredVars) -> (Cow<'a,
         );
     }
 
-    // Original: `default:return process.env.SHELL||"/bin/sh"`
-    let find = r#"default:return process.env.SHELL||"/bin/sh""#;
+    let find = SHELL_PATH_DEFAULT;
 
     if !code.contains(find) {
         return (
@@ -640,18 +1268,16 @@ fn patch_shell_path_fallback<'a>(code: &'a str, v: &DiscoveredVars) -> (Cow<'a,
             StepResult::fail(
                 "Shell path fallback",
                 format!("Cannot find `{find}` pattern"),
-            ),
+            )
+            .with_detail(context_near(code, &[r#""/bin/sh""#, "process.env.SHELL", ".PowerShell:return"])),
         );
     }
 
-    // Discover the PowerShell path resolver function from
-    // `case <enum>.PowerShell:return <fn>()` near the default case.
     let idx = match code.find(find) {
         Some(i) => i,
         None => unreachable!(), // already checked above
     };
-    let region_start = idx.saturating_sub(500);
-    let region = &code[region_start..idx];
+    let region = &code[code.floor_char_boundary(idx.saturating_sub(500))..idx];
 
     if !region.contains("findActualExecutable") && !region.contains("PowerShell") {
         return (
@@ -659,44 +1285,29 @@ fn patch_shell_path_fallback<'a>(code: &'a str, v: &DiscoveredVars) -> (Cow<'a,
             StepResult::fail(
                 "Shell path fallback",
                 "Found pattern but not in getShellExecutablePath context",
-            ),
+            )
+            .with_detail(context_at(code, idx)),
         );
     }
 
-    // Extract the PowerShell path resolver function name dynamically
-    // from `case <enum>.PowerShell:return <fn>()`
-    let ps_re = crate::util::re(&format!(
-        r"case {}\.PowerShell:return (\w+)\(\)",
-        v.enum_var,
-    ));
-    let ps_fn = match ps_re.ok().and_then(|r| {
-        r.captures(region).ok().flatten().and_then(|c| {
-            c.get(1).map(|m| m.as_str().to_string())
-        })
-    }) {
-        Some(f) => f,
-        None => {
-            return (
-                Cow::Borrowed(code),
-                StepResult::fail(
-                    "Shell path fallback",
-                    "Cannot discover PowerShell path resolver function name",
-                ),
-            );
-        }
-    };
+    if v.ps_path_fn.is_none() {
+        return (
+            Cow::Borrowed(code),
+            StepResult::fail(
+                "Shell path fallback",
+                "Cannot discover PowerShell path resolver function name",
+            ),
+        );
+    }
 
     // Replace with:
     //   case <enum>.Naive: { const _np = findActualExecutable("nu",[]).cmd;
     //                        if (_np !== "nu") return _np }
     //   default: return process.env.SHELL || ("win32" === process.platform ? <ps_fn>() : "/bin/sh")
-    let replace = format!(
-        "case {ev}.Naive:{{const _np={fex}(\"nu\",[]).cmd;\
-         if(_np!==\"nu\")return _np}}\
-         default:return process.env.SHELL||(\"win32\"===process.platform?{ps_fn}():\"/bin/sh\")",
-        ev = v.enum_var,
-        fex = find_exec,
-    );
+    let replace = match expand_builtin(&SHELL_PATH_FALLBACK, v) {
+        Ok(r) => r,
+        Err(step) => return (Cow::Borrowed(code), *step),
+    };
 
     let new_code = code.replacen(find, &replace, 1);
     let detail = format!("Find:    {find}\nReplace: {replace}");
@@ -704,7 +1315,153 @@ fn patch_shell_path_fallback<'a>(code: &'a str, v: &DiscoveredVars) -> (Cow<'a,
     (
         Cow::Owned(new_code),
         StepResult::ok("Shell path fallback", "Added Naive case with PATH-based nu discovery")
-            .with_detail(detail),
+            .with_detail(detail)
+            .with_edit(idx..idx + find.len()),
+    )
+}
+
+// ---------------------------------------------------------------------------
+//  User rules (--rules)
+// ---------------------------------------------------------------------------
+
+/// Placeholder names available to templates. `{shell}` is only expanded in
+/// built-in templates.
+const PLACEHOLDERS: &[&str] = &[
+    "hint_var",
+    "enum_var",
+    "lazy_exec",
+    "naive_exec",
+    "cmd_exists_fn",
+    "find_exec_call",
+    "opts_var",
+    "shell_opts_var",
+    "ps_path_fn",
+    "shell",
+];
+
+/// Shell the built-in templates wire in through `{shell}`.
+pub const TARGET_SHELL: &str = "nu";
+
+/// Value of a placeholder: a discovered var, or the target shell name for
+/// `{shell}`. `None` if the var wasn't discovered.
+fn placeholder_value<'v>(v: &'v DiscoveredVars, shell: &'v str, name: &str) -> Option<&'v str> {
+    match name {
+        "shell" => Some(shell),
+        "hint_var" => Some(v.hint_var.as_str()),
+        "enum_var" => Some(v.enum_var.as_str()),
+        "lazy_exec" => v.lazy_exec.as_deref(),
+        "naive_exec" => v.naive_exec.as_deref(),
+        "cmd_exists_fn" => v.cmd_exists_fn.as_deref(),
+        "find_exec_call" => v.find_exec_call.as_deref(),
+        "opts_var" => Some(v.opts_var.as_str()),
+        "shell_opts_var" => v.shell_opts_var.as_deref(),
+        "ps_path_fn" => v.ps_path_fn.as_deref(),
+        _ => None,
+    }
+}
+
+/// Expand `{placeholder}` references in a built-in or user template. Only
+/// the names in `PLACEHOLDERS` are expanded; other braces are kept literally
+/// so JS blocks can be written as-is, and `{shell}` is kept too without a
+/// `shell` (user templates). With `escape`, values are regex-escaped.
+fn expand_template(tpl: &str, v: &DiscoveredVars, shell: Option<&str>, escape: bool) -> Result<String, String> {
+    let mut out = tpl.to_string();
+    for name in PLACEHOLDERS {
+        let key = format!("{{{name}}}");
+        if !out.contains(&key) || (*name == "shell" && shell.is_none()) {
+            continue;
+        }
+        let value = placeholder_value(v, shell.unwrap_or_default(), name)
+            .ok_or_else(|| format!("references {name} but none was discovered"))?;
+        let value = match (escape, *name) {
+            (true, "enum_var") => Cow::Borrowed(v.enum_escaped.as_str()),
+            (true, _) => fancy_regex::escape(value),
+            (false, _) => Cow::Borrowed(value),
+        };
+        out = out.replace(&key, &value);
+    }
+    Ok(out)
+}
+
+/// A user rule's `find` and `replace` as applied: expanded for a template
+/// rule, as written for a literal patch-script step.
+fn rule_texts(rule: &UserRule, v: &DiscoveredVars) -> Result<(String, String), String> {
+    if !rule.templated {
+        return Ok((rule.find.clone(), rule.replace.clone()));
+    }
+    Ok((
+        expand_template(&rule.find, v, None, rule.regex)?,
+        expand_template(&rule.replace, v, None, false)?,
+    ))
+}
+
+/// Apply a user rule: locate `find` and replace it or insert around it.
+fn apply_user_rule<'a>(code: &'a str, v: &DiscoveredVars, rule: &UserRule) -> (Cow<'a, str>, StepResult) {
+    let name = rule.name.clone();
+    let (find, replace) = match rule_texts(rule, v) {
+        Ok(texts) => texts,
+        Err(e) => {
+            return (
+                Cow::Borrowed(code),
+                StepResult::fail(name, format!("Rule {e}")).with_kind(FailureKind::Discovery),
+            );
+        }
+    };
+
+    let span = if rule.regex {
+        match re(&find) {
+            Ok(r) => r.find(code).ok().flatten().map(|m| m.range()),
+            Err(e) => {
+                return (
+                    Cow::Borrowed(code),
+                    StepResult::fail(name, format!("Invalid regex: {e}")).with_kind(FailureKind::Validation),
+                );
+            }
+        }
+    } else {
+        if rule.exact {
+            let count = code.matches(&find).count();
+            if count > 1 {
+                return (
+                    Cow::Borrowed(code),
+                    StepResult::fail(name, format!("`{find}` found {count} times (expected exactly 1)")),
+                );
+            }
+        }
+        code.find(&find).map(|i| i..i + find.len())
+    };
+
+    let Some(span) = span else {
+        if rule.mode == RuleMode::Replace && code.contains(&replace) {
+            return (Cow::Borrowed(code), StepResult::skipped(name, "Already present, skipped"));
+        }
+        return (Cow::Borrowed(code), StepResult::fail(name, format!("Cannot find `{find}`")));
+    };
+
+    let already = match rule.mode {
+        RuleMode::Replace => false,
+        RuleMode::InsertBefore => code[..span.start].ends_with(&replace),
+        RuleMode::InsertAfter => code[span.end..].starts_with(&replace),
+    };
+    if already {
+        return (Cow::Borrowed(code), StepResult::skipped(name, "Already present, skipped"));
+    }
+
+    let (at, remove_to, label, verb) = match rule.mode {
+        RuleMode::Replace => (span.start, span.end, "Replaced", "Replace:"),
+        RuleMode::InsertBefore => (span.start, span.start, "Inserted before match", "Before: "),
+        RuleMode::InsertAfter => (span.end, span.end, "Inserted after match", "After:  "),
+    };
+    let Some(new_code) = splice(code, at, remove_to, &replace) else {
+        return (Cow::Borrowed(code), split_char_failure(name, at));
+    };
+
+    let detail = format!("Find:    {}\n{verb} {replace}", &code[span]);
+    (
+        Cow::Owned(new_code),
+        StepResult::ok(name, format!("{label} (user rule)"))
+            .with_detail(detail)
+            .with_edit(at..remove_to),
     )
 }
 
@@ -718,77 +1475,381 @@ struct PatchPlan {
     label: &'static str,
     /// Which patches to apply (in order).
     patches: &'static [(&'static str, PatchFn)],
-    /// Whether all required patches are already applied.
-    is_fully_patched: fn(&QuickDetect) -> bool,
     /// Restore from backup before patching (IDE needs this, CLI does not).
     restore_before_patch: bool,
 }
 
-fn run_patch(path: &Path, dry_run: bool, plan: &PatchPlan) -> PatchResult {
+/// Options shared by the CLI and IDE patch entry points.
+#[derive(Default, Clone)]
+pub struct PatchOptions {
+    pub dry_run: bool,
+    /// User rules from `--rules` and `--patch-script`, in that order. A rule
+    /// named like a built-in step overrides it; others are appended.
+    pub rules: Vec<UserRule>,
+    /// Built-in steps left out of the plans, by name. Recorded next to the
+    /// patched file so status doesn't report them as missing.
+    pub skip: Vec<&'static str>,
+    /// Treat failed steps as a partial result that `force` may write.
+    /// (Every step runs and reports either way.)
+    pub continue_on_error: bool,
+    /// With `continue_on_error`, write the file even though some steps
+    /// failed (the result is still reported as a failure).
+    pub force: bool,
+    /// Record every discovery regex result on the "Pattern discovery" step
+    /// (`StepResult::trace`). Also runs discovery on already-patched files.
+    pub trace_regex: bool,
+    /// Write the patched code here instead of over the agent file, which is
+    /// left untouched: no backup, no restore, no skipped-steps sidecar.
+    pub out: Option<PathBuf>,
+    /// With `dry_run`, write the complete would-be result here as a
+    /// preview. Nothing else is written.
+    pub emit: Option<PathBuf>,
+    /// Write even if the patched code would change the file's encoding
+    /// (byte-order mark, line endings) outside the patched spots.
+    pub allow_reencode: bool,
+}
+
+impl PatchPlan {
+    /// Whether `det` shows every enabled step applied and every disabled
+    /// one absent (so dropping a step from a patched file re-runs the plan).
+    fn is_fully_patched(&self, det: &QuickDetect, skip: &[&str]) -> bool {
+        self.patches.iter().all(|&(name, _)| {
+            let want = !skip.contains(&name);
+            det.has(name).is_none_or(|has| has == want)
+        })
+    }
+}
+
+/// One step of the plan as constructed at runtime.
+enum PlanStep<'r> {
+    Builtin(&'static str, PatchFn),
+    User(&'r UserRule),
+    /// Built-in step turned off through `PatchOptions::skip`.
+    Disabled(&'static str),
+}
+
+impl PlanStep<'_> {
+    fn name(&self) -> Cow<'static, str> {
+        match self {
+            Self::Builtin(name, _) | Self::Disabled(name) => Cow::Borrowed(name),
+            Self::User(rule) => Cow::Owned(rule.name.clone()),
+        }
+    }
+
+    fn apply<'a>(&self, code: &'a str, v: &DiscoveredVars) -> (Cow<'a, str>, StepResult) {
+        match self {
+            Self::Builtin(_, patch_fn) => patch_fn(code, v),
+            Self::Disabled(name) => (Cow::Borrowed(code), StepResult::skipped(*name, "Disabled, skipped")),
+            Self::User(rule) => {
+                let (code, step) = apply_user_rule(code, v, rule);
+                (code, step.mark_user_rule())
+            }
+        }
+    }
+}
+
+/// Merge the built-in steps with the user rules that target this plan. A
+/// user rule named like a built-in step replaces it in place; other rules
+/// are appended in the order given. Skipped built-ins stay in the plan as
+/// `Disabled` so they show up in the step list.
+fn build_steps<'r>(plan: &PatchPlan, opts: &'r PatchOptions) -> Vec<PlanStep<'r>> {
+    let user: Vec<&UserRule> = opts
+        .rules
+        .iter()
+        .filter(|r| r.target.applies_to(plan.label))
+        .collect();
+    let is_builtin = |name: &str| plan.patches.iter().any(|&(n, _)| n == name);
+
+    let builtin = plan.patches.iter().map(|&(name, patch_fn)| {
+        if opts.skip.contains(&name) {
+            return PlanStep::Disabled(name);
+        }
+        match user.iter().find(|r| r.name == name) {
+            Some(rule) => PlanStep::User(rule),
+            None => PlanStep::Builtin(name, patch_fn),
+        }
+    });
+    let appended = user
+        .iter()
+        .filter(|r| !is_builtin(&r.name))
+        .map(|r| PlanStep::User(r));
+    builtin.chain(appended).collect()
+}
+
+/// Check the merged plan against `code` before anything is written, so a
+/// bad rule fails with a precise error instead of half-applying:
+///
+/// - user rule names are unique within the plan;
+/// - every placeholder a rule references was discovered in this file;
+/// - a literal `find` occurs exactly once (or its replacement is already
+///   present, so the rule will be skipped);
+/// - a regex `find` compiles.
+///
+/// Rules run on the code as patched by the steps before them, so the plan
+/// is run in memory and each rule is checked against the code it will see.
+fn validate_steps(
+    plan: &PatchPlan,
+    opts: &PatchOptions,
+    code: &str,
+    v: &DiscoveredVars,
+) -> Result<(), String> {
+    let user: Vec<&UserRule> = opts
+        .rules
+        .iter()
+        .filter(|r| r.target.applies_to(plan.label))
+        .collect();
+    for (i, rule) in user.iter().enumerate() {
+        if user[..i].iter().any(|r| r.name == rule.name) {
+            return Err(format!("rule '{}' is defined more than once", rule.name));
+        }
+    }
+    let mut code = Cow::Borrowed(code);
+    for step in build_steps(plan, opts) {
+        if let PlanStep::User(rule) = step {
+            validate_rule(rule, &code, v)?;
+        }
+        let (new_code, result) = step.apply(&code, v);
+        if let (true, Cow::Owned(new_code)) = (result.ok, new_code) {
+            code = Cow::Owned(new_code);
+        }
+    }
+    Ok(())
+}
+
+/// `validate_steps` for one rule, against the code it will run on.
+fn validate_rule(rule: &UserRule, code: &str, v: &DiscoveredVars) -> Result<(), String> {
+    let name = &rule.name;
+    let (find, replace) = rule_texts(rule, v).map_err(|e| format!("rule '{name}' {e}"))?;
+    if rule.regex {
+        re(&find).map_err(|e| format!("rule '{name}' has an invalid regex: {e}"))?;
+        return Ok(());
+    }
+    match code.matches(&find).count() {
+        1 => Ok(()),
+        0 if rule.mode == RuleMode::Replace && code.contains(&replace) => Ok(()),
+        0 => Err(format!("rule '{name}': `{find}` not found")),
+        n => Err(format!("rule '{name}': `{find}` found {n} times (expected exactly 1)")),
+    }
+}
+
+fn run_patch(path: &Path, opts: &PatchOptions, plan: &PatchPlan) -> PatchResult {
+    let _span = tracing::info_span!("patch", agent = plan.label, file = %display_name(path)).entered();
+    let dry_run = opts.dry_run;
+    let plan_steps = build_steps(plan, opts);
+    let has_user_rules = plan_steps.iter().any(|s| matches!(s, PlanStep::User(_)));
     let mut steps: Vec<StepResult> = Vec::new();
     let fail = |steps| PatchResult { success: false, steps };
 
-    // Read the live file and check if already fully patched.
-    let live_code = match fs::read_to_string(path) {
+    // Read the live file and check if already fully patched. User rules
+    // aren't tracked by quick_detect, so always run the plan when present.
+    let live_code = match tracing::info_span!("read").in_scope(|| read_text(path)) {
         Ok(c) => c,
         Err(e) => {
-            return fail(vec![StepResult::fail("Read", format!("Failed to read {} agent: {e}", plan.label))]);
+            let e = Error::read_text(&format!("read {} agent", plan.label), path, e);
+            return fail(vec![StepResult::fail("Read", e.chain()).with_kind(e.kind())]);
         }
     };
-    if let Some(det) = quick_detect(&live_code)
-        && (plan.is_fully_patched)(&det)
+    if !has_user_rules
+        && !opts.trace_regex
+        && opts.out.is_none()
+        && opts.emit.is_none()
+        && let Some(det) = detect_read(path, &live_code)
+        && plan.is_fully_patched(&det, &opts.skip)
     {
         steps.push(StepResult::ok("Pattern discovery", "Discovered minified variable names"));
-        for &(name, _) in plan.patches {
-            steps.push(StepResult::skipped(name, "Already present, skipped"));
+        for step in &plan_steps {
+            let msg = match step {
+                PlanStep::Disabled(_) => "Disabled, skipped",
+                _ => "Already present, skipped",
+            };
+            steps.push(StepResult::skipped(step.name(), msg));
         }
         return PatchResult { success: true, steps };
     }
-    // Drop early so the IDE path can re-read after restore.
-    drop(live_code);
+    // Validate user rules before backup/restore touch anything, against the
+    // code the plan will actually run on (the backup, when restoring first).
+    if has_user_rules {
+        let source = match fs::read_to_string(bak_path(path)) {
+            Ok(bak) if plan.restore_before_patch => Cow::Owned(bak),
+            _ => Cow::Borrowed(live_code.as_str()),
+        };
+        let checked = discover_vars(&source)
+            .map_err(|e| e.to_string())
+            .and_then(|v| validate_steps(plan, opts, &source, &v));
+        if let Err(err) = checked {
+            return fail(vec![StepResult::fail("Rule validation", err).with_kind(FailureKind::Validation)]);
+        }
+    }
+    // Patch the code already in memory unless an existing (older) backup is
+    // the pristine source. Without one, the backup is only made right before
+    // the write, once the file is known to be agent code with something to
+    // write -- `patch --dir` and `--glob` leave nothing next to the files
+    // they skip.
+    let write_in_place = !dry_run && opts.out.is_none();
+    let from_backup = plan.restore_before_patch && bak_path(path).exists() && (write_in_place || opts.out.is_some());
+    if write_in_place && from_backup && let Err(e) = restore_from_backup(path, None) {
+        return fail(vec![StepResult::fail("Restore", e.chain()).with_kind(e.kind())]);
+    }
+
+    // The backup is what the live file was just restored to, and with `out`
+    // (where nothing is restored) it's read in its place.
+    let code = if from_backup {
+        let bak = bak_path(path);
+        match tracing::info_span!("read").in_scope(|| read_text(&bak)) {
+            Ok(c) => c,
+            Err(e) => {
+                let e = Error::read_text(&format!("read {} agent backup", plan.label), &bak, e);
+                return fail(vec![StepResult::fail("Read", e.chain()).with_kind(e.kind())]);
+            }
+        }
+    } else {
+        live_code
+    };
+    let (code, mut result) = apply_plan(&plan_steps, &code, opts);
+    let Some(code) = code else {
+        return result;
+    };
+
+    if dry_run && let Some(emit) = &opts.emit {
+        if let Err(e) = fs::write(emit, code.as_bytes()) {
+            let e = Error::io("write preview to", emit, e);
+            result.steps.push(StepResult::fail("Emit", e.chain()).with_kind(e.kind()));
+            result.success = false;
+            return result;
+        }
+        result.steps.push(StepResult::ok("Emit", format!("Preview written: {}", emit.display())));
+    }
+
+    if let Some(out) = &opts.out {
+        if dry_run {
+            result.steps.push(StepResult::skipped("Write", format!("Would write: {}", out.display())));
+        } else if let Err(e) = fs::write(out, code.as_bytes()) {
+            let e = Error::io(&format!("write {} agent", plan.label), out, e);
+            result.steps.push(StepResult::fail("Write", e.chain()).with_kind(e.kind()));
+            result.success = false;
+        } else {
+            result.steps.push(StepResult::ok("Write", format!("Written: {}", out.display())));
+        }
+        return result;
+    }
 
     if !dry_run {
         if let Err(e) = backup(path) {
-            return fail(vec![StepResult::fail("Backup", format!("Failed to create backup: {e}"))]);
+            result.steps.push(StepResult::fail("Backup", e.chain()).with_kind(e.kind()));
+            result.success = false;
+            return result;
         }
-        if plan.restore_before_patch
-            && let Err(e) = restore_from_backup(path)
-        {
-            return fail(vec![StepResult::fail("Restore", format!("Failed to restore from backup: {e}"))]);
+        let written = tracing::info_span!("write").in_scope(|| write_retrying(path, code.as_bytes()));
+        let retries = match written {
+            Ok(n) => n,
+            Err(e) => {
+                let e = Error::io(&format!("write {} agent", plan.label), path, e);
+                result.steps.push(StepResult::fail("Write", e.chain()).with_kind(e.kind()));
+                result.success = false;
+                return result;
+            }
+        };
+        result.steps.push(StepResult::ok(
+            "Write",
+            format!("Written: {}{}", display_name(path), retried_note(retries)),
+        ));
+        let skipped: Vec<&str> = plan
+            .patches
+            .iter()
+            .map(|&(name, _)| name)
+            .filter(|name| opts.skip.contains(name))
+            .collect();
+        if let Err(e) = write_skipped(path, &skipped) {
+            let e = Error::io("record skipped steps in", &state_path(path), e);
+            result.steps.push(StepResult::fail("State", e.chain()).with_kind(e.kind()));
+            result.success = false;
+            return result;
         }
+    } else {
+        result.steps.push(StepResult::skipped("Write", format!("Would write: {}", display_name(path))));
     }
 
-    // (Re-)read the file (may have been restored from backup).
-    let code = match fs::read_to_string(path) {
-        Ok(c) => c,
-        Err(e) => {
-            return fail(vec![StepResult::fail("Read", format!("Failed to read {} agent: {e}", plan.label))]);
-        }
+    result
+}
+
+/// Discover names in `code` and run `plan_steps` over it, in memory.
+/// Returns the patched code when it should be written: every step
+/// succeeded, or a partial result is forced (`continue_on_error` + `force`).
+/// Otherwise the steps end with a skipped "Write" saying why not.
+fn apply_plan<'c>(
+    plan_steps: &[PlanStep],
+    code: &'c str,
+    opts: &PatchOptions,
+) -> (Option<Cow<'c, str>>, PatchResult) {
+    let mut steps: Vec<StepResult> = Vec::new();
+    let fail = |steps| (None, PatchResult { success: false, steps });
+
+    let mut trace = if opts.trace_regex { RegexTrace::enabled() } else { RegexTrace::default() };
+    let discovered = tracing::info_span!("discovery").in_scope(|| discover_vars_traced(code, &mut trace));
+    let limit_note = match trace.limit_hits.as_slice() {
+        [] => String::new(),
+        hits => format!(" (backtrack limit hit: {})", hits.join(", ")),
     };
-    let v = match discover_vars(&code) {
+    let trace = trace.into_text();
+    let v = match discovered {
         Ok(v) => v,
         Err(err) => {
-            return fail(vec![StepResult::fail("Pattern discovery", err)]);
+            let step = match err {
+                Error::PatternNotFound { pattern, near, .. } => {
+                    StepResult::fail("Pattern discovery", format!("Cannot find {pattern}{limit_note}"))
+                        .with_detail(near.unwrap_or_default())
+                }
+                err => StepResult::fail("Pattern discovery", format!("{err}{limit_note}")),
+            };
+            return fail(vec![step.with_kind(FailureKind::Discovery).with_trace(trace)]);
         }
     };
 
-    steps.push(StepResult::ok("Pattern discovery", "Discovered minified variable names")
+    steps.push(StepResult::ok("Pattern discovery", format!("Discovered minified variable names{limit_note}"))
         .with_detail(format!(
             "hint_var={}  enum_var={}  lazy_exec={:?}  naive_exec={:?}  \
-             cmd_exists={:?}  find_exec={:?}  has_uth={}  has_sys_nu={}",
+             cmd_exists={:?}  find_exec={:?}  opts={}  shell_opts={:?}  ps_path={:?}  \
+             has_uth={}  has_sys_nu={}",
             v.hint_var, v.enum_var, v.lazy_exec, v.naive_exec,
-            v.cmd_exists_fn, v.find_exec_call,
+            v.cmd_exists_fn, v.find_exec_call, v.opts_var, v.shell_opts_var, v.ps_path_fn,
             v.has_user_terminal_hint, v.has_system_nu,
-        )));
-
-    // Apply each patch in order.
-    let mut code = Cow::Borrowed(code.as_str());
-    for &(_name, patch_fn) in plan.patches {
-        let (new_code, step) = patch_fn(&code, &v);
+        ))
+        .with_trace(trace));
+
+    // Apply each patch in order, in memory. A failed step leaves the code
+    // unchanged and the remaining steps still run, so one pass reports
+    // every pattern that can't be found; nothing is written unless all
+    // steps succeeded (or a partial write is forced below).
+    let mut code = Cow::Borrowed(code);
+    let mut reencoded = None;
+    let mut failed = 0;
+    let mut applied = 0;
+    for plan_step in plan_steps {
+        let (new_code, mut step) = tracing::info_span!("step", name = %plan_step.name())
+            .in_scope(|| plan_step.apply(&code, &v));
         let ok = step.ok;
+        // Patch steps fail because an anchor wasn't found unless they say
+        // otherwise (e.g. a placeholder that wasn't discovered).
+        if !ok && step.kind.is_none() {
+            step.kind = Some(FailureKind::PatternNotFound);
+        }
+        if ok && !step.skipped {
+            applied += 1;
+        }
+        // Every step splices into the code it was given, so any other
+        // change is nupatch altering bytes it shouldn't -- bytes the
+        // integrity chain hashes.
+        if ok
+            && reencoded.is_none()
+            && let Cow::Owned(after) = &new_code
+            && let Some(change) = encoding_change(&code, after, step.edit.clone())
+        {
+            reencoded = Some((step.name.clone(), change));
+        }
         steps.push(step);
         if !ok {
-            return PatchResult { success: false, steps };
+            failed += 1;
+            continue;
         }
         code = match new_code {
             Cow::Borrowed(_) => code,
@@ -796,17 +1857,135 @@ fn run_patch(path: &Path, dry_run: bool, plan: &PatchPlan) -> PatchResult {
         };
     }
 
-    if !dry_run {
-        if let Err(e) = fs::write(path, code.as_bytes()) {
-            steps.push(StepResult::fail("Write", format!("Failed to write {} agent: {e}", plan.label)));
-            return PatchResult { success: false, steps };
+    // Partial results are only written when forced, and only if something
+    // actually changed.
+    if failed > 0 && !(opts.continue_on_error && opts.force && applied > 0) {
+        let why = if opts.force {
+            "no step applied"
+        } else if opts.continue_on_error {
+            "pass --force to write partial results"
+        } else {
+            "fix these first, or see --continue-on-error"
+        };
+        steps.push(StepResult::skipped(
+            "Write",
+            format!("Not written: {failed} step(s) failed ({why})"),
+        ));
+        return fail(steps);
+    }
+
+    if !opts.allow_reencode && let Some((name, change)) = reencoded {
+        steps.push(
+            StepResult::fail("Encoding", format!("{name} would change the file's {change}"))
+                .with_detail("Nothing was written. Pass --allow-reencode to write it anyway.")
+                .with_kind(FailureKind::Validation),
+        );
+        return fail(steps);
+    }
+
+    (Some(code), PatchResult { success: failed == 0, steps })
+}
+
+/// How a step's output differs from its input outside the step's own
+/// `edit`: a byte-order mark added or lost, line endings normalized,
+/// characters replaced by a lossy conversion, or other bytes touched. `None`
+/// when the untouched prefix and suffix around the splice are identical.
+fn encoding_change(before: &str, after: &str, edit: Option<Range<usize>>) -> Option<&'static str> {
+    let (b, a) = (before.as_bytes(), after.as_bytes());
+    let untouched = match edit {
+        Some(edit) => {
+            let suffix = &b[edit.end..];
+            a.starts_with(&b[..edit.start])
+                && a.len() >= edit.start + suffix.len()
+                && a.ends_with(suffix)
         }
-        steps.push(StepResult::ok("Write", format!("Written: {}", display_name(path))));
+        None => a == b,
+    };
+    if untouched {
+        return None;
+    }
+
+    const BOM: char = '\u{feff}';
+    let endings = |s: &str| {
+        let crlf = s.matches("\r\n").count();
+        (crlf, s.matches('\r').count() - crlf, s.matches('\n').count() - crlf)
+    };
+    let ((crlf, cr, lf), (crlf_after, cr_after, lf_after)) = (endings(before), endings(after));
+    Some(if before.starts_with(BOM) != after.starts_with(BOM) {
+        "byte-order mark"
+    } else if crlf_after < crlf || cr_after < cr || (crlf > 0 && lf == 0 && lf_after > 0) {
+        "line endings"
+    } else if after.matches('\u{fffd}').count() > before.matches('\u{fffd}').count() {
+        "characters (lossy conversion)"
     } else {
-        steps.push(StepResult::skipped("Write", format!("Would write: {}", display_name(path))));
+        "contents outside the patched code"
+    })
+}
+
+/// Patch `code` in memory with the CLI (`label == "CLI"`) or IDE plan,
+/// touching no files: no backup, no write, no integrity chain. Returns the
+/// patched code when a file would have been written, plus the step results.
+pub fn patch_code(label: &str, code: &str, opts: &PatchOptions) -> (Option<String>, PatchResult) {
+    let plan = if label == "CLI" { &CLI_PLAN } else { &IDE_PLAN };
+    let plan_steps = build_steps(plan, opts);
+    if plan_steps.iter().any(|s| matches!(s, PlanStep::User(_))) {
+        let checked = discover_vars(code)
+            .map_err(|e| e.to_string())
+            .and_then(|v| validate_steps(plan, opts, code, &v));
+        if let Err(err) = checked {
+            let step = StepResult::fail("Rule validation", err).with_kind(FailureKind::Validation);
+            return (None, PatchResult { success: false, steps: vec![step] });
+        }
+    }
+    let (patched, result) = apply_plan(&plan_steps, code, opts);
+    (patched.map(Cow::into_owned), result)
+}
+
+// ---------------------------------------------------------------------------
+//  Patch state sidecar
+// ---------------------------------------------------------------------------
+
+/// `<file>.nupatch.json`: built-in steps intentionally left out of the last
+/// patch run, so status can tell "disabled" from "missing".
+pub fn state_path(path: &Path) -> PathBuf {
+    let mut name = path.file_name().unwrap_or_default().to_os_string();
+    name.push(".nupatch.json");
+    path.with_file_name(name)
+}
+
+/// `<file>.patched`: where `patch --side-by-side` writes the patched agent.
+pub fn patched_path(path: &Path) -> PathBuf {
+    let mut name = path.file_name().unwrap_or_default().to_os_string();
+    name.push(".patched");
+    path.with_file_name(name)
+}
+
+/// Record the skipped steps for `path`, removing the sidecar when none are.
+fn write_skipped(path: &Path, skipped: &[&str]) -> std::io::Result<()> {
+    let state = state_path(path);
+    if skipped.is_empty() {
+        return match fs::remove_file(&state) {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(e),
+            _ => Ok(()),
+        };
     }
+    let json = serde_json::json!({ "skipped": skipped });
+    fs::write(state, serde_json::to_string_pretty(&json)? + "\n")
+}
 
-    PatchResult { success: true, steps }
+/// Steps recorded as skipped for `path`. Empty if there is no sidecar.
+fn read_skipped(path: &Path) -> Vec<String> {
+    let Ok(text) = fs::read_to_string(state_path(path)) else {
+        return Vec::new();
+    };
+    serde_json::from_str::<serde_json::Value>(&text)
+        .ok()
+        .and_then(|v| {
+            v.get("skipped")?
+                .as_array()
+                .map(|a| a.iter().filter_map(|s| s.as_str().map(String::from)).collect())
+        })
+        .unwrap_or_default()
 }
 
 // ---------------------------------------------------------------------------
@@ -820,13 +1999,12 @@ const CLI_PLAN: PatchPlan = PatchPlan {
         ("System nu detection", patch_system_nu_detection),
         ("Naive case", patch_naive_case),
     ],
-    is_fully_patched: |d| d.has_nu && d.has_system_nu && d.has_naive_case,
     restore_before_patch: true,
 };
 
 /// Patch the CLI agent file. Applies nu detection and Naive executor case.
-pub fn patch_cli_agent(path: &Path, dry_run: bool) -> PatchResult {
-    run_patch(path, dry_run, &CLI_PLAN)
+pub fn patch_cli_agent(path: &Path, opts: &PatchOptions) -> PatchResult {
+    run_patch(path, opts, &CLI_PLAN)
 }
 
 // ---------------------------------------------------------------------------
@@ -841,23 +2019,88 @@ const IDE_PLAN: PatchPlan = PatchPlan {
         ("userTerminalHint", patch_user_terminal_hint),
         ("Shell path fallback", patch_shell_path_fallback),
     ],
-    is_fully_patched: |d| d.has_nu && d.has_system_nu && d.has_uth,
     restore_before_patch: true,
 };
 
 /// Patch the IDE agent file. Applies nu detection and userTerminalHint wiring.
-pub fn patch_ide_agent(path: &Path, dry_run: bool) -> PatchResult {
-    run_patch(path, dry_run, &IDE_PLAN)
+pub fn patch_ide_agent(path: &Path, opts: &PatchOptions) -> PatchResult {
+    run_patch(path, opts, &IDE_PLAN)
+}
+
+/// Plain-language `(step, explanation)` pairs for the steps the CLI
+/// (`label == "CLI"`) or IDE plan will run with `opts`, in order. Built-in
+/// steps are explained from their `BuiltinRule`; user rules by what they
+/// edit.
+pub fn explain_plan(label: &str, opts: &PatchOptions) -> Vec<(Cow<'static, str>, String)> {
+    let plan = if label == "CLI" { &CLI_PLAN } else { &IDE_PLAN };
+    let builtin = |name: &str| BUILTIN_RULES.iter().find(|r| r.name == name);
+    build_steps(plan, opts)
+        .iter()
+        .map(|step| {
+            let text = match step {
+                PlanStep::Builtin(name, _) => builtin(name)
+                    .map(|rule| format!("{}. {}.", rule.description, rule.why))
+                    .unwrap_or_default(),
+                PlanStep::Disabled(name) => match builtin(name) {
+                    Some(rule) => format!("Disabled for this run ({}).", rule.description),
+                    None => "Disabled for this run.".to_string(),
+                },
+                PlanStep::User(rule) => {
+                    let verb = match rule.mode {
+                        RuleMode::Replace => "replaces",
+                        RuleMode::InsertBefore => "inserts before",
+                        RuleMode::InsertAfter => "inserts after",
+                    };
+                    let role = if builtin(&rule.name).is_some() {
+                        "User rule overriding the built-in step"
+                    } else {
+                        "User rule"
+                    };
+                    format!("{role}: {verb} `{}`.", rule.find)
+                }
+            };
+            (step.name(), text)
+        })
+        .collect()
 }
 
 // ---------------------------------------------------------------------------
 //  Public API -- Check status
 // ---------------------------------------------------------------------------
 
+/// Check whether the `.bak` of `path` is free of nupatch's patches.
+/// `None` if there is no readable backup or its patterns aren't recognized.
+pub fn backup_pristine(path: &Path) -> Option<bool> {
+    let det = detect_file(&bak_path(path))?;
+    Some(!(det.has_nu || det.has_system_nu || det.has_naive_case || det.has_uth))
+}
+
+/// Last time nupatch wrote `path`: its mtime, provided a backup exists and
+/// at least one patch is present (otherwise the mtime is Cursor's own).
+fn last_patched(path: &Path, comp: &ComponentStatus) -> Option<SystemTime> {
+    if !comp.backup_exists || !comp.patches.values().any(|&v| v) {
+        return None;
+    }
+    fs::metadata(path).and_then(|m| m.modified()).ok()
+}
+
+/// Move steps recorded as intentionally skipped out of `comp.patches`.
+fn record_skipped(path: &Path, comp: &mut ComponentStatus) {
+    comp.skipped = read_skipped(path);
+    for name in &comp.skipped {
+        comp.patches.remove(name);
+    }
+}
+
 /// Return the current patch / integrity status without modifying files.
 pub fn check_status(paths: &CursorPaths) -> PatchStatus {
+    let _span = tracing::info_span!("status").entered();
     let mut status = PatchStatus::default();
 
+    if let Some(product_json) = &paths.product_json {
+        status.cursor = read_cursor_version(product_json);
+    }
+
     // CLI
     if let Some(cli_index) = &paths.cli_index
         && cli_index.is_file()
@@ -865,15 +2108,18 @@ pub fn check_status(paths: &CursorPaths) -> PatchStatus {
         status.cli.exists = true;
         status.cli.path = Some(display_name(cli_index).into_owned());
         status.cli.backup_exists = bak_path(cli_index).exists();
+        status.cli.backup_pristine = backup_pristine(cli_index);
 
-        if let Ok(code) = fs::read_to_string(cli_index)
-            && let Some(det) = quick_detect(&code)
-        {
+        if let Some(det) = detect_file(cli_index) {
             status.cli.patches.insert("Nu detection".into(), det.has_nu);
             status.cli.patches.insert("System nu detection".into(), det.has_system_nu);
             status.cli.patches.insert("Naive case".into(), det.has_naive_case);
         }
+        record_skipped(cli_index, &mut status.cli);
+        status.cli.last_patched = last_patched(cli_index, &status.cli);
+        status.cli.recorded_hash_matches = manifest::check(cli_index).map(|c| c.matches());
     }
+    status.cli.newer_unpatched = newer_unpatched_cli(paths);
 
     // IDE
     if let Some(ide_main) = &paths.ide_main
@@ -882,14 +2128,15 @@ pub fn check_status(paths: &CursorPaths) -> PatchStatus {
         status.ide.exists = true;
         status.ide.path = Some(display_name(ide_main).into_owned());
         status.ide.backup_exists = bak_path(ide_main).exists();
+        status.ide.backup_pristine = backup_pristine(ide_main);
 
-        if let Ok(code) = fs::read_to_string(ide_main)
-            && let Some(det) = quick_detect(&code)
-        {
+        if let Some(det) = detect_file(ide_main) {
             status.ide.patches.insert("Nu detection".into(), det.has_nu);
             status.ide.patches.insert("System nu detection".into(), det.has_system_nu);
             status.ide.patches.insert("userTerminalHint".into(), det.has_uth);
         }
+        record_skipped(ide_main, &mut status.ide);
+        status.ide.last_patched = last_patched(ide_main, &status.ide);
     }
 
     // Integrity
@@ -904,6 +2151,7 @@ pub fn check_status(paths: &CursorPaths) -> PatchStatus {
     if let (Some(product_json), Some(cursor_app)) = (&paths.product_json, &paths.cursor_app)
         && product_json.is_file()
     {
+        status.integrity.product_json_found = true;
         status.integrity.product_checksums_match =
             checksums_all_match(product_json, cursor_app);
     }
@@ -911,28 +2159,340 @@ pub fn check_status(paths: &CursorPaths) -> PatchStatus {
     status
 }
 
+/// Every dimension that prevents a clean `status --check`. Unknown
+/// integrity (`None`) counts as a failure.
+pub fn check_failures(status: &PatchStatus) -> Vec<CheckFailure> {
+    let mut failures = Vec::new();
+    if !status.cli.fully_patched() {
+        failures.push(CheckFailure::CliNotPatched);
+    }
+    if !status.ide.fully_patched() {
+        failures.push(CheckFailure::IdeNotPatched);
+    }
+    if status.integrity.ehp_hash_matches != Some(true) {
+        failures.push(CheckFailure::EhpHashMismatch);
+    }
+    if status.integrity.product_checksums_match != Some(true) {
+        failures.push(CheckFailure::ProductChecksumsMismatch);
+    }
+    failures
+}
+
+// ---------------------------------------------------------------------------
+//  Public API -- Discovery dump
+// ---------------------------------------------------------------------------
+
+/// Run full pattern discovery on `path` and return the discovered names as
+/// `(label, value)` pairs for display.
+pub fn discover_file(path: &Path) -> Result<Vec<(&'static str, String)>, Error> {
+    let code = read_text(path).map_err(|e| Error::read_text("read", path, e))?;
+    discover_code(&code)
+}
+
+/// `discover_file` on code already in memory.
+pub fn discover_code(code: &str) -> Result<Vec<(&'static str, String)>, Error> {
+    let v = discover_vars(code)?;
+    let opt = |o: &Option<String>| o.clone().unwrap_or_else(|| "(not found)".to_string());
+    Ok(vec![
+        ("hint_var", v.hint_var.clone()),
+        ("enum_var", v.enum_var.clone()),
+        ("lazy_exec", opt(&v.lazy_exec)),
+        ("naive_exec", opt(&v.naive_exec)),
+        ("cmd_exists_fn", opt(&v.cmd_exists_fn)),
+        ("find_exec_call", opt(&v.find_exec_call)),
+        ("opts_var", v.opts_var.clone()),
+        ("shell_opts_var", opt(&v.shell_opts_var)),
+        ("ps_path_fn", opt(&v.ps_path_fn)),
+    ])
+}
+
+// ---------------------------------------------------------------------------
+//  Public API -- Compare
+// ---------------------------------------------------------------------------
+
+/// The patches quick detection can see in `code`, in plan order. `None`
+/// when `code` isn't recognizable agent code (no zsh anchor).
+pub fn patch_flags(code: &str) -> Option<Vec<(&'static str, bool)>> {
+    let det = quick_detect(code)?;
+    let names = ["Nu detection", "System nu detection", "userTerminalHint", "Naive case"];
+    Some(names.iter().filter_map(|&n| det.has(n).map(|has| (n, has))).collect())
+}
+
+/// Bytes searched before / after the zsh check for the bounds of the
+/// detectShellType statement.
+const REGION_BEFORE: usize = 120;
+const REGION_AFTER: usize = 480;
+
+/// The detectShellType region of `code`: the statement holding the
+/// `includes("zsh")` check (from the previous `;` or `}` to the next `}`),
+/// where the nu detection patches land. Falls back to a fixed window when
+/// those bounds are too far away.
+pub fn shell_type_region(code: &str) -> Option<&str> {
+    let at = code.find(r#".includes("zsh")"#)?;
+    let lo = code.floor_char_boundary(at.saturating_sub(REGION_BEFORE));
+    let hi = code.floor_char_boundary(at + REGION_AFTER);
+    let start = code[lo..at].rfind([';', '}']).map_or(lo, |i| lo + i + 1);
+    let end = code[at..hi].find('}').map_or(hi, |i| at + i + 1);
+    Some(code[start..end].trim_start())
+}
+
+/// One span of a `diff_regions` result.
+pub enum RegionDiff<'a> {
+    Same(&'a str),
+    Removed(&'a str),
+    Added(&'a str),
+}
+
+/// Byte ranges of `code` split into identifier runs and single
+/// punctuation chars.
+fn token_ranges(code: &str) -> Vec<(usize, usize)> {
+    let mut out = Vec::new();
+    let mut start = 0;
+    let mut in_word = false;
+    for (i, c) in code.char_indices() {
+        let word = c.is_alphanumeric() || c == '_' || c == '$';
+        if i > start && !(word && in_word) {
+            out.push((start, i));
+            start = i;
+        }
+        in_word = word;
+    }
+    if start < code.len() {
+        out.push((start, code.len()));
+    }
+    out
+}
+
+/// Token-level diff of two regions (longest common subsequence), merged
+/// into spans. Differences at either edge are dropped: the windows are
+/// cut at fixed offsets, so text there is misaligned rather than changed.
+pub fn diff_regions<'a>(a: &'a str, b: &'a str) -> Vec<RegionDiff<'a>> {
+    let (ra, rb) = (token_ranges(a), token_ranges(b));
+    let ta: Vec<&str> = ra.iter().map(|&(s, e)| &a[s..e]).collect();
+    let tb: Vec<&str> = rb.iter().map(|&(s, e)| &b[s..e]).collect();
+    let (n, m) = (ta.len(), tb.len());
+    // lcs[i][j]: LCS length of ta[i..] and tb[j..].
+    let mut lcs = vec![vec![0u32; m + 1]; n + 1];
+    for i in (0..n).rev() {
+        for j in (0..m).rev() {
+            lcs[i][j] = if ta[i] == tb[j] {
+                lcs[i + 1][j + 1] + 1
+            } else {
+                lcs[i + 1][j].max(lcs[i][j + 1])
+            };
+        }
+    }
+
+    // (kind, start, end): 0 same and 1 removed (ranges in `a`), 2 added (in `b`).
+    let mut runs: Vec<(u8, usize, usize)> = Vec::new();
+    let mut push = |kind: u8, (start, end): (usize, usize)| match runs.last_mut() {
+        Some(last) if last.0 == kind => last.2 = end,
+        _ => runs.push((kind, start, end)),
+    };
+    let (mut i, mut j) = (0, 0);
+    while i < n || j < m {
+        if i < n && j < m && ta[i] == tb[j] {
+            push(0, ra[i]);
+            i += 1;
+            j += 1;
+        } else if j < m && (i == n || lcs[i][j + 1] >= lcs[i + 1][j]) {
+            push(2, rb[j]);
+            j += 1;
+        } else {
+            push(1, ra[i]);
+            i += 1;
+        }
+    }
+
+    let first = runs.iter().position(|r| r.0 == 0).unwrap_or(0);
+    let last = runs.iter().rposition(|r| r.0 == 0).map_or(runs.len(), |l| l + 1);
+    runs[first..last]
+        .iter()
+        .map(|&(kind, start, end)| match kind {
+            0 => RegionDiff::Same(&a[start..end]),
+            1 => RegionDiff::Removed(&a[start..end]),
+            _ => RegionDiff::Added(&b[start..end]),
+        })
+        .collect()
+}
+
+// ---------------------------------------------------------------------------
+//  Public API -- Versions / info
+// ---------------------------------------------------------------------------
+
+/// Read `version` and `commit` from product.json. Missing fields or an
+/// unreadable file yield `None` values rather than an error.
+pub fn read_cursor_version(product_json: &Path) -> CursorVersion {
+    let product: Option<serde_json::Value> = fs::read_to_string(product_json)
+        .ok()
+        .and_then(|t| serde_json::from_str(&t).ok());
+    let field = |key: &str| {
+        product
+            .as_ref()
+            .and_then(|p| p.get(key))
+            .and_then(|v| v.as_str())
+            .map(str::to_string)
+    };
+    CursorVersion {
+        version: field("version"),
+        commit: field("commit"),
+    }
+}
+
+/// The newest CLI agent version present, if it is newer than every version
+/// the manifest says nupatch patched and has no patches -- usually a Cursor
+/// update that arrived after the last patch. `None` when nothing was patched.
+pub fn newer_unpatched_cli(paths: &CursorPaths) -> Option<String> {
+    let manifest = Manifest::load();
+    let newest_patched = manifest.cli_agents().into_iter().map(cli_version_key).max()?;
+    let newest = cli_agent_indexes(paths.cli_agent_dir.as_deref()?).pop()?;
+    if cli_version_key(&newest) <= newest_patched || !looks_unpatched(&newest) {
+        return None;
+    }
+    cli_agent_version(&newest)
+}
+
+/// CLI agent versions the manifest says nupatch patched, other than
+/// `except`, that are still present but now look unpatched (e.g. after a
+/// partial update). Oldest first.
+pub fn lost_cli_patches(except: &[&Path]) -> Vec<String> {
+    let manifest = Manifest::load();
+    let mut lost: Vec<PathBuf> = manifest
+        .cli_agents()
+        .into_iter()
+        .filter(|index| !except.contains(index) && looks_unpatched(index))
+        .map(Path::to_path_buf)
+        .collect();
+    sort_cli_indexes(&mut lost);
+    lost.iter().filter_map(|index| cli_agent_version(index)).collect()
+}
+
+/// Whether a CLI agent is recognized and has none of the CLI patches.
+fn looks_unpatched(index: &Path) -> bool {
+    detect_file(index).is_some_and(|det| !det.has_nu && !det.has_naive_case)
+}
+
+/// CLI agent version, taken from the `versions/<ver>/index.js` directory name.
+pub fn cli_agent_version(cli_index: &Path) -> Option<String> {
+    cli_index
+        .parent()
+        .and_then(Path::file_name)
+        .map(|n| n.to_string_lossy().into_owned())
+}
+
+/// Collect version and platform details for bug reports.
+pub fn install_info(paths: &CursorPaths) -> InstallInfo {
+    InstallInfo {
+        cursor: paths
+            .product_json
+            .as_deref()
+            .map(read_cursor_version)
+            .unwrap_or_default(),
+        cursor_app: paths
+            .cursor_app
+            .as_ref()
+            .map(|p| p.display().to_string()),
+        cli_version: paths.cli_index.as_deref().and_then(cli_agent_version),
+        os: std::env::consts::OS,
+        arch: std::env::consts::ARCH,
+    }
+}
+
 // ---------------------------------------------------------------------------
 //  Public API -- Revert all
 // ---------------------------------------------------------------------------
 
-/// Restore all patched files from their `.bak` backups.
-pub fn revert_all(paths: &CursorPaths) -> RevertResult {
+/// Restore all patched files from their `.bak` backups. With
+/// `all_versions`, every CLI agent version nupatch patched is restored
+/// rather than just the detected one (see `revert_cli_targets`), each named
+/// by its version and only from the original it was patched from.
+pub fn revert_all(paths: &CursorPaths, all_versions: bool) -> RevertResult {
     let mut result = RevertResult { files: vec![] };
 
-    let targets: [Option<&Path>; 4] = [
-        paths.cli_index.as_deref(),
+    let manifest = Manifest::load();
+    for index in revert_cli_targets(paths, all_versions) {
+        let original = manifest.files.get(&index).and_then(|r| r.original_sha256.as_deref());
+        if !all_versions {
+            result.files.push(revert_file(&index, display_name(&index).into_owned(), original));
+            continue;
+        }
+        let name = match cli_agent_version(&index) {
+            Some(version) => format!("{version}/index.js"),
+            None => display_name(&index).into_owned(),
+        };
+        if !index.exists() {
+            result.files.push(RevertFileResult {
+                filename: name,
+                restored: false,
+                error: None,
+                skipped: None,
+                gone: true,
+            });
+            continue;
+        }
+        // A stored original is restored whatever the `.bak` holds.
+        let skipped = if original.and_then(stored_original).is_some() {
+            None
+        } else if let Some(original) = original
+            && sha256_hex(&bak_path(&index)).is_ok_and(|bak| bak != original)
+        {
+            Some("Backup isn't the original nupatch patched")
+        } else if backup_pristine(&index) == Some(false) {
+            Some("Backup already contains patches")
+        } else {
+            None
+        };
+        result.files.push(match skipped {
+            Some(reason) => {
+                RevertFileResult { filename: name, restored: false, error: None, skipped: Some(reason), gone: false }
+            }
+            None => revert_file(&index, name, original),
+        });
+    }
+
+    let targets: [Option<&Path>; 3] = [
         paths.ide_main.as_deref(),
         paths.ehp.as_deref(),
         paths.product_json.as_deref(),
     ];
-
     for target in targets.into_iter().flatten() {
-        let restored = restore_from_backup(target).unwrap_or(false);
-        result.files.push(RevertFileResult {
-            filename: display_name(target).into_owned(),
-            restored,
-        });
+        result.files.push(revert_file(target, display_name(target).into_owned(), None));
     }
 
     result
 }
+
+/// The CLI agents `revert_all` restores: the detected one, or with
+/// `all_versions` also every one the manifest says nupatch patched (even if
+/// an update has since removed it). Without any recorded, every version
+/// present is a target instead.
+pub fn revert_cli_targets(paths: &CursorPaths, all_versions: bool) -> Vec<PathBuf> {
+    if !all_versions {
+        return paths.cli_index.iter().cloned().collect();
+    }
+    let manifest = Manifest::load();
+    let mut targets: Vec<PathBuf> = manifest.cli_agents().into_iter().map(Path::to_path_buf).collect();
+    if targets.is_empty()
+        && let Some(dir) = paths.cli_agent_dir.as_deref()
+    {
+        targets = cli_agent_indexes(dir);
+    }
+    if let Some(index) = &paths.cli_index
+        && !targets.contains(index)
+    {
+        targets.push(index.clone());
+    }
+    sort_cli_indexes(&mut targets);
+    targets
+}
+
+fn revert_file(target: &Path, filename: String, original: Option<&str>) -> RevertFileResult {
+    let (restored, error) = match restore_from_backup(target, original) {
+        Ok(restored) => (restored, None),
+        Err(e) => (false, Some(e)),
+    };
+    if restored {
+        let _ = write_skipped(target, &[]);
+    }
+    RevertFileResult { filename, restored, error, skipped: None, gone: false }
+}
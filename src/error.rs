@@ -0,0 +1,282 @@
+//! Structured errors for core and integrity operations.
+//!
+//! Step-level failures inside a patch run are reported as `StepResult`s;
+//! this enum covers the operations that fail as a whole (discovery, hashing,
+//! checksum verify/fix, backup restore). It converts into an `eyre::Report`
+//! through `std::error::Error`, and `Error::kind` maps it onto the same
+//! `FailureKind` (and exit code) a failed patch step would get.
+//!
+//! Lower-level failures are wrapped in `Context` naming the operation and
+//! path, so the `color_eyre` report (or `Error::chain` in a step message)
+//! reads e.g. "failed to write /…/product.json: Access is denied".
+
+use std::fmt;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use crate::core::FailureKind;
+
+pub type Result<T, E = Error> = std::result::Result<T, E>;
+
+#[derive(Debug)]
+pub enum Error {
+    /// The Cursor installation (or a required part of it) wasn't found.
+    InstallNotFound(&'static str),
+    /// The `--app-path` directory has no `product.json`, so it isn't a
+    /// Cursor `resources/app`.
+    AppPathInvalid(PathBuf),
+    /// Cursor was found, but its resources are packed into this `app.asar`
+    /// rather than unpacked, so there is nothing to patch in place.
+    ResourcesPacked(PathBuf),
+    /// A file the operation needs doesn't exist.
+    FileNotFound(PathBuf),
+    /// A text file (an agent, a bootstrap script) isn't UTF-8; `offset` is
+    /// the first byte that isn't.
+    NotUtf8 { path: PathBuf, offset: usize },
+    /// A path is longer than the file system accepts: past `MAX_PATH` on
+    /// Windows when long paths are disabled, or a too-long name elsewhere.
+    PathTooLong(PathBuf),
+    /// `path` is on a read-only file system, e.g. a Snap's or a running
+    /// AppImage's squashfs mount.
+    ReadOnly(PathBuf),
+    /// An anchor pattern the operation relies on isn't in the file. `near`
+    /// is a dump of where it was expected, when that's known.
+    PatternNotFound {
+        step: String,
+        pattern: String,
+        near: Option<String>,
+    },
+    /// A pattern built from discovered names failed to compile.
+    Regex(fancy_regex::Error),
+    Io(io::Error),
+    /// product.json (or another JSON input) is malformed.
+    Json(serde_json::Error),
+    /// A zip or tar archive is unsupported or malformed.
+    Archive(String),
+    /// A saved run report is unsupported or malformed.
+    Report(String),
+    /// A `.bak` file exists but can't be a copy of the original (empty).
+    BackupCorrupt(PathBuf),
+    /// `source` happened while doing `context` (operation and path).
+    Context { context: String, source: Box<Error> },
+}
+
+impl Error {
+    /// An IO error from `op` ("read", "write", ...) on `path`; a missing
+    /// file becomes `FileNotFound`, an over-long path `PathTooLong`, a
+    /// read-only file system `ReadOnly`.
+    pub fn io(op: &str, path: &Path, err: io::Error) -> Self {
+        if err.kind() == io::ErrorKind::NotFound {
+            Self::FileNotFound(path.to_path_buf())
+        } else if is_path_too_long(&err) {
+            Self::PathTooLong(path.to_path_buf()).context(format!("failed to {op} {}", path.display()))
+        } else if err.kind() == io::ErrorKind::ReadOnlyFilesystem {
+            Self::ReadOnly(path.to_path_buf()).context(format!("failed to {op} {}", path.display()))
+        } else {
+            Self::Io(err).context(format!("failed to {op} {}", path.display()))
+        }
+    }
+
+    /// `io` for a file read whole as text: like `io`, but invalid UTF-8
+    /// is `NotUtf8` with the offending offset rather than a bare
+    /// "stream did not contain valid UTF-8".
+    pub fn read_text(op: &str, path: &Path, err: io::Error) -> Self {
+        match err.get_ref().and_then(|e| e.downcast_ref::<std::string::FromUtf8Error>()) {
+            Some(e) => Self::NotUtf8 { path: path.to_path_buf(), offset: e.utf8_error().valid_up_to() },
+            None => Self::io(op, path, err),
+        }
+    }
+
+    /// Wrap this error in a description of the operation that failed.
+    pub fn context(self, context: impl Into<String>) -> Self {
+        Self::Context {
+            context: context.into(),
+            source: Box::new(self),
+        }
+    }
+
+    /// This error and its sources on one line, outermost first.
+    pub fn chain(&self) -> String {
+        let mut out = self.to_string();
+        let mut source = std::error::Error::source(self);
+        while let Some(e) = source {
+            out.push_str(": ");
+            out.push_str(&e.to_string());
+            source = e.source();
+        }
+        out
+    }
+
+    pub fn pattern_not_found(step: &str, pattern: impl Into<String>) -> Self {
+        Self::PatternNotFound {
+            step: step.to_string(),
+            pattern: pattern.into(),
+            near: None,
+        }
+    }
+
+    /// The failure category, as used for patch step results and exit codes.
+    pub fn kind(&self) -> FailureKind {
+        match self {
+            Self::InstallNotFound(_)
+            | Self::AppPathInvalid(_)
+            | Self::ResourcesPacked(_)
+            | Self::FileNotFound(_)
+            | Self::PathTooLong(_)
+            | Self::ReadOnly(_)
+            | Self::Io(_)
+            | Self::BackupCorrupt(_) => FailureKind::Io,
+            Self::PatternNotFound { .. } => FailureKind::PatternNotFound,
+            Self::Regex(_) => FailureKind::Discovery,
+            Self::Json(_) | Self::Archive(_) | Self::Report(_) | Self::NotUtf8 { .. } => FailureKind::InvalidFile,
+            Self::Context { source, .. } => source.kind(),
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::InstallNotFound(what) => write!(f, "Could not find {what}."),
+            Self::AppPathInvalid(dir) => write!(
+                f,
+                "No product.json in --app-path:\n  {}\n\
+                 Point it at Cursor's resources/app directory or an extracted AppImage.",
+                dir.display()
+            ),
+            Self::ResourcesPacked(asar) => write!(
+                f,
+                "Cursor's resources are packed into an archive:\n  {}\n\
+                 nupatch can only patch an unpacked resources/app directory.",
+                asar.display()
+            ),
+            Self::FileNotFound(path) => write!(f, "{} not found", path.display()),
+            Self::NotUtf8 { path, offset } => {
+                write!(f, "{} is not valid UTF-8 (invalid byte at offset {offset})", path.display())
+            }
+            Self::PathTooLong(path) => write!(
+                f,
+                "path is too long ({} characters){}",
+                path.as_os_str().len(),
+                if cfg!(windows) {
+                    "; enable Win32 long paths (LongPathsEnabled) or move the install somewhere shorter"
+                } else {
+                    ""
+                }
+            ),
+            Self::ReadOnly(path) if path.starts_with("/snap") => write!(
+                f,
+                "read-only Snap mount; patches can't be applied to a Snap install of Cursor"
+            ),
+            Self::ReadOnly(path) if is_appimage_mount(path) => write!(
+                f,
+                "AppImage is read-only, extract it first (--appimage-extract) and pass --app-path squashfs-root"
+            ),
+            Self::ReadOnly(_) => write!(f, "read-only file system"),
+            Self::PatternNotFound { step, pattern, .. } => write!(f, "{step}: cannot find {pattern}"),
+            Self::Regex(e) => write!(f, "invalid pattern: {e}"),
+            Self::Io(e) => write!(f, "{e}"),
+            Self::Json(e) => write!(f, "invalid JSON: {e}"),
+            Self::Archive(e) => write!(f, "invalid archive: {e}"),
+            Self::Report(e) => write!(f, "invalid report: {e}"),
+            Self::BackupCorrupt(path) => {
+                write!(f, "backup {} is empty; refusing to restore from it", path.display())
+            }
+            Self::Context { context, .. } => write!(f, "{context}"),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        // Leaf variants already print their cause; only `Context` chains.
+        match self {
+            Self::Context { source, .. } => Some(source.as_ref()),
+            _ => None,
+        }
+    }
+}
+
+/// Whether `path` is inside a running AppImage, which is mounted at
+/// `/tmp/.mount_<name>XXXXXX`.
+fn is_appimage_mount(path: &Path) -> bool {
+    path.components().any(|c| c.as_os_str().to_string_lossy().starts_with(".mount_"))
+}
+
+/// `ENAMETOOLONG`, or `ERROR_FILENAME_EXCED_RANGE` on Windows. Absolute
+/// paths past `MAX_PATH` are already passed to Windows in extended-length
+/// (`\\?\`) form by std, so this is what's left: relative paths, and
+/// tools and file systems that don't take the long form.
+fn is_path_too_long(err: &io::Error) -> bool {
+    const ERROR_FILENAME_EXCED_RANGE: i32 = 206;
+    err.kind() == io::ErrorKind::InvalidFilename
+        && (!cfg!(windows) || err.raw_os_error() == Some(ERROR_FILENAME_EXCED_RANGE))
+}
+
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Self {
+        Self::Io(e)
+    }
+}
+
+impl From<serde_json::Error> for Error {
+    fn from(e: serde_json::Error) -> Self {
+        Self::Json(e)
+    }
+}
+
+impl From<fancy_regex::Error> for Error {
+    fn from(e: fancy_regex::Error) -> Self {
+        Self::Regex(e)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::TempDir;
+    use std::fs;
+
+    /// The error under any `Context` wrappers.
+    fn root(e: &Error) -> &Error {
+        match e {
+            Error::Context { source, .. } => root(source),
+            e => e,
+        }
+    }
+
+    #[test]
+    fn invalid_filename_is_path_too_long() {
+        let err = if cfg!(windows) {
+            io::Error::from_raw_os_error(206)
+        } else {
+            io::Error::from(io::ErrorKind::InvalidFilename)
+        };
+        let e = Error::io("write", Path::new("x"), err);
+        assert!(matches!(root(&e), Error::PathTooLong(_)), "{e:?}");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn over_long_file_name_is_path_too_long() {
+        let dir = TempDir::new("long-name");
+        let path = dir.0.join("x".repeat(300));
+        let e = Error::io("write", &path, fs::write(&path, "").unwrap_err());
+        assert!(matches!(root(&e), Error::PathTooLong(_)), "{e:?}");
+    }
+
+    /// A path past `MAX_PATH`: either std's extended-length form gets it
+    /// through, or the failure is reported as `PathTooLong`.
+    #[cfg(windows)]
+    #[test]
+    fn deep_path_past_max_path() {
+        let dir = TempDir::new("deep-path");
+        let deep = (0..30).fold(dir.0.clone(), |p, i| p.join(format!("segment{i:02}")));
+        let file = deep.join("main.js");
+        assert!(file.as_os_str().len() > 260);
+        if let Err(err) = fs::create_dir_all(&deep).and_then(|()| fs::write(&file, "")) {
+            let e = Error::io("write", &file, err);
+            assert!(matches!(root(&e), Error::PathTooLong(_)), "{e:?}");
+        }
+    }
+}
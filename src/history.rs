@@ -0,0 +1,287 @@
+//! The history log: `~/.nupatch/history.jsonl` gets one JSON line for every
+//! run that changed the install (`patch`, `revert`, `fix-checksums`, `undo`),
+//! so "what did nupatch do to my install" can be answered after the fact.
+//!
+//! ```json
+//! {"time":1760000000,"command":"patch","nupatch":"1.0.0","success":true,
+//!  "files":[{"path":"/opt/Cursor/.../main.js","sha256":"9f86d0..."}],
+//!  "results":[{"component":"IDE","success":true,"steps":[...]}],
+//!  "snapshot":{"id":"1760000000-4242","files":[{"path":"...","copy":"..."}]}}
+//! ```
+//!
+//! `files` are the files the run wrote, hashed after it finished; `results`
+//! have the layout of a report's `results` (see `report`). `snapshot` lists
+//! copies of the files as they were just before the run (`copy` is null for
+//! a file that didn't exist yet), kept under `~/.nupatch/snapshots/<id>` for
+//! `undo`; an `undo` line names the run it reversed in `undoes`. The log is
+//! only ever appended to, and recording is best-effort: a log that can't be
+//! written never fails the operation it describes.
+
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use serde_json::{Value, json};
+
+use crate::core::{PatchResult, state_path};
+use crate::error::{Error, Result};
+use crate::integrity::sha256_hex;
+use crate::paths::nupatch_dir;
+use crate::report::result_json;
+
+/// Snapshots kept for `undo`; older ones are pruned as new ones are taken.
+const KEEP_SNAPSHOTS: usize = 5;
+
+/// `~/.nupatch/history.jsonl`, if there is a home directory.
+pub fn history_path() -> Option<PathBuf> {
+    nupatch_dir().map(|dir| dir.join("history.jsonl"))
+}
+
+fn snapshots_dir() -> Option<PathBuf> {
+    nupatch_dir().map(|dir| dir.join("snapshots"))
+}
+
+/// One recorded run.
+pub struct Entry {
+    pub time: Option<SystemTime>,
+    pub command: String,
+    pub success: bool,
+    /// The files the run wrote.
+    pub files: Vec<PathBuf>,
+    /// Id of the snapshot taken before the run, if one was.
+    pub snapshot_id: Option<String>,
+    /// Each snapshotted file and its copy (`None`: it didn't exist).
+    pub snapshot: Vec<(PathBuf, Option<PathBuf>)>,
+    /// For an `undo`, the snapshot id of the run it reversed.
+    pub undoes: Option<String>,
+    /// The line as recorded, for `history --json`.
+    pub raw: Value,
+}
+
+/// The parseable part of the log, oldest first.
+pub struct History {
+    pub entries: Vec<Entry>,
+    /// Lines that couldn't be parsed (e.g. truncated by a crash).
+    pub unreadable: usize,
+}
+
+/// Read the log at `path`. A missing log is an empty history, and lines
+/// that don't parse are counted and skipped rather than failing the rest.
+pub fn read(path: &Path) -> Result<History> {
+    let bytes = match fs::read(path) {
+        Ok(bytes) => bytes,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Vec::new(),
+        Err(e) => return Err(Error::io("read history", path, e)),
+    };
+    let mut history = History { entries: Vec::new(), unreadable: 0 };
+    for line in String::from_utf8_lossy(&bytes).lines().filter(|l| !l.trim().is_empty()) {
+        match serde_json::from_str(line).ok().and_then(entry_from_json) {
+            Some(entry) => history.entries.push(entry),
+            None => history.unreadable += 1,
+        }
+    }
+    Ok(history)
+}
+
+fn entry_from_json(raw: Value) -> Option<Entry> {
+    let files = raw["files"]
+        .as_array()?
+        .iter()
+        .map(|f| f["path"].as_str().map(PathBuf::from))
+        .collect::<Option<Vec<_>>>()?;
+    let snapshot = match raw["snapshot"]["files"].as_array() {
+        Some(files) => files
+            .iter()
+            .map(|f| Some((PathBuf::from(f["path"].as_str()?), f["copy"].as_str().map(PathBuf::from))))
+            .collect::<Option<Vec<_>>>()?,
+        None => Vec::new(),
+    };
+    let string = |v: &Value| v.as_str().map(str::to_string);
+    Some(Entry {
+        time: raw["time"].as_u64().map(|s| UNIX_EPOCH + Duration::from_secs(s)),
+        command: raw["command"].as_str()?.to_string(),
+        success: raw["success"].as_bool()?,
+        files,
+        snapshot_id: string(&raw["snapshot"]["id"]),
+        snapshot,
+        undoes: string(&raw["undoes"]),
+        raw,
+    })
+}
+
+impl History {
+    /// The run `undo` would reverse: the most recent one that isn't an
+    /// undo and hasn't been undone.
+    pub fn last_undoable(&self) -> Option<&Entry> {
+        let undone: Vec<&str> = self.entries.iter().filter_map(|e| e.undoes.as_deref()).collect();
+        self.entries
+            .iter()
+            .rev()
+            .find(|e| e.undoes.is_none() && !e.snapshot_id.as_deref().is_some_and(|id| undone.contains(&id)))
+    }
+}
+
+// ---------------------------------------------------------------------------
+//  Snapshots
+// ---------------------------------------------------------------------------
+
+/// Copies of files (and their `.nupatch.json` sidecars) taken just before a
+/// run changes them. Dropped without being `record`ed (nothing was written,
+/// or the run bailed out early), the copies are removed.
+pub struct Snapshot {
+    id: String,
+    dir: PathBuf,
+    files: Vec<(PathBuf, Option<PathBuf>)>,
+    /// A history line refers to it: keep it on drop.
+    recorded: bool,
+}
+
+impl Snapshot {
+    /// Snapshot `files`. Best-effort like the rest of the history: `None`
+    /// if there's nowhere to put it or a copy fails.
+    pub fn take(files: &[&Path]) -> Option<Self> {
+        let secs = SystemTime::now().duration_since(UNIX_EPOCH).ok()?.as_secs();
+        let id = format!("{secs}-{}", std::process::id());
+        let dir = snapshots_dir()?.join(&id);
+        fs::create_dir_all(&dir).ok()?;
+        let mut snapshot = Self { id, dir, files: Vec::new(), recorded: false };
+        let targets = files.iter().flat_map(|f| [f.to_path_buf(), state_path(f)]);
+        for (i, path) in targets.enumerate() {
+            let copy = match path.file_name() {
+                Some(name) if path.is_file() => {
+                    let copy = snapshot.dir.join(format!("{i}-{}", name.to_string_lossy()));
+                    if fs::copy(&path, &copy).is_err() {
+                        return None;
+                    }
+                    Some(copy)
+                }
+                _ => None,
+            };
+            snapshot.files.push((path, copy));
+        }
+        Some(snapshot)
+    }
+
+    fn to_json(&self) -> Value {
+        let files: Vec<Value> = self
+            .files
+            .iter()
+            .map(|(path, copy)| json!({ "path": path, "copy": copy }))
+            .collect();
+        json!({ "id": self.id, "files": files })
+    }
+}
+
+impl Drop for Snapshot {
+    fn drop(&mut self) {
+        if !self.recorded {
+            let _ = fs::remove_dir_all(&self.dir);
+        }
+    }
+}
+
+/// Put back the files `entry`'s snapshot holds, removing the ones that
+/// didn't exist before the run. Returns each file it touched with its
+/// outcome, and whether it was removed.
+pub fn restore_snapshot(entry: &Entry) -> Vec<(&Path, bool, Result<()>)> {
+    entry
+        .snapshot
+        .iter()
+        .filter(|(path, copy)| copy.is_some() || path.exists())
+        .map(|(path, copy)| {
+            let outcome = match copy {
+                Some(copy) => fs::copy(copy, path).map(|_| ()).map_err(|e| Error::io("restore", path, e)),
+                None => match fs::remove_file(path) {
+                    Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(Error::io("remove", path, e)),
+                    _ => Ok(()),
+                },
+            };
+            (path.as_path(), copy.is_none(), outcome)
+        })
+        .collect()
+}
+
+/// Remove the snapshot of an undone run.
+pub fn discard_snapshot(id: &str) {
+    if let Some(dir) = snapshots_dir() {
+        let _ = fs::remove_dir_all(dir.join(id));
+    }
+}
+
+/// Keep only the newest `KEEP_SNAPSHOTS` snapshots. Ids start with the
+/// creation time, so name order is age order.
+fn prune_snapshots() {
+    let Some(dir) = snapshots_dir() else { return };
+    let Ok(read) = fs::read_dir(&dir) else { return };
+    let mut ids: Vec<PathBuf> = read.flatten().map(|e| e.path()).collect();
+    ids.sort();
+    let excess = ids.len().saturating_sub(KEEP_SNAPSHOTS);
+    for old in &ids[..excess] {
+        let _ = fs::remove_dir_all(old);
+    }
+}
+
+// ---------------------------------------------------------------------------
+//  Recording
+// ---------------------------------------------------------------------------
+
+/// Append a line for a `command` run that wrote `files`, with the snapshot
+/// taken before it. The snapshot is kept only if the line was written.
+pub fn record(
+    command: &str,
+    success: bool,
+    files: &[&Path],
+    results: &[(&str, &PatchResult)],
+    mut snapshot: Option<Snapshot>,
+) {
+    let mut entry = entry_json(command, success, files, results);
+    if let Some(snapshot) = &snapshot {
+        entry["snapshot"] = snapshot.to_json();
+    }
+    if let Some(path) = history_path()
+        && append(&path, &entry).is_ok()
+        && let Some(snapshot) = &mut snapshot
+    {
+        snapshot.recorded = true;
+    }
+    drop(snapshot);
+    prune_snapshots();
+}
+
+/// Append the line for an `undo` of the run with snapshot `undone`.
+pub fn record_undo(undone: &str, success: bool, files: &[&Path], results: &[(&str, &PatchResult)]) {
+    let mut entry = entry_json("undo", success, files, results);
+    entry["undoes"] = json!(undone);
+    if let Some(path) = history_path() {
+        let _ = append(&path, &entry);
+    }
+}
+
+fn entry_json(command: &str, success: bool, files: &[&Path], results: &[(&str, &PatchResult)]) -> Value {
+    let files: Vec<Value> = files
+        .iter()
+        .map(|f| json!({ "path": f, "sha256": sha256_hex(f).ok() }))
+        .collect();
+    let results: Vec<Value> = results.iter().map(|(label, r)| result_json(label, r)).collect();
+    json!({
+        "time": SystemTime::now().duration_since(UNIX_EPOCH).ok().map(|d| d.as_secs()),
+        "command": command,
+        "nupatch": env!("CARGO_PKG_VERSION"),
+        "success": success,
+        "files": files,
+        "results": results,
+    })
+}
+
+fn append(path: &Path, entry: &Value) -> std::io::Result<()> {
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    let mut line = serde_json::to_string(entry)?;
+    line.push('\n');
+    // One write per line, so an interrupted run can at worst leave a
+    // truncated last line behind.
+    let mut file = OpenOptions::new().create(true).append(true).open(path)?;
+    file.write_all(line.as_bytes())
+}
@@ -3,31 +3,145 @@
 use base64::Engine;
 use base64::engine::general_purpose::STANDARD;
 use serde_json::Value;
+use std::collections::HashMap;
 use std::fs;
 use std::path::{Path, PathBuf};
 
 use sha2::{Digest, Sha256};
 
-use color_eyre::eyre::{self, WrapErr};
-
-use crate::core::{PatchResult, StepResult};
-use crate::util::lazy_re;
+use crate::core::{FailureKind, PatchResult, StepResult};
+use crate::error::{Error, Result};
+use crate::paths::nupatch_dir;
+use crate::util::{re, read_text, retried_note, write_retrying};
 
 // ---------------------------------------------------------------------------
 //  Helpers
 // ---------------------------------------------------------------------------
 
-/// Convert 2-space indentation to tab indentation, only in leading whitespace.
-/// Avoids corrupting string values that might contain double spaces.
-fn tab_indent(json: &str) -> String {
-    json.lines()
-        .map(|line| {
-            let trimmed = line.trim_start_matches("  ");
-            let depth = (line.len() - trimmed.len()) / 2;
-            format!("{}{}", "\t".repeat(depth), trimmed)
-        })
-        .collect::<Vec<_>>()
-        .join("\n")
+/// Strip `//` and `/* */` comments and trailing commas so JSONC-style
+/// product.json files (hand-edited or distro-patched) parse with serde_json.
+///
+/// Only used for parsing; writes go through `apply_checksum_updates` on the
+/// original text, so comments survive a rewrite.
+fn strip_jsonc(text: &str) -> String {
+    let bytes = text.as_bytes();
+    let mut out = String::with_capacity(text.len());
+    let mut i = 0;
+    let mut in_string = false;
+
+    while i < bytes.len() {
+        let c = bytes[i];
+        if in_string {
+            if c == b'\\' && i + 1 < bytes.len() {
+                let esc_len = text[i + 1..].chars().next().map_or(1, char::len_utf8);
+                out.push_str(&text[i..i + 1 + esc_len]);
+                i += 1 + esc_len;
+                continue;
+            }
+            if c == b'"' {
+                in_string = false;
+            }
+        } else if c == b'"' {
+            in_string = true;
+        } else if text[i..].starts_with("//") {
+            i = text[i..].find('\n').map_or(bytes.len(), |n| i + n);
+            continue;
+        } else if text[i..].starts_with("/*") {
+            i = text[i + 2..].find("*/").map_or(bytes.len(), |n| i + 2 + n + 2);
+            continue;
+        } else if c == b',' {
+            // Drop the comma if the next significant character closes a
+            // container (a trailing comma).
+            if matches!(next_significant(text, i + 1), Some(b'}' | b']')) {
+                i += 1;
+                continue;
+            }
+        }
+        // Push the whole UTF-8 character starting at `i`.
+        let ch_len = text[i..].chars().next().map_or(1, char::len_utf8);
+        out.push_str(&text[i..i + ch_len]);
+        i += ch_len;
+    }
+
+    out
+}
+
+/// Parse product.json, falling back to JSONC stripping only when strict
+/// parsing fails.
+fn parse_product(text: &str) -> serde_json::Result<Value> {
+    serde_json::from_str(text).or_else(|e| serde_json::from_str(&strip_jsonc(text)).map_err(|_| e))
+}
+
+/// First byte at or after `from` that is neither whitespace nor part of a
+/// comment.
+fn next_significant(text: &str, from: usize) -> Option<u8> {
+    text.as_bytes().get(skip_insignificant(text, from)?).copied()
+}
+
+/// Offset of that byte (or the end of `text`). `None` inside an
+/// unterminated comment.
+fn skip_insignificant(text: &str, mut from: usize) -> Option<usize> {
+    loop {
+        let rest = text.get(from..)?.trim_start();
+        from = text.len() - rest.len();
+        if rest.starts_with("//") {
+            from += rest.find('\n')?;
+        } else if let Some(body) = rest.strip_prefix("/*") {
+            from += body.find("*/")? + 4;
+        } else {
+            return Some(from);
+        }
+    }
+}
+
+/// A checksum value to rewrite in product.json: `(rel_path, old, new)`.
+type ChecksumUpdate = (String, String, String);
+
+/// Locate the byte range of the quoted `"<old>"` value belonging to the
+/// `"<rel_path>"` key inside the checksums section.
+///
+/// Anchoring on the key (rather than searching for the bare hash) keeps the
+/// edit correct when the same hash value appears elsewhere in the file.
+/// Whitespace and JSONC comments may sit around the `:`, as `strip_jsonc`
+/// allows.
+fn checksum_value_span(text: &str, rel_path: &str, old: &str) -> Option<std::ops::Range<usize>> {
+    let section = text.find(r#""checksums""#)?;
+    let key = serde_json::to_string(rel_path).ok()?;
+    let value = serde_json::to_string(old).ok()?;
+
+    let mut from = section;
+    while let Some(offset) = text[from..].find(&key) {
+        let after_key = from + offset + key.len();
+        if let Some(colon) = skip_insignificant(text, after_key)
+            && text[colon..].starts_with(':')
+            && let Some(start) = skip_insignificant(text, colon + 1)
+            && text[start..].starts_with(&value)
+        {
+            return Some(start..start + value.len());
+        }
+        from = after_key;
+    }
+    None
+}
+
+/// Apply checksum updates to the original product.json text, replacing only
+/// the changed values. Everything else (key order, indentation, line
+/// endings) is left byte-for-byte intact.
+fn apply_checksum_updates(text: &str, updates: &[ChecksumUpdate]) -> Result<String> {
+    let mut out = text.to_string();
+    for (rel_path, old, new) in updates {
+        let span = checksum_value_span(&out, rel_path, old)
+            .ok_or_else(|| Error::pattern_not_found("Product checksums", format!("checksum entry for {rel_path}")))?;
+        out.replace_range(span, &serde_json::to_string(new)?);
+    }
+    Ok(out)
+}
+
+/// Write checksum updates into product.json on disk. Returns the number of
+/// write retries needed.
+fn write_product_json(product_json: &Path, text: &str, updates: &[ChecksumUpdate]) -> Result<u32> {
+    let out = apply_checksum_updates(text, updates)?;
+    write_retrying(product_json, out).map_err(|e| Error::io("write", product_json, e))
 }
 
 // ---------------------------------------------------------------------------
@@ -35,45 +149,155 @@ fn tab_indent(json: &str) -> String {
 // ---------------------------------------------------------------------------
 
 /// SHA-256 hex digest of a file.
-pub fn sha256_hex(path: &Path) -> eyre::Result<String> {
-    let data = fs::read(path)
-        .wrap_err_with(|| format!("failed to read {}", path.display()))?;
+pub fn sha256_hex(path: &Path) -> Result<String> {
+    let data = fs::read(path).map_err(|e| Error::io("read", path, e))?;
     let hash = Sha256::digest(&data);
     Ok(format!("{:x}", hash))
 }
 
 /// SHA-256 base64 digest with trailing `=` stripped.
-pub fn sha256_base64_stripped(path: &Path) -> eyre::Result<String> {
-    let data = fs::read(path)
-        .wrap_err_with(|| format!("failed to read {}", path.display()))?;
+pub fn sha256_base64_stripped(path: &Path) -> Result<String> {
+    let _span = tracing::info_span!("hash", file = %path.file_name().unwrap_or_default().to_string_lossy()).entered();
+    let data = fs::read(path).map_err(|e| Error::io("read", path, e))?;
     let hash = Sha256::digest(&data);
     Ok(STANDARD.encode(hash).trim_end_matches('=').to_string())
 }
 
+/// Whether the platform's file system usually ignores case (NTFS, APFS), so
+/// checksum keys that differ only in case resolve to the same file.
+const CASE_INSENSITIVE_FS: bool = cfg!(any(windows, target_os = "macos"));
+
+/// Whether checksum keys `a` and `b` name the same file on this platform.
+fn same_key(a: &str, b: &str) -> bool {
+    let (a, b) = (a.replace('\\', "/"), b.replace('\\', "/"));
+    if CASE_INSENSITIVE_FS { a.eq_ignore_ascii_case(&b) } else { a == b }
+}
+
+/// Whether a checksum key uses `\` separators instead of `/`, as some
+/// third-party tools write them.
+fn has_backslashes(rel_path: &str) -> bool {
+    rel_path.contains('\\')
+}
+
+/// Pairs of checksum keys that differ only in case. On a case-insensitive
+/// file system both resolve to one file, so both get its hash; elsewhere
+/// they are different files and this is empty.
+pub fn case_clashes<'a>(keys: impl IntoIterator<Item = &'a String>) -> Vec<(String, String)> {
+    if !CASE_INSENSITIVE_FS {
+        return Vec::new();
+    }
+    let mut seen: HashMap<String, &String> = HashMap::new();
+    let mut clashes = Vec::new();
+    for key in keys {
+        if let Some(first) = seen.get(&key.to_ascii_lowercase()) {
+            clashes.push(((*first).clone(), key.clone()));
+        } else {
+            seen.insert(key.to_ascii_lowercase(), key);
+        }
+    }
+    clashes
+}
+
+/// Memoizes `sha256_base64_stripped` by canonical path, so a file referenced
+/// under several checksum keys (duplicates, symlinks) is only hashed once.
+#[derive(Default)]
+struct HashCache {
+    hashes: HashMap<PathBuf, String>,
+}
+
+impl HashCache {
+    fn sha256_base64_stripped(&mut self, path: &Path) -> Result<String> {
+        let key = fs::canonicalize(path).unwrap_or_else(|_| path.to_path_buf());
+        // Keys differing only in case name the same file here.
+        let key = if CASE_INSENSITIVE_FS {
+            PathBuf::from(key.to_string_lossy().to_lowercase())
+        } else {
+            key
+        };
+        if let Some(hash) = self.hashes.get(&key) {
+            return Ok(hash.clone());
+        }
+        let hash = sha256_base64_stripped(path)?;
+        self.hashes.insert(key, hash.clone());
+        Ok(hash)
+    }
+}
+
 // ---------------------------------------------------------------------------
 //  Backup / restore
 // ---------------------------------------------------------------------------
 
 /// Create a `.bak` copy if one doesn't already exist.
-pub fn backup(filepath: &Path) -> Result<PathBuf, std::io::Error> {
+pub fn backup(filepath: &Path) -> Result<PathBuf> {
+    let _span = tracing::info_span!("backup").entered();
     let bak = bak_path(filepath);
     if !bak.exists() {
-        fs::copy(filepath, &bak)?;
+        fs::copy(filepath, &bak).map_err(|e| {
+            // The `.bak` sits next to the file, so "not found" is the file.
+            let path = if e.kind() == std::io::ErrorKind::NotFound { filepath } else { &bak };
+            Error::io("create backup", path, e)
+        })?;
     }
     Ok(bak)
 }
 
-/// Restore a file from its `.bak` copy. Returns true on success.
-pub fn restore_from_backup(filepath: &Path) -> Result<bool, std::io::Error> {
+/// Restore a file from its `.bak` copy. Returns true on success, false if
+/// there is no backup. An empty backup is refused rather than copied over
+/// the live file.
+///
+/// `original` is the hash of the original the file was patched from, when
+/// known (see `manifest`). A `.bak` that is missing or no longer that
+/// original -- left over from another version -- is then passed over for the
+/// copy in the originals store, if there is one.
+pub fn restore_from_backup(filepath: &Path, original: Option<&str>) -> Result<bool> {
     let bak = bak_path(filepath);
+    if let Some(stored) = original.and_then(stored_original)
+        && sha256_hex(&bak).ok().as_deref() != original
+    {
+        fs::copy(&stored, filepath).map_err(|e| Error::io("restore", filepath, e))?;
+        return Ok(true);
+    }
     if bak.exists() {
-        fs::copy(&bak, filepath)?;
+        if fs::metadata(&bak).map_err(|e| Error::io("read", &bak, e))?.len() == 0 {
+            return Err(Error::BackupCorrupt(bak));
+        }
+        fs::copy(&bak, filepath).map_err(|e| Error::io("restore", filepath, e))?;
         Ok(true)
     } else {
         Ok(false)
     }
 }
 
+/// `~/.nupatch/originals`: pristine originals, each stored once under its
+/// SHA-256, so a file's original outlives a `.bak` an update made stale.
+fn originals_dir() -> Option<PathBuf> {
+    nupatch_dir().map(|dir| dir.join("originals"))
+}
+
+/// Keep a copy of the pristine original `source` in the originals store,
+/// unless the same content is already there. Returns its hash. Best-effort:
+/// `None` if it can't be stored.
+pub fn store_original(source: &Path) -> Option<String> {
+    let hash = sha256_hex(source).ok()?;
+    if stored_original(&hash).is_some() {
+        return Some(hash);
+    }
+    let dir = originals_dir()?;
+    fs::create_dir_all(&dir).ok()?;
+    // Copy under a temporary name so an interrupted copy is never taken for
+    // the original.
+    let partial = dir.join(format!("{hash}.partial"));
+    fs::copy(source, &partial).ok()?;
+    fs::rename(&partial, dir.join(&hash)).ok()?;
+    Some(hash)
+}
+
+/// The stored original with SHA-256 `hash`, if the store has it intact.
+pub fn stored_original(hash: &str) -> Option<PathBuf> {
+    let path = originals_dir()?.join(hash);
+    (sha256_hex(&path).ok()? == hash).then_some(path)
+}
+
 /// Get the `.bak` path for a file.
 /// Returns the path unchanged if `file_name()` is `None` (e.g. root path).
 pub fn bak_path(filepath: &Path) -> PathBuf {
@@ -91,165 +315,364 @@ pub fn bak_path(filepath: &Path) -> PathBuf {
 //  Update integrity hashes
 // ---------------------------------------------------------------------------
 
+/// A bootstrap script that embeds the SHA-256 of another file (so far the
+/// patched IDE agent, `main.js`), which must be kept in sync after patching.
+struct EmbeddedHash {
+    /// Step name used in results.
+    name: &'static str,
+    /// Script path components, relative to the Cursor app directory.
+    script: &'static [&'static str],
+    /// Path components of the file whose hash `script` embeds, relative to
+    /// the Cursor app directory.
+    target: &'static [&'static str],
+    /// Structural variants tried in order. Each is a regex with three
+    /// groups (prefix, 64-hex hash, suffix); `{ext}` and `{file}` are
+    /// replaced with the escaped extension id and agent file name.
+    patterns: &'static [&'static str],
+    /// Whether a missing script or pattern fails the chain. Optional entries
+    /// are silently skipped on installs that don't have them.
+    required: bool,
+}
+
+impl EmbeddedHash {
+    fn script_path(&self, cursor_app: &Path) -> PathBuf {
+        self.script.iter().fold(cursor_app.to_path_buf(), |p, c| p.join(c))
+    }
+
+    fn target_path(&self, cursor_app: &Path) -> PathBuf {
+        self.target.iter().fold(cursor_app.to_path_buf(), |p, c| p.join(c))
+    }
+
+    /// Expand the pattern variants for a given agent file. The extension id
+    /// is taken from `extensions/<id>/dist/<file>`, falling back to
+    /// `cursor-agent-exec` when the layout is unexpected.
+    fn expand_patterns(&self, target: &Path) -> Vec<String> {
+        let ext = target
+            .parent()
+            .and_then(Path::parent)
+            .and_then(Path::file_name)
+            .map_or_else(|| "cursor-agent-exec".into(), |n| n.to_string_lossy());
+        let file = target
+            .file_name()
+            .map_or_else(|| "main.js".into(), |n| n.to_string_lossy());
+        self.patterns
+            .iter()
+            .map(|p| {
+                p.replace("{ext}", &fancy_regex::escape(&ext))
+                    .replace("{file}", &fancy_regex::escape(&file))
+            })
+            .collect()
+    }
+}
+
+/// Integrity pointers to keep consistent. Append an entry when Cursor starts
+/// embedding the agent hash in another script.
+const EMBEDDED_HASHES: &[EmbeddedHash] = &[EmbeddedHash {
+    name: "EHP hash",
+    script: &["out", "vs", "workbench", "api", "node", "extensionHostProcess.js"],
+    target: &["extensions", "cursor-agent-exec", "dist", "main.js"],
+    patterns: &[
+        // {ext}:{...dist:{..."main.js":"<hash>"}}
+        r#"({ext}[^}]*dist:\{[^}]*"{file}":")([a-f0-9]{64})(")"#,
+        // JSON-style quoted keys with optional whitespace
+        r#"("{ext}"\s*:\s*\{[^}]*"dist"\s*:\s*\{[^}]*"{file}"\s*:\s*")([a-f0-9]{64})(")"#,
+        // Flat path key: "{ext}/dist/main.js":"<hash>"
+        r#"("{ext}/dist/{file}"\s*:\s*")([a-f0-9]{64})(")"#,
+    ],
+    required: true,
+}];
+
+/// Replace the hash of `target` embedded in `script` with `new_hash`.
+///
+/// Works from the script's `.bak` when one exists, so re-running after a
+/// previous patch still finds the original hash. Returns `None` when an
+/// optional entry doesn't apply to this install.
+fn update_embedded_hash(
+    entry: &EmbeddedHash,
+    script: &Path,
+    target: &Path,
+    new_hash: &str,
+    dry_run: bool,
+) -> Option<StepResult> {
+    let name = entry.name;
+    let script_name = script.file_name().map_or_else(
+        || script.to_string_lossy(),
+        |n| n.to_string_lossy(),
+    );
+
+    if !script.is_file() {
+        return entry
+            .required
+            .then(|| StepResult::fail(name, format!("Missing {script_name}")).with_kind(FailureKind::Io));
+    }
+
+    let bak = bak_path(script);
+    let source = if bak.exists() { bak.as_path() } else { script };
+    let mut code = match read_text(source) {
+        Ok(c) => c,
+        Err(e) => {
+            let e = Error::read_text("read", source, e);
+            return Some(StepResult::fail(name, e.chain()).with_kind(e.kind()));
+        }
+    };
+
+    let patterns = entry.expand_patterns(target);
+    let mut matched: Option<(usize, std::ops::Range<usize>)> = None;
+    for (i, pattern) in patterns.iter().enumerate() {
+        let hash_re = match re(pattern) {
+            Ok(r) => r,
+            Err(e) => {
+                return Some(
+                    StepResult::fail(name, format!("Invalid hash pattern: {e}"))
+                        .with_kind(FailureKind::PatternNotFound),
+                );
+            }
+        };
+        if let Some(caps) = hash_re.captures(&code).ok().flatten() {
+            matched = Some((i, caps.get(2).unwrap().range()));
+            break;
+        }
+    }
+
+    let (mut message, detail) = if let Some((i, span)) = matched {
+        code.replace_range(span, new_hash);
+        (
+            format!("Replaced hash in {script_name}"),
+            format!("Matched variant {} of {}: {}", i + 1, patterns.len(), patterns[i]),
+        )
+    } else if !entry.required {
+        return None;
+    } else {
+        // Fallback: compute old hash from the target's backup
+        let target_bak = bak_path(target);
+        if !target_bak.exists() {
+            return Some(
+                StepResult::fail(name, "Cannot find hash map pattern or backup file")
+                    .with_kind(FailureKind::PatternNotFound),
+            );
+        }
+        let old_hash = match sha256_hex(&target_bak) {
+            Ok(h) => h,
+            Err(e) => {
+                return Some(StepResult::fail(name, e.chain()).with_kind(e.kind()));
+            }
+        };
+        let count = code.matches(&old_hash).count();
+        if count != 1 {
+            return Some(
+                StepResult::fail(name, format!("Old hash found {count} time(s) (expected 1)"))
+                    .with_kind(FailureKind::PatternNotFound),
+            );
+        }
+        code = code.replacen(&old_hash, new_hash, 1);
+        (
+            "Replaced hash via backup comparison".to_string(),
+            format!("No structural variant matched; tried:\n{}", patterns.join("\n")),
+        )
+    };
+
+    if !dry_run {
+        if let Err(e) = backup(script) {
+            return Some(StepResult::fail(name, e.chain()).with_kind(e.kind()));
+        }
+        match write_retrying(script, &code) {
+            Ok(retries) => message.push_str(&retried_note(retries)),
+            Err(e) => {
+                let e = Error::io("write", script, e);
+                return Some(StepResult::fail(name, e.chain()).with_kind(e.kind()));
+            }
+        }
+    }
+
+    Some(StepResult::ok(name, message).with_detail(detail))
+}
+
+/// The file a product.json checksum key (relative to `<app>/out`,
+/// `/`-separated on every OS) refers to, the inverse of
+/// `affected_rel_paths`. Joined component by component so the result uses
+/// the platform separator: an extended-length (`\\?\`) `cursor_app` takes
+/// separators literally, so the key's `/` must not end up in the path.
+/// Empty and `.` segments (`vs//a.js`, `./vs/a.js`) are dropped, and `\`
+/// is accepted as a separator too (see `has_backslashes`). `None` for a
+/// key with a `..` segment, which could point outside `out/`; callers
+/// treat it like a missing file.
+fn checksum_file(cursor_app: &Path, rel_path: &str) -> Option<PathBuf> {
+    let mut path = cursor_app.join("out");
+    for component in rel_path.split(['/', '\\']).filter(|c| !c.is_empty() && *c != ".") {
+        if component == ".." {
+            return None;
+        }
+        path.push(component);
+    }
+    Some(path)
+}
+
+/// product.json checksum keys (relative to `<app>/out`, `/`-separated) for
+/// the files nupatch itself modifies. Files outside `out/` have no entry.
+fn affected_rel_paths(cursor_app: &Path, modified: &[&Path]) -> Vec<String> {
+    let out_dir = cursor_app.join("out");
+    modified
+        .iter()
+        .filter_map(|p| p.strip_prefix(&out_dir).ok())
+        .map(|rel| {
+            rel.components()
+                .map(|c| c.as_os_str().to_string_lossy())
+                .collect::<Vec<_>>()
+                .join("/")
+        })
+        .collect()
+}
+
+/// Plain-language summary of `update_integrity` for `patch --explain`.
+pub const INTEGRITY_EXPLANATION: &str = "Records the patched main.js hash in \
+    extensionHostProcess.js and refreshes the product.json checksums, so Cursor doesn't \
+    flag the edited agent as a corrupt install.";
+
 /// Update the SHA-256 integrity chain after patching the IDE agent.
 ///
-/// Returns a `PatchResult` directly (no `eyre::Result` wrapper) so callers
+/// Only the product.json entries for files nupatch modified are recomputed
+/// unless `full_checksums` is set, in which case every entry is rehashed.
+///
+/// Returns a `PatchResult` directly (no `Result` wrapper) so callers
 /// handle a single failure channel, matching the pattern used by the core
 /// patch functions.
 pub fn update_integrity(
     ide_main: &Path,
-    ehp: Option<&Path>,
     product_json: Option<&Path>,
     cursor_app: Option<&Path>,
     dry_run: bool,
+    full_checksums: bool,
 ) -> PatchResult {
+    let _span = tracing::info_span!("integrity").entered();
     let mut steps: Vec<StepResult> = Vec::new();
 
     let fail = |steps: Vec<StepResult>| PatchResult { success: false, steps };
 
-    let (Some(ehp), Some(product_json), Some(cursor_app)) = (ehp, product_json, cursor_app)
-    else {
-        return fail(vec![StepResult::fail("Integrity", "Missing EHP / product.json / cursor app path")]);
+    let (Some(product_json), Some(cursor_app)) = (product_json, cursor_app) else {
+        return fail(vec![
+            StepResult::fail("Integrity", "Missing product.json / cursor app path")
+                .with_kind(FailureKind::Io),
+        ]);
     };
 
     // Step 1: compute new hash of patched main.js
     let new_main_hash = match sha256_hex(ide_main) {
         Ok(h) => h,
         Err(e) => {
-            return fail(vec![StepResult::fail("Compute hash", format!("Failed to hash main.js: {e}"))]);
+            return fail(vec![StepResult::fail("Compute hash", e.chain()).with_kind(e.kind())]);
         }
     };
     steps.push(StepResult::ok("Compute hash", format!("main.js SHA-256: {}...", &new_main_hash[..16])));
 
-    // Step 2: update hash in extensionHostProcess.js
-    if !dry_run {
-        if let Err(e) = backup(ehp) {
-            return fail(vec![StepResult::fail("EHP backup", format!("Failed to backup EHP: {e}"))]);
-        }
-        if let Err(e) = restore_from_backup(ehp) {
-            return fail(vec![StepResult::fail("EHP restore", format!("Failed to restore EHP: {e}"))]);
-        }
-    }
-
-    let mut ehp_code = match fs::read_to_string(ehp) {
-        Ok(c) => c,
-        Err(e) => {
-            return fail(vec![StepResult::fail("EHP read", format!("Failed to read EHP: {e}"))]);
-        }
-    };
-
-    let hash_re = lazy_re!(
-        r#"(cursor-agent-exec[^}]*dist:\{[^}]*"main\.js":")([a-f0-9]{64})(")"#
-    );
-
-    if let Some(caps) = hash_re.captures(&ehp_code).ok().flatten() {
-        let old_hash = caps.get(2).unwrap().as_str();
-        ehp_code = ehp_code.replacen(old_hash, &new_main_hash, 1);
-        steps.push(StepResult::ok("EHP hash", "Replaced hash in extensionHostProcess.js"));
-    } else {
-        // Fallback: compute old hash from backup
-        let bak = bak_path(ide_main);
-        if bak.exists() {
-            let old_hash = match sha256_hex(&bak) {
-                Ok(h) => h,
+    // Step 2: update embedded hashes in bootstrap scripts, each for its own
+    // target (the patched main.js is already hashed)
+    let mut target_hashes: Vec<(PathBuf, String)> = vec![(ide_main.to_path_buf(), new_main_hash)];
+    for entry in EMBEDDED_HASHES {
+        let script = entry.script_path(cursor_app);
+        let target = entry.target_path(cursor_app);
+        let hash = match target_hashes.iter().find(|(p, _)| *p == target) {
+            Some((_, h)) => h.clone(),
+            None if !entry.required && !target.is_file() => continue,
+            None => match sha256_hex(&target) {
+                Ok(h) => {
+                    target_hashes.push((target.clone(), h.clone()));
+                    h
+                }
                 Err(e) => {
-                    steps.push(StepResult::fail("EHP hash", format!("Failed to hash backup: {e}")));
+                    steps.push(StepResult::fail(entry.name, e.chain()).with_kind(e.kind()));
                     return fail(steps);
                 }
-            };
-            let count = ehp_code.matches(&old_hash).count();
-            if count == 1 {
-                ehp_code = ehp_code.replacen(&old_hash, &new_main_hash, 1);
-                steps.push(StepResult::ok("EHP hash", "Replaced hash via backup comparison"));
-            } else {
-                steps.push(StepResult::fail("EHP hash", format!("Old hash found {count} time(s) (expected 1)")));
+            },
+        };
+        match update_embedded_hash(entry, &script, &target, &hash, dry_run) {
+            Some(step) if !step.ok => {
+                steps.push(step);
                 return fail(steps);
             }
-        } else {
-            steps.push(StepResult::fail("EHP hash", "Cannot find hash map pattern or backup file"));
-            return fail(steps);
+            Some(step) => steps.push(step),
+            None => {}
         }
     }
 
+    // Step 3: update product.json checksums
     if !dry_run
-        && let Err(e) = fs::write(ehp, &ehp_code)
+        && let Err(e) = backup(product_json)
     {
-        steps.push(StepResult::fail("EHP write", format!("Failed to write EHP: {e}")));
+        steps.push(StepResult::fail("Product backup", e.chain()).with_kind(e.kind()));
         return fail(steps);
     }
 
-    // Step 3: update product.json checksums
-    if !dry_run {
-        if let Err(e) = backup(product_json) {
-            steps.push(StepResult::fail("Product backup", format!("Failed to backup product.json: {e}")));
-            return fail(steps);
-        }
-    }
-
     let product_text = match fs::read_to_string(product_json) {
         Ok(t) => t,
         Err(e) => {
-            steps.push(StepResult::fail("Product checksums", format!("Failed to read product.json: {e}")));
+            let e = Error::io("read", product_json, e);
+            steps.push(StepResult::fail("Product checksums", e.chain()).with_kind(e.kind()));
             return fail(steps);
         }
     };
-    let mut product: Value = match serde_json::from_str(&product_text) {
+    let product: Value = match parse_product(&product_text) {
         Ok(v) => v,
         Err(e) => {
-            steps.push(StepResult::fail("Product checksums", format!("Failed to parse product.json: {e}")));
+            let e = Error::from(e).context(format!("failed to parse {}", product_json.display()));
+            steps.push(StepResult::fail("Product checksums", e.chain()).with_kind(e.kind()));
             return fail(steps);
         }
     };
 
-    let checksums = match product.get_mut("checksums").and_then(|v| v.as_object_mut()) {
+    let checksums = match product.get("checksums").and_then(|v| v.as_object()) {
         Some(c) => c,
         None => {
-            steps.push(StepResult::fail("Product checksums", "No checksums section in product.json"));
+            steps.push(
+                StepResult::fail("Product checksums", "No checksums section in product.json")
+                    .with_kind(FailureKind::InvalidFile),
+            );
             return fail(steps);
         }
     };
 
-    let mut changed = 0u32;
-    let entries: Vec<(String, String)> = checksums
-        .iter()
-        .map(|(k, v)| (k.clone(), v.as_str().unwrap_or("").to_string()))
-        .collect();
+    let mut updates: Vec<ChecksumUpdate> = Vec::new();
+    let mut cache = HashCache::default();
+    let scripts: Vec<PathBuf> = EMBEDDED_HASHES.iter().map(|e| e.script_path(cursor_app)).collect();
+    let mut modified: Vec<&Path> = vec![ide_main];
+    modified.extend(scripts.iter().map(PathBuf::as_path));
+    let affected = affected_rel_paths(cursor_app, &modified);
 
-    for (rel_path, old_hash) in &entries {
-        let full_path = cursor_app.join("out").join(rel_path);
-        if !full_path.is_file() {
+    for (rel_path, old_val) in checksums {
+        if !full_checksums && !affected.iter().any(|a| same_key(a, rel_path)) {
             continue;
         }
-        let new_hash = match sha256_base64_stripped(&full_path) {
+        let old_hash = old_val.as_str().unwrap_or("");
+        let Some(full_path) = checksum_file(cursor_app, rel_path).filter(|p| p.is_file()) else {
+            continue;
+        };
+        let new_hash = match cache.sha256_base64_stripped(&full_path) {
             Ok(h) => h,
             Err(e) => {
-                steps.push(StepResult::fail("Product checksums", format!("Failed to hash {rel_path}: {e}")));
+                steps.push(StepResult::fail("Product checksums", e.chain()).with_kind(e.kind()));
                 return fail(steps);
             }
         };
-        if old_hash != &new_hash {
-            checksums.insert(rel_path.clone(), Value::String(new_hash));
-            changed += 1;
+        if old_hash != new_hash {
+            updates.push((rel_path.clone(), old_hash.to_string(), new_hash));
         }
     }
+    let changed = updates.len();
 
+    let mut retries = 0;
     if changed > 0 && !dry_run {
-        let out = match serde_json::to_string_pretty(&product) {
-            Ok(s) => s,
+        match write_product_json(product_json, &product_text, &updates) {
+            Ok(n) => retries = n,
             Err(e) => {
-                steps.push(StepResult::fail("Product checksums", format!("Failed to serialize product.json: {e}")));
+                steps.push(StepResult::fail("Product checksums", e.chain()).with_kind(e.kind()));
                 return fail(steps);
             }
-        };
-        // Match original tab indentation
-        let out = tab_indent(&out);
-        if let Err(e) = fs::write(product_json, out) {
-            steps.push(StepResult::fail("Product checksums", format!("Failed to write product.json: {e}")));
-            return fail(steps);
         }
     }
 
-    steps.push(StepResult::ok("Product checksums", format!("Updated {changed} checksum(s) in product.json")));
+    steps.push(StepResult::ok(
+        "Product checksums",
+        format!("Updated {changed} checksum(s) in product.json{}", retried_note(retries)),
+    ));
 
     PatchResult {
         success: true,
@@ -257,31 +680,34 @@ pub fn update_integrity(
     }
 }
 
-/// Read and parse product.json, returning the parsed JSON value and the
-/// checksums map. Shared preamble for verify/fix/update operations.
-fn load_product_checksums(product_json: &Path) -> eyre::Result<(Value, serde_json::Map<String, Value>)> {
-    let product_text = fs::read_to_string(product_json)?;
-    let product: Value = serde_json::from_str(&product_text)?;
-    let checksums = product
-        .get("checksums")
-        .and_then(|v| v.as_object())
-        .cloned()
-        .unwrap_or_default();
-    Ok((product, checksums))
+/// Read and parse product.json, returning the raw text and the checksums
+/// map. Shared preamble for verify/fix/update operations.
+fn load_product_checksums(product_json: &Path) -> Result<(String, serde_json::Map<String, Value>)> {
+    let product_text =
+        fs::read_to_string(product_json).map_err(|e| Error::io("read", product_json, e))?;
+    let mut product: Value = parse_product(&product_text)
+        .map_err(|e| Error::from(e).context(format!("failed to parse {}", product_json.display())))?;
+    // Move the checksums map out of the parsed value instead of cloning it.
+    let checksums = match product.get_mut("checksums").map(Value::take) {
+        Some(Value::Object(map)) => map,
+        _ => serde_json::Map::new(),
+    };
+    Ok((product_text, checksums))
 }
 
 /// Check whether all product.json checksums match the files on disk.
 /// Returns `None` if product.json cannot be read or lacks a checksums section.
 pub fn checksums_all_match(product_json: &Path, cursor_app: &Path) -> Option<bool> {
-    let (_product, checksums) = load_product_checksums(product_json).ok()?;
+    let (_text, checksums) = load_product_checksums(product_json).ok()?;
     if checksums.is_empty() {
         return None;
     }
+    let mut cache = HashCache::default();
     for (rel_path, expected_val) in &checksums {
         let expected = expected_val.as_str().unwrap_or("");
-        let full = cursor_app.join("out").join(rel_path);
-        if full.is_file()
-            && let Ok(actual) = sha256_base64_stripped(&full)
+        if let Some(full) = checksum_file(cursor_app, rel_path)
+            && full.is_file()
+            && let Ok(actual) = cache.sha256_base64_stripped(&full)
             && actual != expected
         {
             return Some(false);
@@ -301,53 +727,72 @@ pub struct VerifyEntry {
     pub actual: String,
     pub matches: bool,
     pub missing: bool,
+    /// Mismatch where the file on disk is newer than product.json, i.e. the
+    /// checksum is stale rather than the file being corrupted.
+    pub stale: bool,
 }
 
 /// Result of checksum verification.
 pub struct VerifyResult {
     pub entries: Vec<VerifyEntry>,
     pub all_match: bool,
+    /// Keys that differ only in case (see `case_clashes`).
+    pub case_clashes: Vec<(String, String)>,
+    /// Keys written with `\` separators; resolved anyway.
+    pub backslash_keys: Vec<String>,
+}
+
+/// Modification time of a file, if available.
+fn mtime(path: &Path) -> Option<std::time::SystemTime> {
+    fs::metadata(path).and_then(|m| m.modified()).ok()
 }
 
 /// Verify every checksum in product.json against files on disk.
 pub fn verify_checksums(
     product_json: &Path,
     cursor_app: &Path,
-) -> eyre::Result<VerifyResult> {
-    let (_product, checksums) = load_product_checksums(product_json)?;
+) -> Result<VerifyResult> {
+    let _span = tracing::info_span!("verify").entered();
+    let (_text, checksums) = load_product_checksums(product_json)?;
 
     let mut result = VerifyResult {
         entries: vec![],
         all_match: true,
+        case_clashes: case_clashes(checksums.keys()),
+        backslash_keys: checksums.keys().filter(|k| has_backslashes(k)).cloned().collect(),
     };
+    let mut cache = HashCache::default();
+    let product_mtime = mtime(product_json);
 
     for (rel_path, expected_val) in &checksums {
         let expected = expected_val.as_str().unwrap_or("").to_string();
-        let full_path = cursor_app.join("out").join(rel_path);
-
-        if !full_path.is_file() {
+        let Some(full_path) = checksum_file(cursor_app, rel_path).filter(|p| p.is_file()) else {
             result.entries.push(VerifyEntry {
                 rel_path: rel_path.clone(),
                 expected,
                 actual: String::new(),
                 matches: false,
                 missing: true,
+                stale: false,
             });
             result.all_match = false;
             continue;
-        }
+        };
 
-        let actual = sha256_base64_stripped(&full_path)?;
+        let actual = cache.sha256_base64_stripped(&full_path)?;
         let matches = actual == expected;
         if !matches {
             result.all_match = false;
         }
+        let stale = !matches
+            && matches!((mtime(&full_path), product_mtime), (Some(f), Some(p)) if f > p);
         result.entries.push(VerifyEntry {
             rel_path: rel_path.clone(),
             expected,
             actual,
             matches,
             missing: false,
+            stale,
         });
     }
 
@@ -375,54 +820,44 @@ pub struct FixEntry {
 pub struct FixChecksumsResult {
     pub entries: Vec<FixEntry>,
     pub changed_count: u32,
+    /// Retries needed to write product.json (transient IO errors).
+    pub write_retries: u32,
 }
 
 /// Recompute and write correct checksums into product.json.
 pub fn fix_checksums(
     product_json: &Path,
     cursor_app: &Path,
-) -> eyre::Result<FixChecksumsResult> {
-    let (mut product, _) = load_product_checksums(product_json)?;
-
-    let checksums = match product.get_mut("checksums").and_then(|v| v.as_object_mut()) {
-        Some(c) => c,
-        None => {
-            return Ok(FixChecksumsResult {
-                entries: vec![],
-                changed_count: 0,
-            });
-        }
-    };
+) -> Result<FixChecksumsResult> {
+    let _span = tracing::info_span!("fix checksums").entered();
+    let (product_text, checksums) = load_product_checksums(product_json)?;
 
     let mut result = FixChecksumsResult {
         entries: vec![],
         changed_count: 0,
+        write_retries: 0,
     };
+    let mut cache = HashCache::default();
+    let mut updates: Vec<ChecksumUpdate> = Vec::new();
 
-    let keys: Vec<(String, String)> = checksums
-        .iter()
-        .map(|(k, v)| (k.clone(), v.as_str().unwrap_or("").to_string()))
-        .collect();
-
-    for (rel_path, old_hash) in &keys {
-        let full_path = cursor_app.join("out").join(rel_path);
-
-        if !full_path.is_file() {
+    for (rel_path, old_val) in &checksums {
+        let old_hash = old_val.as_str().unwrap_or("");
+        let Some(full_path) = checksum_file(cursor_app, rel_path).filter(|p| p.is_file()) else {
             result.entries.push(FixEntry {
                 rel_path: rel_path.clone(),
                 status: FixStatus::Missing,
             });
             continue;
-        }
+        };
 
-        let new_hash = sha256_base64_stripped(&full_path)?;
-        if old_hash == &new_hash {
+        let new_hash = cache.sha256_base64_stripped(&full_path)?;
+        if old_hash == new_hash {
             result.entries.push(FixEntry {
                 rel_path: rel_path.clone(),
                 status: FixStatus::Ok,
             });
         } else {
-            checksums.insert(rel_path.clone(), Value::String(new_hash));
+            updates.push((rel_path.clone(), old_hash.to_string(), new_hash));
             result.entries.push(FixEntry {
                 rel_path: rel_path.clone(),
                 status: FixStatus::Updated,
@@ -432,10 +867,222 @@ pub fn fix_checksums(
     }
 
     if result.changed_count > 0 {
-        let out = serde_json::to_string_pretty(&product)?;
-        let out = tab_indent(&out);
-        fs::write(product_json, out)?;
+        result.write_retries = write_product_json(product_json, &product_text, &updates)?;
     }
 
     Ok(result)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::TempDir;
+
+    const PRODUCT: &str = r#"{
+	"nameShort": "Cursor",
+	"checksums": {
+		"vs/workbench/workbench.desktop.main.js": "AAAA",
+		"vs/workbench/api/node/extensionHostProcess.js": "BBBB",
+		"vs/code/electron-sandbox/workbench/workbench.js": "AAAA"
+	},
+	"version": "1.6.42"
+}
+"#;
+
+    fn update(rel_path: &str, old: &str, new: &str) -> ChecksumUpdate {
+        (rel_path.to_string(), old.to_string(), new.to_string())
+    }
+
+    #[test]
+    fn one_checksum_update_changes_one_line() {
+        let updates = [update("vs/workbench/workbench.desktop.main.js", "AAAA", "CCCC")];
+        let out = apply_checksum_updates(PRODUCT, &updates).unwrap();
+
+        let changed: Vec<(&str, &str)> = PRODUCT.lines().zip(out.lines()).filter(|(a, b)| a != b).collect();
+        assert_eq!(out.lines().count(), PRODUCT.lines().count());
+        assert_eq!(
+            changed,
+            [(
+                "\t\t\"vs/workbench/workbench.desktop.main.js\": \"AAAA\",",
+                "\t\t\"vs/workbench/workbench.desktop.main.js\": \"CCCC\",",
+            )]
+        );
+    }
+
+    #[test]
+    fn checksum_update_skips_comments_around_colon() {
+        let text = concat!(
+            "{\n",
+            "  // JSONC\n",
+            "  \"checksums\": {\n",
+            "    \"a.js\": /* x */ \"AAAA\",\n",
+            "    \"b.js\" /* y */ : // z\n",
+            "      \"BBBB\"\n",
+            "  }\n",
+            "}\n",
+        );
+        let updates = [update("a.js", "AAAA", "CCCC"), update("b.js", "BBBB", "DDDD")];
+        let out = apply_checksum_updates(text, &updates).unwrap();
+        assert_eq!(out, text.replace("AAAA", "CCCC").replace("BBBB", "DDDD"));
+        assert_eq!(parse_product(&out).unwrap()["checksums"]["b.js"], "DDDD");
+    }
+
+    #[test]
+    fn unknown_checksum_key_is_an_error() {
+        let updates = [update("vs/missing.js", "AAAA", "CCCC")];
+        assert!(apply_checksum_updates(PRODUCT, &updates).is_err());
+    }
+
+    /// Base64 SHA-256 (padding stripped) of `data`, as product.json has it.
+    fn checksum(data: &str) -> String {
+        STANDARD.encode(Sha256::digest(data)).trim_end_matches('=').to_string()
+    }
+
+    /// Top-level keys of `text`, in file order.
+    fn top_level_keys(text: &str) -> Vec<String> {
+        parse_product(text).unwrap().as_object().unwrap().keys().cloned().collect()
+    }
+
+    #[test]
+    fn fix_checksums_keeps_key_order() {
+        let app = TempDir::new("key-order");
+        app.write("out/vs/a.js", "patched");
+        app.write("out/vs/b.js", "untouched");
+        let text = r#"{
+  "version": "1.6.42",
+  "nameShort": "Cursor",
+  "checksums": {
+    "vs/b.js": "B",
+    "vs/a.js": "A"
+  },
+  "commit": "deadbeef",
+  "applicationName": "cursor"
+}
+"#
+        .replace("\"B\"", &format!("{:?}", checksum("untouched")))
+        .replace("\"A\"", &format!("{:?}", checksum("original")));
+        let product_json = app.write("product.json", &text);
+
+        let result = fix_checksums(&product_json, &app.0).unwrap();
+        assert_eq!(result.changed_count, 1);
+
+        let fixed = fs::read_to_string(&product_json).unwrap();
+        assert_eq!(top_level_keys(&fixed), ["version", "nameShort", "checksums", "commit", "applicationName"]);
+        let checksum_keys: Vec<String> = parse_product(&fixed).unwrap()["checksums"]
+            .as_object()
+            .unwrap()
+            .keys()
+            .cloned()
+            .collect();
+        assert_eq!(checksum_keys, ["vs/b.js", "vs/a.js"]);
+        // Every line but the rewritten checksum is where it was.
+        let moved: Vec<usize> = text
+            .lines()
+            .zip(fixed.lines())
+            .enumerate()
+            .filter(|(_, (a, b))| a != b)
+            .map(|(i, _)| i)
+            .collect();
+        assert_eq!(fixed.lines().count(), text.lines().count());
+        assert_eq!(moved, [5]);
+        assert!(fixed.lines().nth(5).unwrap().contains(&checksum("patched")));
+    }
+
+    #[test]
+    fn checksum_file_joins_each_segment() {
+        let app = Path::new("app");
+        let expected: PathBuf = ["app", "out", "vs", "workbench", "workbench.desktop.main.js"].iter().collect();
+        for key in [
+            "vs/workbench/workbench.desktop.main.js",
+            "./vs/workbench/workbench.desktop.main.js",
+            "vs//workbench/./workbench.desktop.main.js",
+            "vs\\workbench\\workbench.desktop.main.js",
+        ] {
+            assert_eq!(checksum_file(app, key), Some(expected.clone()), "{key}");
+        }
+    }
+
+    #[test]
+    fn checksum_file_rejects_parent_segments() {
+        let app = Path::new("app");
+        assert_eq!(checksum_file(app, "../../x"), None);
+        assert_eq!(checksum_file(app, "vs/../../product.json"), None);
+        assert_eq!(checksum_file(app, "vs\\..\\x.js"), None);
+    }
+
+    #[test]
+    fn fix_checksums_skips_keys_outside_out() {
+        let app = TempDir::new("fix-parent-key");
+        app.write("secret", "outside");
+        let product_json = app.write("product.json", r#"{"checksums": {"../secret": "AAAA"}}"#);
+
+        let result = fix_checksums(&product_json, &app.0).unwrap();
+        assert_eq!(result.changed_count, 0);
+        assert!(matches!(result.entries[0].status, FixStatus::Missing));
+        assert!(fs::read_to_string(&product_json).unwrap().contains("AAAA"));
+    }
+
+    #[test]
+    fn update_integrity_writes_each_entrys_target_hash() {
+        let app = TempDir::new("embedded-target");
+        let main_js = app.write("extensions/cursor-agent-exec/dist/main.js", "patched agent");
+        let old = "0".repeat(64);
+        let ehp = app.write(
+            "out/vs/workbench/api/node/extensionHostProcess.js",
+            format!(r#"const h={{"cursor-agent-exec":{{"dist":{{"main.js":"{old}"}}}}}};"#),
+        );
+        let ehp_key = "vs/workbench/api/node/extensionHostProcess.js";
+        let product_json = app.write("product.json", format!(r#"{{"checksums": {{"{ehp_key}": "AAAA"}}}}"#));
+
+        let result = update_integrity(&main_js, Some(&product_json), Some(&app.0), false, false);
+        assert!(result.success);
+        for entry in EMBEDDED_HASHES {
+            let target_hash = sha256_hex(&entry.target_path(&app.0)).unwrap();
+            let script = fs::read_to_string(entry.script_path(&app.0)).unwrap();
+            assert!(script.contains(&target_hash), "{}", entry.name);
+        }
+        let product = parse_product(&fs::read_to_string(&product_json).unwrap()).unwrap();
+        assert_eq!(product["checksums"][ehp_key], sha256_base64_stripped(&ehp).unwrap());
+    }
+
+    /// A CRLF product.json whose `vs/a.js` checksum is `a_hash`.
+    fn crlf_product(a_hash: &str) -> String {
+        [
+            "{",
+            "  \"nameShort\": \"Cursor\",",
+            "  \"checksums\": {",
+            &format!("    \"vs/a.js\": {a_hash:?}"),
+            "  }",
+            "}",
+            "",
+        ]
+        .join("\r\n")
+    }
+
+    #[test]
+    fn crlf_product_json_round_trips_when_nothing_changed() {
+        let app = TempDir::new("crlf-unchanged");
+        app.write("out/vs/a.js", "patched");
+        let text = crlf_product(&checksum("patched"));
+        let product_json = app.write("product.json", &text);
+
+        let result = fix_checksums(&product_json, &app.0).unwrap();
+        assert_eq!(result.changed_count, 0);
+        assert_eq!(fs::read_to_string(&product_json).unwrap(), text);
+    }
+
+    #[test]
+    fn crlf_product_json_keeps_crlf_when_a_checksum_changes() {
+        let app = TempDir::new("crlf-changed");
+        app.write("out/vs/a.js", "patched");
+        let text = crlf_product(&checksum("original"));
+        let product_json = app.write("product.json", &text);
+
+        let result = fix_checksums(&product_json, &app.0).unwrap();
+        assert_eq!(result.changed_count, 1);
+        let fixed = fs::read_to_string(&product_json).unwrap();
+        assert_eq!(fixed, crlf_product(&checksum("patched")));
+        assert_eq!(fixed.matches("\r\n").count(), text.matches("\r\n").count());
+        assert_eq!(fixed.matches('\n').count(), fixed.matches("\r\n").count());
+    }
+}
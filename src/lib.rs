@@ -0,0 +1,20 @@
+//! Library half of nupatch: everything but argument parsing, so benches
+//! (and other tools) can drive discovery and patching directly.
+
+pub mod archive;
+pub mod bugreport;
+pub mod cli;
+pub mod core;
+pub mod error;
+pub mod history;
+pub mod integrity;
+pub mod manifest;
+pub mod notify;
+pub mod paths;
+pub mod report;
+pub mod rules;
+#[cfg(test)]
+mod testutil;
+pub mod timing;
+pub mod util;
+pub mod version;
@@ -1,11 +1,10 @@
-mod cli;
-mod core;
-mod integrity;
-mod paths;
-mod util;
+use std::path::PathBuf;
 
 use clap::{Parser, Subcommand};
-use color_eyre::eyre::Result;
+use color_eyre::eyre::{Result, eyre};
+
+use nupatch::version::Version;
+use nupatch::{cli, error, paths, timing};
 
 const VERSION: &str = env!("CARGO_PKG_VERSION");
 
@@ -18,9 +17,25 @@ const VERSION: &str = env!("CARGO_PKG_VERSION");
 struct Cli {
     #[command(subcommand)]
     command: Commands,
+
+    /// Print how long each phase took (detection, discovery, each step, ...)
+    #[arg(long, global = true)]
+    trace: bool,
+
+    /// Use DIR as Cursor's resources/app instead of detecting the install
+    /// (takes precedence over $NUPATCH_CURSOR_APP)
+    #[arg(long, global = true, value_name = "DIR")]
+    app_path: Option<PathBuf>,
+
+    /// Only detect Cursor installs of this channel (stable or insiders);
+    /// not with --app-path or $NUPATCH_CURSOR_APP, which pick the install
+    #[arg(long, global = true, value_name = "CHANNEL", conflicts_with = "app_path")]
+    channel: Option<paths::Channel>,
 }
 
+// Parsed once at startup; boxing `Patch` would only add noise.
 #[derive(Subcommand)]
+#[allow(clippy::large_enum_variant)]
 enum Commands {
     /// Apply nushell patches to Cursor agents
     Patch {
@@ -32,30 +47,290 @@ enum Commands {
         #[arg(long)]
         ide_only: bool,
 
+        /// Patch every installed CLI agent version, not just the newest
+        #[arg(long, conflicts_with_all = ["ide_only", "out", "file", "dir", "glob", "archive", "emit"])]
+        cli_all: bool,
+
+        /// Patch the N most recent CLI agent versions (default 1: the newest)
+        #[arg(
+            long,
+            value_name = "N",
+            conflicts_with_all = ["cli_all", "ide_only", "out", "file", "dir", "glob", "archive", "emit"]
+        )]
+        cli_recent: Option<usize>,
+
+        /// Patch this CLI agent version (a directory under versions/) instead of the newest
+        #[arg(
+            long,
+            value_name = "VER",
+            conflicts_with_all = ["cli_all", "cli_recent", "ide_only", "file", "dir", "glob", "archive"]
+        )]
+        agent_version: Option<String>,
+
+        /// List the installed CLI agent versions and exit
+        #[arg(
+            long,
+            conflicts_with_all = ["cli_all", "cli_recent", "agent_version", "ide_only", "file", "dir", "glob", "archive"]
+        )]
+        list_versions: bool,
+
         /// Preview changes without applying
         #[arg(short = 'n', long)]
         dry_run: bool,
+
+        /// Recompute every product.json checksum, not just patched files
+        #[arg(long)]
+        full_checksums: bool,
+
+        /// Treat Cursor as this version for version-gated checks
+        #[arg(long, value_name = "VER")]
+        assume_version: Option<String>,
+
+        /// Only patch if Cursor is at least this version; skip otherwise
+        #[arg(long, value_name = "VER", conflicts_with_all = ["file", "dir", "glob", "archive"])]
+        since: Option<Version>,
+
+        /// Add or override patch steps from a JSON rules manifest
+        #[arg(long, value_name = "FILE")]
+        rules: Option<PathBuf>,
+
+        /// Literal find/replace pairs applied after the built-in patches
+        #[arg(long, value_name = "FILE")]
+        patch_script: Option<PathBuf>,
+
+        /// Skip the PATH-based nu detection; detect nu from the shell hint only
+        #[arg(long)]
+        no_system_detection: bool,
+
+        /// Leave userTerminalHint out of IDE shell resolution (PATH discovery only)
+        #[arg(long)]
+        no_user_terminal_hint: bool,
+
+        /// Accept a partial result when some steps fail (write it with --force)
+        #[arg(long)]
+        continue_on_error: bool,
+
+        /// With --continue-on-error, write the partial result anyway
+        #[arg(long, requires = "continue_on_error")]
+        force: bool,
+
+        /// Write even if patching would change the file's BOM or line endings
+        #[arg(long)]
+        allow_reencode: bool,
+
+        /// Describe what each patch step does, and why, before it runs
+        #[arg(long)]
+        explain: bool,
+
+        /// Log every discovery regex result and show the code around failed matches
+        #[arg(long)]
+        trace_regex: bool,
+
+        /// Patch this agent file ('-' for stdin) and print the result to stdout
+        #[arg(long, value_name = "FILE")]
+        file: Option<PathBuf>,
+
+        /// Write the patched agent to PATH; the original, backups and hashes are left alone
+        #[arg(long, value_name = "PATH")]
+        out: Option<PathBuf>,
+
+        /// Write each patched agent to <file>.patched next to the original instead
+        #[arg(long, conflicts_with_all = ["out", "file"])]
+        side_by_side: bool,
+
+        /// With --dry-run, open each change in CMD (default: $DIFFTOOL, else git difftool)
+        #[arg(long, value_name = "CMD", num_args = 0..=1, default_missing_value = "", requires = "dry_run")]
+        diff_tool: Option<String>,
+
+        /// Patch every index.js (CLI) and main.js (IDE) under DIR; --out is then a directory
+        #[arg(long, value_name = "DIR", conflicts_with = "file")]
+        dir: Option<PathBuf>,
+
+        /// Patch the files matching PATTERN (e.g. '**/versions/*/index.js')
+        #[arg(long, value_name = "PATTERN", conflicts_with_all = ["file", "dir", "out"])]
+        glob: Option<String>,
+
+        /// Patch the agents inside a .zip/.tar/.tar.gz of resources/app into a new archive (--out)
+        #[arg(long, value_name = "FILE", conflicts_with_all = ["file", "dir", "glob", "side_by_side"])]
+        archive: Option<PathBuf>,
+
+        /// With --dry-run, write the complete patched file to PATH as a preview
+        #[arg(
+            long,
+            value_name = "PATH",
+            requires = "dry_run",
+            conflicts_with_all = ["out", "side_by_side", "dir", "glob", "archive"]
+        )]
+        emit: Option<PathBuf>,
+
+        /// Show a desktop notification when agents are (re)patched
+        /// (needs a build with the `notify` feature)
+        #[arg(long, conflicts_with = "dry_run")]
+        notify: bool,
+
+        /// Save the results and the resulting status as a JSON report
+        /// (render it later with `status --from-report`)
+        #[arg(long, value_name = "FILE", conflicts_with_all = ["file", "dir", "glob", "archive"])]
+        report: Option<PathBuf>,
+    },
+
+    /// Show the minified names discovered in an agent file ('-' for stdin)
+    Discover {
+        file: PathBuf,
+    },
+
+    /// Compare the patches in two agent files and diff their shell-detection code
+    Compare {
+        file_a: PathBuf,
+        file_b: PathBuf,
     },
 
     /// Restore all patched files from backups
-    Revert,
+    Revert {
+        /// Don't ask for confirmation
+        #[arg(short, long)]
+        yes: bool,
+
+        /// Restore every CLI agent version nupatch patched, not just the newest
+        #[arg(long)]
+        all_versions: bool,
+    },
+
+    /// Reverse the last patch, revert or fix-checksums from its snapshot
+    Undo {
+        /// Don't ask for confirmation
+        #[arg(short, long)]
+        yes: bool,
+    },
+
+    /// Show the runs that changed the install, newest first
+    History {
+        /// Show at most this many runs
+        #[arg(long, value_name = "N")]
+        limit: Option<usize>,
+
+        /// Print the recorded entries as JSON
+        #[arg(long)]
+        json: bool,
+    },
+
+    /// Bundle versions, paths, discovery and status into one JSON file for an issue
+    #[command(name = "bug-report")]
+    BugReport {
+        /// Write the bundle here instead of printing it
+        #[arg(long, value_name = "FILE")]
+        out: Option<PathBuf>,
+
+        /// Replace the home directory in paths with `~` (the default)
+        #[arg(long, overrides_with = "no_redact")]
+        redact: bool,
+
+        /// Keep full paths, including the home directory
+        #[arg(long, overrides_with = "redact")]
+        no_redact: bool,
+
+        /// Also replace CLI agent version directory names with short hashes
+        #[arg(long, conflicts_with = "no_redact")]
+        hash_versions: bool,
+    },
+
+    /// Show nupatch, Cursor, and CLI agent versions
+    #[command(alias = "i")]
+    Info,
 
     /// Show current patch status for CLI and IDE agents
     #[command(alias = "s")]
-    Status,
+    Status {
+        /// Print nothing; exit 0 only if fully patched and integrity-clean
+        #[arg(long)]
+        check: bool,
+
+        /// Show discovered minified names (or failing checks with --check)
+        #[arg(short, long)]
+        verbose: bool,
+
+        /// Print status as JSON; exit code reflects the overall state
+        #[arg(long, conflicts_with = "check")]
+        json: bool,
+
+        /// Keep redrawing the status until Ctrl-C, to watch for Cursor updates
+        #[arg(long, conflicts_with_all = ["check", "json"])]
+        watch: bool,
+
+        /// Seconds between redraws with --watch
+        #[arg(
+            long,
+            value_name = "SECS",
+            default_value_t = 2,
+            requires = "watch",
+            value_parser = clap::value_parser!(u64).range(1..)
+        )]
+        interval: u64,
+
+        /// Render a report saved by `patch --report` / `verify --report`
+        /// instead of checking the live install
+        #[arg(long, value_name = "FILE", conflicts_with_all = ["check", "json", "watch"])]
+        from_report: Option<PathBuf>,
+    },
 
     /// Verify product.json checksums against files on disk
     #[command(alias = "v")]
-    Verify,
+    Verify {
+        /// Print results as JSON (full, untruncated hashes)
+        #[arg(long)]
+        json: bool,
+
+        /// Also check the CLI agent against the hash recorded when nupatch patched it
+        #[arg(long)]
+        cli: bool,
+
+        /// Also check both agents against the hashes recorded when nupatch patched them
+        #[arg(long)]
+        include_agents: bool,
+
+        /// Also save the results and the current status as a JSON report
+        #[arg(long, value_name = "FILE")]
+        report: Option<PathBuf>,
+    },
+
+    /// List product.json checksums that differ from files on disk
+    #[command(name = "diff-checksums", alias = "dc")]
+    DiffChecksums,
 
     /// Recalculate all product.json checksums
     #[command(name = "fix-checksums", alias = "fc")]
     FixChecksums,
+
+    /// List the built-in patch steps and what they edit
+    Rules {
+        /// Print the rules as a JSON manifest (same format as --rules)
+        #[arg(long)]
+        export: bool,
+    },
 }
 
 fn main() -> Result<()> {
     color_eyre::install()?;
 
+    let result = run();
+    // --trace timings cover failed runs too.
+    cli::display_timings();
+    match result {
+        Ok(0) => Ok(()),
+        Ok(code) => std::process::exit(code),
+        // Structured errors exit with the same codes as a failed patch step.
+        Err(report) => {
+            let code = report
+                .downcast_ref::<error::Error>()
+                .map_or(1, |e| e.kind().exit_code());
+            eprintln!("Error: {report:?}");
+            std::process::exit(code);
+        }
+    }
+}
+
+/// Run the command; returns the process exit code.
+fn run() -> Result<i32> {
     // Intercept clap's help/version to render with richrs
     let args = match Cli::try_parse() {
         Ok(args) => args,
@@ -64,24 +339,116 @@ fn main() -> Result<()> {
                 || e.kind() == clap::error::ErrorKind::DisplayHelpOnMissingArgumentOrSubcommand =>
         {
             cli::cmd_help(VERSION);
-            return Ok(());
+            return Ok(0);
         }
         Err(e) if e.kind() == clap::error::ErrorKind::DisplayVersion => {
             cli::cmd_version(VERSION);
-            return Ok(());
+            return Ok(0);
         }
         Err(e) => e.exit(),
     };
 
+    if args.trace {
+        timing::install();
+    }
+    if let Some(dir) = args.app_path {
+        paths::set_app_path(dir);
+    }
+    if let Some(channel) = args.channel {
+        // The variable names the install outright, so the channel would
+        // silently be ignored.
+        if std::env::var_os(paths::CURSOR_APP_ENV).is_some_and(|dir| !dir.is_empty()) {
+            return Err(eyre!(
+                "--channel can't be used with ${} set: it already picks the install (unset it, or drop --channel)",
+                paths::CURSOR_APP_ENV
+            ));
+        }
+        paths::set_channel(channel);
+    }
+
     match args.command {
         Commands::Patch {
             cli_only,
             ide_only,
+            cli_all,
+            cli_recent,
+            agent_version,
+            list_versions,
             dry_run,
-        } => cli::cmd_patch(cli_only, ide_only, dry_run),
-        Commands::Revert => cli::cmd_revert(),
-        Commands::Status => cli::cmd_status(),
-        Commands::Verify => cli::cmd_verify(),
-        Commands::FixChecksums => cli::cmd_fix_checksums(),
+            full_checksums,
+            assume_version,
+            since,
+            rules,
+            patch_script,
+            no_system_detection,
+            no_user_terminal_hint,
+            continue_on_error,
+            force,
+            allow_reencode,
+            explain,
+            trace_regex,
+            file,
+            out,
+            side_by_side,
+            diff_tool,
+            dir,
+            glob,
+            archive,
+            emit,
+            notify,
+            report,
+        } => cli::cmd_patch(cli::PatchArgs {
+            cli_only,
+            ide_only,
+            cli_all,
+            cli_recent,
+            agent_version,
+            list_versions,
+            dry_run,
+            full_checksums,
+            assume_version,
+            since,
+            rules,
+            patch_script,
+            no_system_detection,
+            no_user_terminal_hint,
+            continue_on_error,
+            force,
+            allow_reencode,
+            explain,
+            trace_regex,
+            file,
+            out,
+            side_by_side,
+            diff_tool,
+            dir,
+            glob,
+            archive,
+            emit,
+            notify,
+            report,
+        }),
+        Commands::Discover { file } => cli::cmd_discover(&file).map(|()| 0),
+        Commands::Compare { file_a, file_b } => cli::cmd_compare(&file_a, &file_b).map(|()| 0),
+        Commands::Revert { yes, all_versions } => cli::cmd_revert(yes, all_versions).map(|()| 0),
+        Commands::Undo { yes } => cli::cmd_undo(yes).map(|()| 0),
+        Commands::History { limit, json } => cli::cmd_history(limit, json).map(|()| 0),
+        Commands::BugReport { out, no_redact, hash_versions, .. } => {
+            cli::cmd_bug_report(out.as_deref(), !no_redact, hash_versions).map(|()| 0)
+        }
+        Commands::Info => cli::cmd_info(VERSION).map(|()| 0),
+        Commands::Status { from_report: Some(file), .. } => cli::cmd_status_from_report(&file).map(|()| 0),
+        Commands::Status { check: true, verbose, .. } => Ok(cli::cmd_status_check(verbose)),
+        Commands::Status { json: true, .. } => cli::cmd_status_json(),
+        Commands::Status { watch: true, verbose, interval, .. } => {
+            cli::cmd_status_watch(verbose, interval).map(|()| 0)
+        }
+        Commands::Status { verbose, .. } => cli::cmd_status(verbose).map(|()| 0),
+        Commands::Verify { json, cli, include_agents, report } => {
+            cli::cmd_verify(json, cli, include_agents, report.as_deref()).map(|()| 0)
+        }
+        Commands::DiffChecksums => cli::cmd_diff_checksums().map(|()| 0),
+        Commands::FixChecksums => cli::cmd_fix_checksums().map(|()| 0),
+        Commands::Rules { export } => cli::cmd_rules(export).map(|()| 0),
     }
 }
@@ -0,0 +1,203 @@
+//! The manifest: `~/.nupatch/manifest.json` records every agent file
+//! nupatch has patched in place (CLI and IDE), with the hash it left the
+//! file at.
+//!
+//! ```json
+//! {"files":{"/home/me/.cursor-agent/versions/2025.09.04-abc/index.js":
+//!   {"label":"CLI","version":"2025.09.04-abc","sha256":"9f86d0...",
+//!    "original_sha256":"2c26b4...","time":1760000000}}}
+//! ```
+//!
+//! product.json's checksums only cover the IDE's `out/` files, so this is
+//! how nupatch knows by hash whether its CLI patch is still the file on
+//! disk: a different hash usually means Cursor updated the agent. It is
+//! also the list of CLI versions nupatch patched, with the hash each had
+//! before, so `revert --all-versions` restores exactly those. Each original
+//! is kept under that hash in `~/.nupatch/originals` (see
+//! `integrity::store_original`), so it can be restored even once the
+//! `.bak` next to the file is gone or stale. Like the
+//! history log, the manifest is best-effort -- one that can't be read is
+//! empty, and one that can't be written never fails a patch.
+
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use serde_json::{Value, json};
+
+use crate::core::{backup_pristine, cli_agent_version};
+use crate::integrity::{bak_path, sha256_hex, store_original};
+use crate::paths::nupatch_dir;
+
+/// `~/.nupatch/manifest.json`, if there is a home directory.
+pub fn manifest_path() -> Option<PathBuf> {
+    nupatch_dir().map(|dir| dir.join("manifest.json"))
+}
+
+/// What nupatch left one file at.
+pub struct Record {
+    /// `CLI` or `IDE`.
+    pub label: String,
+    /// For a CLI agent, its version directory name.
+    pub version: Option<String>,
+    /// Hash of the file right after it was patched.
+    pub sha256: String,
+    /// Hash of the original it was patched from (its `.bak`), if known.
+    pub original_sha256: Option<String>,
+    pub time: Option<SystemTime>,
+}
+
+/// Every recorded file, by path.
+#[derive(Default)]
+pub struct Manifest {
+    pub files: BTreeMap<PathBuf, Record>,
+}
+
+impl Manifest {
+    /// Load the manifest. Missing or unreadable is empty, and entries that
+    /// don't parse are dropped.
+    pub fn load() -> Self {
+        let value = manifest_path()
+            .and_then(|p| fs::read_to_string(p).ok())
+            .and_then(|text| serde_json::from_str::<Value>(&text).ok());
+        let Some(files) = value.as_ref().and_then(|v| v["files"].as_object()) else {
+            return Self::default();
+        };
+        let files = files
+            .iter()
+            .filter_map(|(path, r)| {
+                let record = Record {
+                    label: r["label"].as_str()?.to_string(),
+                    version: r["version"].as_str().map(str::to_string),
+                    sha256: r["sha256"].as_str()?.to_string(),
+                    original_sha256: r["original_sha256"].as_str().map(str::to_string),
+                    time: r["time"].as_u64().map(|s| UNIX_EPOCH + Duration::from_secs(s)),
+                };
+                Some((PathBuf::from(path), record))
+            })
+            .collect();
+        Self { files }
+    }
+
+    /// Write the manifest back, best-effort.
+    pub fn save(&self) {
+        let Some(path) = manifest_path() else { return };
+        let files: serde_json::Map<String, Value> = self
+            .files
+            .iter()
+            .map(|(path, r)| {
+                let record = json!({
+                    "label": r.label,
+                    "version": r.version,
+                    "sha256": r.sha256,
+                    "original_sha256": r.original_sha256,
+                    "time": r.time.and_then(|t| t.duration_since(UNIX_EPOCH).ok()).map(|d| d.as_secs()),
+                });
+                (path.to_string_lossy().into_owned(), record)
+            })
+            .collect();
+        let Ok(text) = serde_json::to_string_pretty(&json!({ "files": files })) else { return };
+        if let Some(dir) = path.parent() {
+            let _ = fs::create_dir_all(dir);
+        }
+        let _ = fs::write(path, text + "\n");
+    }
+
+    /// Record `path` as patched, at its current hash, from the original in
+    /// its backup. A backup that already contains patches is no original: the
+    /// one recorded before for the same version is kept instead, so a known
+    /// pristine original is never replaced by patched content.
+    pub fn record(&mut self, path: &Path, label: &str) {
+        let Ok(sha256) = sha256_hex(path) else { return };
+        let version = if label == "CLI" { cli_agent_version(path) } else { None };
+        let original_sha256 = match backup_pristine(path) {
+            Some(false) => self
+                .files
+                .get(path)
+                .filter(|r| r.version == version)
+                .and_then(|r| r.original_sha256.clone()),
+            _ => store_original(&bak_path(path)),
+        };
+        let record = Record {
+            label: label.to_string(),
+            version,
+            sha256,
+            original_sha256,
+            time: Some(SystemTime::now()),
+        };
+        self.files.insert(path.to_path_buf(), record);
+    }
+
+    /// The CLI agents nupatch patched, whether or not they still exist.
+    pub fn cli_agents(&self) -> Vec<&Path> {
+        self.files
+            .iter()
+            .filter(|(_, r)| r.label == "CLI")
+            .map(|(path, _)| path.as_path())
+            .collect()
+    }
+
+    /// Drop the record of a file nupatch no longer has patched.
+    pub fn forget(&mut self, path: &Path) {
+        self.files.remove(path);
+    }
+}
+
+/// Drop the records of `files` that no longer have their recorded hash,
+/// after a revert or undo put something else back.
+pub fn forget_changed(files: &[&Path]) {
+    let mut manifest = Manifest::load();
+    let before = manifest.files.len();
+    for &path in files {
+        if manifest.files.get(path).is_some_and(|r| sha256_hex(path).ok().as_deref() != Some(r.sha256.as_str())) {
+            manifest.forget(path);
+        }
+    }
+    if manifest.files.len() != before {
+        manifest.save();
+    }
+}
+
+/// A file checked against its recorded hashes.
+pub struct Check {
+    /// The hash nupatch left it at.
+    pub recorded: String,
+    /// The hash of the original it was patched from, if known.
+    pub original: Option<String>,
+    /// `None` when the file is gone or unreadable.
+    pub actual: Option<String>,
+}
+
+impl Check {
+    /// Whether the file is still as nupatch patched it.
+    pub fn matches(&self) -> bool {
+        self.actual.as_deref() == Some(self.recorded.as_str())
+    }
+
+    /// Whether the file is the original it was patched from.
+    pub fn is_original(&self) -> bool {
+        self.actual.is_some() && self.actual == self.original
+    }
+
+    pub fn to_json(&self, path: &Path) -> Value {
+        json!({
+            "path": path,
+            "recorded": self.recorded,
+            "original": self.original,
+            "actual": self.actual,
+            "matches": self.matches(),
+        })
+    }
+}
+
+/// Check `path` against the manifest. `None` if nothing is recorded for it.
+pub fn check(path: &Path) -> Option<Check> {
+    let manifest = Manifest::load();
+    let record = manifest.files.get(path)?;
+    Some(Check {
+        recorded: record.sha256.clone(),
+        original: record.original_sha256.clone(),
+        actual: sha256_hex(path).ok(),
+    })
+}
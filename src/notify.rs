@@ -0,0 +1,23 @@
+//! Desktop notifications for `patch --notify`.
+//!
+//! Only built with the `notify` cargo feature, so the default build doesn't
+//! pull in a notification stack. Without it, or when no notification
+//! service is running, `send` does nothing.
+
+/// Whether this build can show notifications at all.
+pub const AVAILABLE: bool = cfg!(feature = "notify");
+
+/// Show a desktop notification. Failures are ignored: a missing backend
+/// shouldn't fail a patch that already succeeded.
+pub fn send(summary: &str, body: &str) {
+    #[cfg(feature = "notify")]
+    {
+        let _ = notify_rust::Notification::new()
+            .appname("nupatch")
+            .summary(summary)
+            .body(body)
+            .show();
+    }
+    #[cfg(not(feature = "notify"))]
+    let _ = (summary, body);
+}
@@ -1,7 +1,11 @@
 //! Cross-platform detection of Cursor installation paths.
 
 use std::env;
+use std::fs;
 use std::path::{Path, PathBuf};
+use std::sync::OnceLock;
+
+use crate::version::Version;
 
 /// Resolve `%LOCALAPPDATA%`, falling back to `%USERPROFILE%\AppData\Local`.
 fn local_app_data() -> Option<PathBuf> {
@@ -11,6 +15,71 @@ fn local_app_data() -> Option<PathBuf> {
             .map(|p| PathBuf::from(p).join("AppData").join("Local")))
 }
 
+/// The user's home directory: `$HOME`, or `%USERPROFILE%` on Windows.
+pub fn home_dir() -> Option<PathBuf> {
+    env::var_os("HOME")
+        .filter(|_| !cfg!(target_os = "windows"))
+        .or_else(|| env::var_os("USERPROFILE"))
+        .filter(|home| !home.is_empty())
+        .map(PathBuf::from)
+}
+
+/// `~/.nupatch`, where nupatch keeps its own state (the history log).
+/// `None` when no home directory is set.
+pub fn nupatch_dir() -> Option<PathBuf> {
+    home_dir().map(|home| home.join(".nupatch"))
+}
+
+/// `--app-path`: the `resources/app` directory to use instead of a detected one.
+static APP_PATH: OnceLock<PathBuf> = OnceLock::new();
+
+/// Use `dir` as Cursor's `resources/app` for the rest of the run
+/// (`--app-path`), skipping auto-detection.
+pub fn set_app_path(dir: PathBuf) {
+    let _ = APP_PATH.set(dir);
+}
+
+/// The `--app-path` directory, if one was given.
+pub fn app_path() -> Option<&'static Path> {
+    APP_PATH.get().map(PathBuf::as_path)
+}
+
+/// A Cursor release channel. Insiders builds install as "Cursor Nightly".
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Channel {
+    Stable,
+    Insiders,
+}
+
+impl Channel {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::Stable => "stable",
+            Self::Insiders => "insiders",
+        }
+    }
+}
+
+impl std::str::FromStr for Channel {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "stable" => Ok(Self::Stable),
+            "insiders" | "nightly" => Ok(Self::Insiders),
+            _ => Err(format!("'{s}' is not a channel (expected stable or insiders)")),
+        }
+    }
+}
+
+/// `--channel`: only detect installs of this channel.
+static CHANNEL: OnceLock<Channel> = OnceLock::new();
+
+/// Only detect installs of `channel` for the rest of the run (`--channel`).
+pub fn set_channel(channel: Channel) {
+    let _ = CHANNEL.set(channel);
+}
+
 /// Resolved paths for the Cursor installation.
 #[derive(Debug, Default)]
 #[allow(dead_code)]
@@ -22,65 +91,182 @@ pub struct CursorPaths {
     /// Path to `extensionHostProcess.js` (the extension host bootstrap script).
     pub ehp: Option<PathBuf>,
     pub product_json: Option<PathBuf>,
+    /// `resources/app.asar` of an install whose resources are packed into an
+    /// archive instead of unpacked under `resources/app`. Only set when no
+    /// unpacked install was found; nupatch can't patch inside the archive.
+    pub packed_asar: Option<PathBuf>,
+    /// Channel of the detected `cursor_app`; `None` when it came from
+    /// `--app-path` or `$NUPATCH_CURSOR_APP`.
+    pub channel: Option<Channel>,
+    /// Another channel that is installed too but wasn't picked.
+    pub other_channel: Option<Channel>,
 }
 
-/// Find the Cursor IDE installation directory.
-fn detect_cursor_app() -> Option<PathBuf> {
-    let is_candidate = |p: &Path| p.join("product.json").is_file();
-
-    if cfg!(target_os = "windows") {
-        if let Some(local) = local_app_data() {
-            let p = local
-                .join("Programs")
-                .join("cursor")
-                .join("resources")
-                .join("app");
-            if is_candidate(&p) {
-                return Some(p);
-            }
+/// Where the Cursor IDE's `resources/app` directory may live, in order of
+/// preference: every stable location before any Insiders one. With
+/// `--channel`, only that channel's.
+fn cursor_app_candidates() -> Vec<(Channel, PathBuf)> {
+    let mut candidates = Vec::new();
+    let windows_local = wsl_windows_local_app_data();
+    // (channel, Windows/Linux directory name, macOS bundle, /opt directory)
+    for (channel, dir, mac_app, opt) in [
+        (Channel::Stable, "cursor", "Cursor.app", "Cursor"),
+        (Channel::Insiders, "cursor-nightly", "Cursor Nightly.app", "cursor-nightly"),
+    ] {
+        if CHANNEL.get().is_some_and(|&c| c != channel) {
+            continue;
         }
-    } else if cfg!(target_os = "macos") {
-        let p = PathBuf::from("/Applications/Cursor.app/Contents/Resources/app");
-        if is_candidate(&p) {
-            return Some(p);
-        }
-        if let Ok(home) = env::var("HOME") {
-            let p = Path::new(&home)
-                .join("Applications")
-                .join("Cursor.app")
-                .join("Contents")
-                .join("Resources")
-                .join("app");
-            if is_candidate(&p) {
-                return Some(p);
+        if cfg!(target_os = "windows") {
+            if let Some(local) = local_app_data() {
+                candidates.push((
+                    channel,
+                    local
+                        .join("Programs")
+                        .join(dir)
+                        .join("resources")
+                        .join("app"),
+                ));
             }
-        }
-    } else {
-        let p = PathBuf::from("/opt/Cursor/resources/app");
-        if is_candidate(&p) {
-            return Some(p);
-        }
-        let p = PathBuf::from("/usr/share/cursor/resources/app");
-        if is_candidate(&p) {
-            return Some(p);
-        }
-        if let Ok(home) = env::var("HOME") {
-            let p = Path::new(&home)
-                .join(".local")
-                .join("share")
-                .join("cursor")
-                .join("resources")
-                .join("app");
-            if is_candidate(&p) {
-                return Some(p);
+        } else if cfg!(target_os = "macos") {
+            let app = Path::new(mac_app).join("Contents").join("Resources").join("app");
+            candidates.push((channel, Path::new("/Applications").join(&app)));
+            if let Ok(home) = env::var("HOME") {
+                candidates.push((channel, Path::new(&home).join("Applications").join(&app)));
+            }
+            if channel == Channel::Stable {
+                candidates.extend(caskroom_candidates().into_iter().map(|dir| (channel, dir.join(&app))));
+            }
+        } else {
+            candidates.push((channel, Path::new("/opt").join(opt).join("resources").join("app")));
+            candidates.push((channel, Path::new("/usr/share").join(dir).join("resources").join("app")));
+            candidates.push((channel, Path::new("/snap").join(dir).join("current").join("resources").join("app")));
+            if let Ok(home) = env::var("HOME") {
+                candidates.push((
+                    channel,
+                    Path::new(&home)
+                        .join(".local")
+                        .join("share")
+                        .join(dir)
+                        .join("resources")
+                        .join("app"),
+                ));
+            }
+            if channel == Channel::Stable {
+                candidates.extend(flatpak_candidates().into_iter().map(|p| (channel, p)));
+            }
+            if let Some(local) = &windows_local {
+                candidates.push((channel, local.join("Programs").join(dir).join("resources").join("app")));
             }
         }
     }
+    candidates
+}
 
-    None
+/// Whether this is Linux under WSL, where the Windows drives are mounted
+/// at `/mnt/<letter>`.
+fn is_wsl() -> bool {
+    fs::read_to_string("/proc/sys/kernel/osrelease").is_ok_and(|r| r.to_ascii_lowercase().contains("microsoft"))
+}
+
+/// Under WSL, the Windows user's `%LOCALAPPDATA%` through the `C:` drive,
+/// for the Windows user named like the Linux one (`$USER`). A different
+/// Windows user name needs `--app-path`.
+fn wsl_windows_local_app_data() -> Option<PathBuf> {
+    if cfg!(target_os = "windows") || cfg!(target_os = "macos") || !is_wsl() {
+        return None;
+    }
+    let user = env::var("USER").ok().filter(|u| !u.is_empty())?;
+    Some(Path::new("/mnt/c/Users").join(user).join("AppData").join("Local"))
+}
+
+/// Version directories of the `cursor` Homebrew cask, newest first, under
+/// `$HOMEBREW_PREFIX` or the default prefixes (Apple silicon, then Intel).
+/// The bundle in `/Applications` is usually a link to one of these.
+fn caskroom_candidates() -> Vec<PathBuf> {
+    let prefixes = env::var_os("HOMEBREW_PREFIX")
+        .filter(|p| !p.is_empty())
+        .map(PathBuf::from)
+        .into_iter()
+        .chain(["/opt/homebrew", "/usr/local"].map(PathBuf::from));
+    let mut candidates = Vec::new();
+    for prefix in prefixes {
+        let Ok(read) = fs::read_dir(prefix.join("Caskroom").join("cursor")) else { continue };
+        let mut versions: Vec<PathBuf> = read.flatten().map(|e| e.path()).collect();
+        versions.sort_by_cached_key(|p| p.file_name().and_then(|n| Version::parse(&n.to_string_lossy())));
+        candidates.extend(versions.into_iter().rev());
+    }
+    candidates
 }
 
-/// Find the Cursor CLI agent versions directory.
+/// Flathub id of the Cursor Flatpak.
+const FLATPAK_APP_ID: &str = "com.cursor.Cursor";
+
+/// `resources/app` inside the Cursor Flatpak, installed per user or
+/// system-wide. The app is unpacked either straight under `files` or, as
+/// extra data, under `files/extra`.
+fn flatpak_candidates() -> Vec<PathBuf> {
+    let user = env::var("HOME").ok().map(|home| Path::new(&home).join(".local").join("share").join("flatpak"));
+    let system = Some(PathBuf::from("/var/lib/flatpak"));
+    [user, system]
+        .into_iter()
+        .flatten()
+        .flat_map(|root| {
+            let files = root
+                .join("app")
+                .join(FLATPAK_APP_ID)
+                .join("current")
+                .join("active")
+                .join("files");
+            [files.join("extra"), files].map(|dir| dir.join("cursor").join("resources").join("app"))
+        })
+        .collect()
+}
+
+/// The `resources/app` for an `--app-path` of `dir`: `dir` itself, or the
+/// app inside an extracted AppImage root (`squashfs-root`).
+fn app_path_app(dir: &Path) -> Option<PathBuf> {
+    [
+        dir.to_path_buf(),
+        dir.join("resources").join("app"),
+        dir.join("usr").join("share").join("cursor").join("resources").join("app"),
+    ]
+    .into_iter()
+    .find(|p| p.join("product.json").is_file())
+}
+
+/// Environment variable naming the `resources/app` directory to use, for
+/// scripts that can't pass `--app-path` every time.
+pub const CURSOR_APP_ENV: &str = "NUPATCH_CURSOR_APP";
+
+/// Find the Cursor IDE installation directory: `$NUPATCH_CURSOR_APP` if it
+/// has a `product.json`, else the first candidate that does, with its
+/// channel. On macOS the directory is canonicalized, so an app linked into
+/// `/Applications` (a Homebrew cask) resolves to the real bundle and
+/// backups and checksums land on real files.
+fn detect_cursor_app(candidates: &[(Channel, PathBuf)]) -> Option<(Option<Channel>, PathBuf)> {
+    let (channel, app) = env::var_os(CURSOR_APP_ENV)
+        .filter(|dir| !dir.is_empty())
+        .map(|dir| (None, PathBuf::from(dir)))
+        .into_iter()
+        .chain(candidates.iter().map(|(c, p)| (Some(*c), p.clone())))
+        .find(|(_, p)| p.join("product.json").is_file())?;
+    if cfg!(target_os = "macos") {
+        return Some((channel, fs::canonicalize(&app).unwrap_or(app)));
+    }
+    Some((channel, app))
+}
+
+/// Find a `resources/app.asar` standing in for an unpacked `resources/app`.
+fn detect_packed_asar(candidates: &[(Channel, PathBuf)]) -> Option<PathBuf> {
+    candidates
+        .iter()
+        .map(|(_, p)| p.with_extension("asar"))
+        .find(|asar| asar.is_file())
+}
+
+/// Find the Cursor CLI agent versions directory. On Linux, the Flatpak's
+/// and Snap's own homes (`~/.var/app/<id>`, `~/snap/cursor/current`) are
+/// tried when `~/.cursor-agent` isn't there.
 fn detect_cli_agent_dir() -> Option<PathBuf> {
     if cfg!(target_os = "windows") {
         if let Some(local) = local_app_data() {
@@ -98,6 +284,18 @@ fn detect_cli_agent_dir() -> Option<PathBuf> {
         if p.is_dir() {
             return Some(p);
         }
+        let sandboxed = [
+            Path::new(&home).join(".var").join("app").join(FLATPAK_APP_ID),
+            Path::new(&home).join("snap").join("cursor").join("current"),
+        ];
+        if !cfg!(target_os = "macos")
+            && let Some(p) = sandboxed
+                .iter()
+                .map(|dir| dir.join(".cursor-agent").join("versions"))
+                .find(|p| p.is_dir())
+        {
+            return Some(p);
+        }
     }
 
     None
@@ -126,9 +324,111 @@ fn find_cli_index(cli_dir: &Path) -> Option<PathBuf> {
     }
 }
 
-/// Detect all Cursor-related paths on this system.
+/// Every CLI agent version's `index.js` under `cli_dir`, oldest version
+/// first (see `sort_cli_indexes`).
+pub fn cli_agent_indexes(cli_dir: &Path) -> Vec<PathBuf> {
+    cli_version_indexes(cli_dir).into_iter().filter(|p| p.is_file()).collect()
+}
+
+/// Where each version directory under `cli_dir` keeps its `index.js`,
+/// whether or not it's there (a broken or half-installed version), oldest
+/// version first.
+pub fn cli_version_indexes(cli_dir: &Path) -> Vec<PathBuf> {
+    let Ok(entries) = std::fs::read_dir(cli_dir) else {
+        return Vec::new();
+    };
+    let mut indexes: Vec<PathBuf> = entries
+        .filter_map(|e| e.ok())
+        .map(|e| e.path())
+        .filter(|p| p.is_dir())
+        .map(|p| p.join("index.js"))
+        .collect();
+    sort_cli_indexes(&mut indexes);
+    indexes
+}
+
+/// Sort CLI agent `index.js` paths oldest version first (see
+/// `cli_version_key`).
+pub fn sort_cli_indexes(indexes: &mut [PathBuf]) {
+    indexes.sort_by_cached_key(|p| cli_version_key(p));
+}
+
+/// Ordering key for a CLI agent `index.js`: its version directory name
+/// compared as a version (`2025.10.2-abc` after `2025.9.17-def`), then as
+/// a name.
+pub fn cli_version_key(index: &Path) -> (Option<Version>, Option<String>) {
+    let name = index.parent().and_then(Path::file_name).map(|n| n.to_string_lossy().into_owned());
+    (name.as_deref().and_then(Version::parse), name)
+}
+
+/// Which plan patches an agent file: by file name (`index.js` is the CLI
+/// agent, `main.js` the IDE agent), else by a `cursor-agent-exec` (IDE) or
+/// `cursor-agent` (CLI) directory in the path.
+pub fn agent_label(path: &Path) -> Option<&'static str> {
+    match path.file_name()?.to_str()? {
+        "index.js" => return Some("CLI"),
+        "main.js" => return Some("IDE"),
+        _ => {}
+    }
+    let dirs: Vec<_> = path.components().filter_map(|c| c.as_os_str().to_str()).collect();
+    if dirs.contains(&"cursor-agent-exec") {
+        Some("IDE")
+    } else if dirs.iter().any(|d| d.trim_start_matches('.') == "cursor-agent") {
+        Some("CLI")
+    } else {
+        None
+    }
+}
+
+/// Every `index.js` and `main.js` under `dir`, recursively, sorted.
+/// Symlinked directories aren't followed.
+pub fn find_agent_files(dir: &Path) -> std::io::Result<Vec<PathBuf>> {
+    let mut found = Vec::new();
+    let mut pending = vec![dir.to_path_buf()];
+    while let Some(dir) = pending.pop() {
+        for entry in std::fs::read_dir(&dir)? {
+            let entry = entry?;
+            let path = entry.path();
+            let kind = entry.file_type()?;
+            if kind.is_dir() {
+                pending.push(path);
+            } else if matches!(path.file_name().and_then(|n| n.to_str()), Some("index.js" | "main.js"))
+                && path.is_file()
+            {
+                found.push(path);
+            }
+        }
+    }
+    found.sort();
+    Ok(found)
+}
+
+/// Detect all Cursor-related paths on this system. With `--app-path`, that
+/// directory (or the app in it, for an extracted AppImage) is `cursor_app`
+/// if it has a `product.json`, and nothing else is looked for.
+/// `$NUPATCH_CURSOR_APP` comes next, then the usual install locations
+/// (stable before Insiders, or only `--channel`'s).
 pub fn detect_paths() -> CursorPaths {
-    let cursor_app = detect_cursor_app();
+    let _span = tracing::info_span!("detect").entered();
+    let (cursor_app, packed_asar, channel, other_channel) = match app_path() {
+        Some(dir) => (app_path_app(dir), None, None, None),
+        None => {
+            let candidates = cursor_app_candidates();
+            let (channel, cursor_app) = detect_cursor_app(&candidates).unzip();
+            let channel = channel.flatten();
+            let packed_asar = match cursor_app {
+                Some(_) => None,
+                None => detect_packed_asar(&candidates),
+            };
+            let other_channel = channel.and_then(|picked| {
+                candidates
+                    .iter()
+                    .find(|(c, p)| *c != picked && p.join("product.json").is_file())
+                    .map(|(c, _)| *c)
+            });
+            (cursor_app, packed_asar, channel, other_channel)
+        }
+    };
     let cli_agent_dir = detect_cli_agent_dir();
     let cli_index = cli_agent_dir
         .as_ref()
@@ -165,5 +465,88 @@ pub fn detect_paths() -> CursorPaths {
         ide_main,
         ehp,
         product_json,
+        packed_asar,
+        channel,
+        other_channel,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::TempDir;
+    use std::sync::Mutex;
+
+    /// Tests that set environment variables (`HOME`, ...) take this, so
+    /// they don't see each other's values.
+    static ENV_LOCK: Mutex<()> = Mutex::new(());
+
+    /// Run `f` with `vars` set (or removed, for `None`), restoring them after.
+    fn with_env(vars: &[(&str, Option<&Path>)], f: impl FnOnce()) {
+        let _guard = ENV_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+        let saved: Vec<_> = vars.iter().map(|(k, _)| (*k, env::var_os(k))).collect();
+        let set = |k: &str, v: Option<&std::ffi::OsStr>| match v {
+            // SAFETY: ENV_LOCK serializes every test that touches the environment.
+            Some(v) => unsafe { env::set_var(k, v) },
+            None => unsafe { env::remove_var(k) },
+        };
+        for (k, v) in vars {
+            set(k, v.map(Path::as_os_str));
+        }
+        let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(f));
+        for (k, v) in &saved {
+            set(k, v.as_deref());
+        }
+        if let Err(panic) = result {
+            std::panic::resume_unwind(panic);
+        }
+    }
+
+    #[test]
+    #[cfg(target_os = "linux")]
+    fn flatpak_layout_under_home() {
+        let home = TempDir::new("flatpak");
+        let app = home.file(concat!(
+            ".local/share/flatpak/app/com.cursor.Cursor/current/active/",
+            "files/extra/cursor/resources/app/product.json",
+        ));
+        let app = app.parent().unwrap();
+        let agents = home.0.join(".var/app/com.cursor.Cursor/.cursor-agent/versions");
+        fs::create_dir_all(&agents).unwrap();
+
+        with_env(&[("HOME", Some(&home.0))], || {
+            assert!(flatpak_candidates().iter().any(|p| p == app));
+            assert!(cursor_app_candidates().iter().any(|(c, p)| *c == Channel::Stable && p == app));
+            assert_eq!(detect_cli_agent_dir(), Some(agents.clone()));
+        });
+    }
+
+    #[test]
+    fn app_path_finds_app_in_extracted_appimage() {
+        let root = TempDir::new("squashfs-root");
+        let product = root.file("usr/share/cursor/resources/app/product.json");
+        let app = product.parent().unwrap();
+
+        assert_eq!(app_path_app(&root.0).as_deref(), Some(app));
+        assert_eq!(app_path_app(app).as_deref(), Some(app));
+        assert_eq!(app_path_app(&root.0.join("usr")), None);
+    }
+
+    #[test]
+    fn cursor_app_env_picks_the_install() {
+        let home = TempDir::new("cursor-app-env");
+        let app = home.0.join("custom").join("resources").join("app");
+        let ide_main = home.file("custom/resources/app/extensions/cursor-agent-exec/dist/main.js");
+        let ehp = home.file("custom/resources/app/out/vs/workbench/api/node/extensionHostProcess.js");
+        let product_json = home.file("custom/resources/app/product.json");
+
+        with_env(&[(CURSOR_APP_ENV, Some(&app)), ("HOME", Some(&home.0))], || {
+            let paths = detect_paths();
+            assert_eq!(paths.cursor_app, Some(app.clone()));
+            assert_eq!(paths.ide_main, Some(ide_main.clone()));
+            assert_eq!(paths.ehp, Some(ehp.clone()));
+            assert_eq!(paths.product_json, Some(product_json.clone()));
+            assert_eq!(paths.channel, None);
+        });
     }
 }
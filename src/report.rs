@@ -0,0 +1,349 @@
+//! Run reports: `patch --report` / `verify --report` save the results of a
+//! run together with the resulting status, and `status --from-report`
+//! renders a saved report without looking at the live install.
+//!
+//! A report is JSON. Its `status` object is exactly what `status --json`
+//! prints, so the two stay one schema:
+//!
+//! ```json
+//! {
+//!   "report": 1,
+//!   "nupatch": "1.0.0",
+//!   "command": "patch",
+//!   "created": 1760000000,
+//!   "status": { "state": "fully_patched", "cursor": {...}, "cli": {...}, ... },
+//!   "results": [ { "component": "CLI", "success": true, "steps": [...] } ],
+//!   "verify": { "all_match": true, "entries": [...] }
+//! }
+//! ```
+//!
+//! `results` and `verify` are present for the commands that produce them.
+
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use serde_json::{Value, json};
+
+use crate::core::{
+    ComponentStatus, CursorVersion, FailureKind, IntegrityStatus, PatchResult, PatchStatus, StepResult,
+};
+use crate::error::{Error, Result};
+use crate::integrity::{VerifyEntry, VerifyResult};
+
+/// Version of the report layout, bumped on incompatible changes.
+const FORMAT: u64 = 1;
+
+/// A saved run.
+pub struct Report {
+    /// nupatch version that wrote the report.
+    pub nupatch: String,
+    /// The command that was run (`patch`, `verify`).
+    pub command: String,
+    pub created: SystemTime,
+    pub status: PatchStatus,
+    /// `resources/app.asar` of a packed install, as in `CursorPaths`.
+    pub packed_asar: Option<PathBuf>,
+    /// Per-component results, labelled (`CLI`, `IDE`, `Integrity`).
+    pub results: Vec<(String, PatchResult)>,
+    pub verify: Option<VerifyResult>,
+}
+
+impl Report {
+    /// A report of `command` created now.
+    pub fn new(command: &str, status: PatchStatus, packed_asar: Option<PathBuf>) -> Self {
+        Self {
+            nupatch: env!("CARGO_PKG_VERSION").to_string(),
+            command: command.to_string(),
+            created: SystemTime::now(),
+            status,
+            packed_asar,
+            results: Vec::new(),
+            verify: None,
+        }
+    }
+
+    pub fn write(&self, path: &Path) -> Result<()> {
+        let mut out = json!({
+            "report": FORMAT,
+            "nupatch": self.nupatch,
+            "command": self.command,
+            "created": secs(self.created),
+            "status": status_json(&self.status, self.packed_asar.as_deref()),
+        });
+        if !self.results.is_empty() {
+            out["results"] = self.results.iter().map(|(label, r)| result_json(label, r)).collect();
+        }
+        if let Some(verify) = &self.verify {
+            out["verify"] = verify_json(verify);
+        }
+        let text = serde_json::to_string_pretty(&out)?;
+        fs::write(path, text).map_err(|e| Error::io("write report", path, e))
+    }
+
+    pub fn read(path: &Path) -> Result<Self> {
+        let text = fs::read_to_string(path).map_err(|e| Error::io("read report", path, e))?;
+        let value: Value = serde_json::from_str(&text)?;
+        let bad = |what: &str| Error::Report(format!("{}: {what}", path.display()));
+        match value["report"].as_u64() {
+            Some(FORMAT) => {}
+            Some(n) => return Err(bad(&format!("unsupported report format {n}"))),
+            None => return Err(bad("not a nupatch report")),
+        }
+        let status = &value["status"];
+        let results = match value["results"].as_array() {
+            Some(results) => results.iter().map(result_from_json).collect::<Option<Vec<_>>>(),
+            None => Some(Vec::new()),
+        };
+        Ok(Self {
+            nupatch: value["nupatch"].as_str().unwrap_or("unknown").to_string(),
+            command: value["command"].as_str().unwrap_or("unknown").to_string(),
+            created: from_secs(&value["created"]).ok_or_else(|| bad("missing creation time"))?,
+            status: status_from_json(status).ok_or_else(|| bad("malformed status"))?,
+            packed_asar: status["cursor"]["packed_asar"].as_str().map(PathBuf::from),
+            results: results.ok_or_else(|| bad("malformed results"))?,
+            verify: match &value["verify"] {
+                Value::Null => None,
+                v => Some(verify_from_json(v).ok_or_else(|| bad("malformed verify results"))?),
+            },
+        })
+    }
+}
+
+fn secs(t: SystemTime) -> Option<u64> {
+    t.duration_since(UNIX_EPOCH).ok().map(|d| d.as_secs())
+}
+
+fn from_secs(v: &Value) -> Option<SystemTime> {
+    v.as_u64().map(|s| UNIX_EPOCH + Duration::from_secs(s))
+}
+
+// ---------------------------------------------------------------------------
+//  Status
+// ---------------------------------------------------------------------------
+
+fn component_json(comp: &ComponentStatus) -> Value {
+    let mut patches: Vec<(&String, &bool)> = comp.patches.iter().collect();
+    patches.sort_by_key(|(name, _)| name.as_str());
+    let patches: serde_json::Map<String, Value> = patches
+        .into_iter()
+        .map(|(k, v)| (k.clone(), Value::Bool(*v)))
+        .collect();
+    json!({
+        "exists": comp.exists,
+        "path": comp.path,
+        "backup_exists": comp.backup_exists,
+        "backup_pristine": comp.backup_pristine,
+        "fully_patched": comp.fully_patched(),
+        "patches": patches,
+        "skipped": comp.skipped,
+        "last_patched": comp.last_patched.and_then(secs),
+        "recorded_hash_matches": comp.recorded_hash_matches,
+        "newer_unpatched": comp.newer_unpatched,
+    })
+}
+
+fn component_from_json(v: &Value) -> Option<ComponentStatus> {
+    let patches = v["patches"]
+        .as_object()?
+        .iter()
+        .map(|(k, v)| Some((k.clone(), v.as_bool()?)))
+        .collect::<Option<HashMap<_, _>>>()?;
+    let skipped = v["skipped"]
+        .as_array()?
+        .iter()
+        .map(|s| s.as_str().map(str::to_string))
+        .collect::<Option<Vec<_>>>()?;
+    Some(ComponentStatus {
+        path: v["path"].as_str().map(str::to_string),
+        exists: v["exists"].as_bool()?,
+        backup_exists: v["backup_exists"].as_bool()?,
+        backup_pristine: v["backup_pristine"].as_bool(),
+        patches,
+        skipped,
+        last_patched: from_secs(&v["last_patched"]),
+        recorded_hash_matches: v["recorded_hash_matches"].as_bool(),
+        newer_unpatched: v["newer_unpatched"].as_str().map(str::to_string),
+    })
+}
+
+/// The `status --json` object for `st`.
+pub fn status_json(st: &PatchStatus, packed_asar: Option<&Path>) -> Value {
+    json!({
+        "state": st.overall().as_str(),
+        "cursor": {
+            "version": st.cursor.version,
+            "commit": st.cursor.commit,
+            "packed_asar": packed_asar,
+        },
+        "cli": component_json(&st.cli),
+        "ide": component_json(&st.ide),
+        "integrity": {
+            "ehp_hash_matches": st.integrity.ehp_hash_matches,
+            "product_checksums_match": st.integrity.product_checksums_match,
+            "product_json_found": st.integrity.product_json_found,
+        },
+    })
+}
+
+fn status_from_json(v: &Value) -> Option<PatchStatus> {
+    let integrity = &v["integrity"];
+    let string = |v: &Value| v.as_str().map(str::to_string);
+    Some(PatchStatus {
+        cursor: CursorVersion {
+            version: string(&v["cursor"]["version"]),
+            commit: string(&v["cursor"]["commit"]),
+        },
+        cli: component_from_json(&v["cli"])?,
+        ide: component_from_json(&v["ide"])?,
+        integrity: IntegrityStatus {
+            ehp_hash_matches: integrity["ehp_hash_matches"].as_bool(),
+            product_checksums_match: integrity["product_checksums_match"].as_bool(),
+            product_json_found: integrity["product_json_found"].as_bool().unwrap_or(false),
+        },
+    })
+}
+
+// ---------------------------------------------------------------------------
+//  Results
+// ---------------------------------------------------------------------------
+
+const KINDS: &[(FailureKind, &str)] = &[
+    (FailureKind::Io, "io"),
+    (FailureKind::Discovery, "discovery"),
+    (FailureKind::PatternNotFound, "pattern_not_found"),
+    (FailureKind::Validation, "validation"),
+    (FailureKind::InvalidFile, "invalid_file"),
+];
+
+/// JSON for a labelled result, as in a report's `results`.
+pub fn result_json(label: &str, result: &PatchResult) -> Value {
+    let steps: Vec<Value> = result
+        .steps
+        .iter()
+        .map(|s| {
+            json!({
+                "name": s.name,
+                "ok": s.ok,
+                "skipped": s.skipped,
+                "message": s.message,
+                "detail": s.detail,
+                "user_rule": s.user_rule,
+                "kind": s.kind.and_then(|k| KINDS.iter().find(|(kind, _)| *kind == k)).map(|(_, name)| name),
+            })
+        })
+        .collect();
+    json!({ "component": label, "success": result.success, "steps": steps })
+}
+
+fn result_from_json(v: &Value) -> Option<(String, PatchResult)> {
+    let steps = v["steps"]
+        .as_array()?
+        .iter()
+        .map(|s| {
+            Some(StepResult {
+                name: s["name"].as_str()?.to_string().into(),
+                ok: s["ok"].as_bool()?,
+                message: s["message"].as_str()?.to_string(),
+                skipped: s["skipped"].as_bool()?,
+                detail: s["detail"].as_str().unwrap_or_default().to_string(),
+                user_rule: s["user_rule"].as_bool().unwrap_or(false),
+                kind: s["kind"]
+                    .as_str()
+                    .and_then(|k| KINDS.iter().find(|(_, name)| *name == k))
+                    .map(|&(kind, _)| kind),
+                trace: None,
+                edit: None,
+            })
+        })
+        .collect::<Option<Vec<_>>>()?;
+    let result = PatchResult { success: v["success"].as_bool()?, steps };
+    Some((v["component"].as_str()?.to_string(), result))
+}
+
+// ---------------------------------------------------------------------------
+//  Verify
+// ---------------------------------------------------------------------------
+
+/// Label for a verify entry, as in `verify --json`.
+pub fn verify_status_label(entry: &VerifyEntry) -> &'static str {
+    if entry.missing {
+        "missing"
+    } else if entry.matches {
+        "match"
+    } else if entry.stale {
+        "stale"
+    } else {
+        "mismatch"
+    }
+}
+
+/// The `verify --json` object for `result`. Hashes are always full-length
+/// here; truncation only happens in the table view. With no checksums to
+/// verify, `all_match` is `null` and `checksums_missing` is set, rather
+/// than an empty list that reads as success.
+pub fn verify_json(result: &VerifyResult) -> Value {
+    let entries: Vec<Value> = result
+        .entries
+        .iter()
+        .map(|e| {
+            json!({
+                "rel_path": e.rel_path,
+                "expected": e.expected,
+                "actual": e.actual,
+                "status": verify_status_label(e),
+                "matches": e.matches,
+                "missing": e.missing,
+                "stale": e.stale,
+            })
+        })
+        .collect();
+    let clashes: Vec<Value> = result.case_clashes.iter().map(|(a, b)| json!([a, b])).collect();
+    let missing = entries.is_empty();
+    let mut out = json!({
+        "all_match": if missing { Value::Null } else { result.all_match.into() },
+        "checksums_missing": missing,
+        "entries": entries,
+        "case_clashes": clashes,
+        "backslash_keys": result.backslash_keys,
+    });
+    if missing {
+        out["warning"] = "product.json checksums section is empty or missing; integrity cannot be verified".into();
+    }
+    out
+}
+
+fn verify_from_json(v: &Value) -> Option<VerifyResult> {
+    let entries = v["entries"]
+        .as_array()?
+        .iter()
+        .map(|e| {
+            Some(VerifyEntry {
+                rel_path: e["rel_path"].as_str()?.to_string(),
+                expected: e["expected"].as_str()?.to_string(),
+                actual: e["actual"].as_str()?.to_string(),
+                matches: e["matches"].as_bool()?,
+                missing: e["missing"].as_bool()?,
+                stale: e["stale"].as_bool()?,
+            })
+        })
+        .collect::<Option<Vec<_>>>()?;
+    let case_clashes = match v["case_clashes"].as_array() {
+        Some(clashes) => clashes
+            .iter()
+            .map(|c| Some((c[0].as_str()?.to_string(), c[1].as_str()?.to_string())))
+            .collect::<Option<Vec<_>>>()?,
+        None => Vec::new(),
+    };
+    let backslash_keys = match v["backslash_keys"].as_array() {
+        Some(keys) => keys.iter().map(|k| k.as_str().map(str::to_string)).collect::<Option<Vec<_>>>()?,
+        None => Vec::new(),
+    };
+    // `null` when there were no checksums (see `verify_json`).
+    let all_match = match &v["all_match"] {
+        Value::Null if entries.is_empty() => true,
+        all_match => all_match.as_bool()?,
+    };
+    Some(VerifyResult { entries, all_match, case_clashes, backslash_keys })
+}
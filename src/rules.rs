@@ -0,0 +1,188 @@
+//! User-supplied patch rules loaded from an external JSON manifest.
+//!
+//! A manifest lists extra steps for the built-in CLI/IDE plans. A rule whose
+//! `name` matches a built-in step (see `nupatch rules`) replaces that step
+//! in place; other rules are appended in order:
+//!
+//! ```json
+//! {
+//!   "rules": [
+//!     {
+//!       "name": "my-fix",
+//!       "target": "ide",
+//!       "find": "process.env.SHELL||\"/bin/sh\"",
+//!       "regex": false,
+//!       "mode": "replace",
+//!       "replace": "process.env.SHELL||\"nu\""
+//!     }
+//!   ]
+//! }
+//! ```
+//!
+//! `replace` is a template: `{hint_var}`, `{enum_var}`, `{lazy_exec}`,
+//! `{naive_exec}`, `{cmd_exists_fn}` and `{find_exec_call}` expand to the
+//! names discovered in the file being patched, as do `{opts_var}`,
+//! `{shell_opts_var}` and `{ps_path_fn}`. Any other braces, `{shell}`
+//! included, are kept as written. `nupatch rules --export` prints the
+//! built-in steps in this format, with the shell they wire in (`nu`)
+//! filled in.
+//!
+//! A simpler patch script (`--patch-script`) is a JSON array of literal
+//! `{"find": ..., "replace": ...}` pairs; see `load_patch_script`.
+
+use std::fs;
+use std::path::Path;
+
+use color_eyre::eyre::{self, WrapErr, bail, eyre};
+use serde_json::Value;
+
+/// Which agent plan a rule attaches to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RuleTarget {
+    Cli,
+    Ide,
+    Both,
+}
+
+impl RuleTarget {
+    pub fn applies_to(self, label: &str) -> bool {
+        match self {
+            Self::Cli => label == "CLI",
+            Self::Ide => label == "IDE",
+            Self::Both => true,
+        }
+    }
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::Cli => "cli",
+            Self::Ide => "ide",
+            Self::Both => "both",
+        }
+    }
+}
+
+/// How the expanded template is applied at the match.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RuleMode {
+    /// Replace the matched text.
+    Replace,
+    /// Insert before the match, keeping it.
+    InsertBefore,
+    /// Insert after the match, keeping it.
+    InsertAfter,
+}
+
+impl RuleMode {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::Replace => "replace",
+            Self::InsertBefore => "insert_before",
+            Self::InsertAfter => "insert_after",
+        }
+    }
+}
+
+/// A single user-defined patch step.
+#[derive(Debug, Clone)]
+pub struct UserRule {
+    pub name: String,
+    pub target: RuleTarget,
+    /// Text to locate; a regex when `regex` is set, otherwise a literal.
+    pub find: String,
+    pub regex: bool,
+    pub mode: RuleMode,
+    /// Replacement / insertion template.
+    pub replace: String,
+    /// Require `find` to match exactly once (patch-script steps).
+    pub exact: bool,
+    /// Expand `{placeholder}`s in `find` and `replace`. Manifest rules are
+    /// templates; patch-script steps are literal.
+    pub templated: bool,
+}
+
+fn str_field<'a>(rule: &'a Value, key: &str, idx: usize) -> eyre::Result<&'a str> {
+    rule.get(key)
+        .and_then(Value::as_str)
+        .ok_or_else(|| eyre!("rule #{idx}: missing string field `{key}`"))
+}
+
+fn parse_rule(rule: &Value, idx: usize) -> eyre::Result<UserRule> {
+    let name = str_field(rule, "name", idx)?.to_string();
+    let target = parse_target(rule, &format!("rule '{name}'"))?;
+    let mode = match rule.get("mode").and_then(Value::as_str).unwrap_or("replace") {
+        "replace" => RuleMode::Replace,
+        "insert_before" => RuleMode::InsertBefore,
+        "insert_after" => RuleMode::InsertAfter,
+        other => bail!(
+            "rule '{name}': unknown mode `{other}` (expected replace, insert_before, or insert_after)"
+        ),
+    };
+    Ok(UserRule {
+        find: str_field(rule, "find", idx)?.to_string(),
+        regex: rule.get("regex").and_then(Value::as_bool).unwrap_or(false),
+        replace: str_field(rule, "replace", idx)?.to_string(),
+        exact: false,
+        templated: true,
+        name,
+        target,
+        mode,
+    })
+}
+
+fn parse_target(step: &Value, name: &str) -> eyre::Result<RuleTarget> {
+    match step.get("target").and_then(Value::as_str).unwrap_or("both") {
+        "cli" => Ok(RuleTarget::Cli),
+        "ide" => Ok(RuleTarget::Ide),
+        "both" => Ok(RuleTarget::Both),
+        other => bail!("{name}: unknown target `{other}` (expected cli, ide, or both)"),
+    }
+}
+
+/// Load and parse a rules manifest.
+pub fn load_rules(path: &Path) -> eyre::Result<Vec<UserRule>> {
+    let text = fs::read_to_string(path)
+        .wrap_err_with(|| format!("failed to read {}", path.display()))?;
+    let manifest: Value = serde_json::from_str(&text)
+        .wrap_err_with(|| format!("failed to parse {}", path.display()))?;
+    let rules = manifest
+        .get("rules")
+        .and_then(Value::as_array)
+        .ok_or_else(|| eyre!("{}: expected a top-level `rules` array", path.display()))?;
+    rules
+        .iter()
+        .enumerate()
+        .map(|(i, r)| parse_rule(r, i + 1))
+        .collect()
+}
+
+/// Load a patch script: a JSON array of literal `{"find", "replace"}` pairs
+/// (optionally with `target`), applied in order after the built-in patches.
+/// Each `find` must match exactly once, and neither side is a template:
+/// `{enum_var}` in a script is just text.
+pub fn load_patch_script(path: &Path) -> eyre::Result<Vec<UserRule>> {
+    let text = fs::read_to_string(path)
+        .wrap_err_with(|| format!("failed to read {}", path.display()))?;
+    let script: Value = serde_json::from_str(&text)
+        .wrap_err_with(|| format!("failed to parse {}", path.display()))?;
+    let steps = script
+        .as_array()
+        .ok_or_else(|| eyre!("{}: expected a JSON array of find/replace pairs", path.display()))?;
+    steps
+        .iter()
+        .enumerate()
+        .map(|(i, step)| {
+            let name = format!("script #{}", i + 1);
+            Ok(UserRule {
+                target: parse_target(step, &name)?,
+                find: str_field(step, "find", i + 1)?.to_string(),
+                regex: false,
+                mode: RuleMode::Replace,
+                replace: str_field(step, "replace", i + 1)?.to_string(),
+                exact: true,
+                templated: false,
+                name,
+            })
+        })
+        .collect()
+}
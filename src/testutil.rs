@@ -0,0 +1,37 @@
+//! Fixtures shared by the unit tests.
+
+use std::env;
+use std::fs;
+use std::path::PathBuf;
+
+/// A directory under the system temp dir, removed on drop.
+pub struct TempDir(pub PathBuf);
+
+impl TempDir {
+    pub fn new(name: &str) -> Self {
+        let dir = env::temp_dir().join(format!("nupatch-test-{name}-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        // macOS's temp dir is behind a link; detection canonicalizes there.
+        Self(fs::canonicalize(&dir).unwrap())
+    }
+
+    /// Create `rel` (and its parents) as an empty file.
+    pub fn file(&self, rel: &str) -> PathBuf {
+        self.write(rel, "")
+    }
+
+    /// Create `rel` (and its parents) holding `contents`.
+    pub fn write(&self, rel: &str, contents: impl AsRef<[u8]>) -> PathBuf {
+        let path = self.0.join(rel);
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(&path, contents).unwrap();
+        path
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.0);
+    }
+}
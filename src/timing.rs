@@ -0,0 +1,106 @@
+//! `--trace`: per-phase timings from `tracing` spans.
+//!
+//! The major phases (detection, read, discovery, each patch step, write,
+//! integrity update, each verified file) are wrapped in `tracing` spans.
+//! Without `--trace` no subscriber is installed, so the spans are inert. With
+//! it, `TimingLayer` records how long each span was open, and `take` hands
+//! them to `cli::display_timings` once the command finishes.
+
+use std::fmt::Write as _;
+use std::sync::{Mutex, OnceLock};
+use std::time::{Duration, Instant};
+
+use tracing::field::{Field, Visit};
+use tracing::span::{Attributes, Id};
+use tracing::Subscriber;
+use tracing_subscriber::layer::{Context, Layer, SubscriberExt};
+use tracing_subscriber::registry::LookupSpan;
+use tracing_subscriber::util::SubscriberInitExt;
+
+/// A closed span: its order of creation, nesting depth, label and how long
+/// it was open.
+struct Timing {
+    order: usize,
+    depth: usize,
+    label: String,
+    elapsed: Duration,
+}
+
+static TIMINGS: OnceLock<Mutex<Vec<Timing>>> = OnceLock::new();
+
+/// Stored in each span's extensions until it closes.
+struct Started {
+    order: usize,
+    depth: usize,
+    label: String,
+    at: Instant,
+}
+
+/// `name{field=value ...}` for a span's fields.
+struct Label(String);
+
+impl Visit for Label {
+    fn record_str(&mut self, field: &Field, value: &str) {
+        let _ = write!(self.0, " {}={value}", field.name());
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn std::fmt::Debug) {
+        let _ = write!(self.0, " {}={value:?}", field.name());
+    }
+}
+
+struct TimingLayer {
+    next: std::sync::atomic::AtomicUsize,
+}
+
+impl<S: Subscriber + for<'a> LookupSpan<'a>> Layer<S> for TimingLayer {
+    fn on_new_span(&self, attrs: &Attributes<'_>, id: &Id, ctx: Context<'_, S>) {
+        let Some(span) = ctx.span(id) else {
+            return;
+        };
+        let mut label = Label(attrs.metadata().name().to_string());
+        attrs.record(&mut label);
+        let started = Started {
+            order: self.next.fetch_add(1, std::sync::atomic::Ordering::Relaxed),
+            depth: span.scope().skip(1).count(),
+            label: label.0,
+            at: Instant::now(),
+        };
+        span.extensions_mut().insert(started);
+    }
+
+    fn on_close(&self, id: Id, ctx: Context<'_, S>) {
+        let Some(span) = ctx.span(&id) else {
+            return;
+        };
+        if let Some(s) = span.extensions_mut().remove::<Started>()
+            && let Some(timings) = TIMINGS.get()
+        {
+            timings.lock().unwrap_or_else(|e| e.into_inner()).push(Timing {
+                order: s.order,
+                depth: s.depth,
+                label: s.label,
+                elapsed: s.at.elapsed(),
+            });
+        }
+    }
+}
+
+/// Start recording span timings for `--trace`.
+pub fn install() {
+    if TIMINGS.set(Mutex::new(Vec::new())).is_ok() {
+        let layer = TimingLayer { next: 0.into() };
+        let _ = tracing_subscriber::registry().with(layer).try_init();
+    }
+}
+
+/// Take the recorded timings as `(depth, label, elapsed)`, in the order
+/// the phases started. Empty unless `install` was called.
+pub fn take() -> Vec<(usize, String, Duration)> {
+    let Some(timings) = TIMINGS.get() else {
+        return Vec::new();
+    };
+    let mut timings = std::mem::take(&mut *timings.lock().unwrap_or_else(|e| e.into_inner()));
+    timings.sort_by_key(|t| t.order);
+    timings.into_iter().map(|t| (t.depth, t.label, t.elapsed)).collect()
+}
@@ -1,22 +1,79 @@
 //! Shared utilities.
 
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::{self, Read};
+use std::path::Path;
+use std::sync::{LazyLock, Mutex};
+use std::thread;
+use std::time::Duration;
+
 use fancy_regex::{Regex, RegexBuilder};
 
-/// Build a Regex with a generous backtrack limit (fallible).
-/// Use for dynamic patterns that interpolate runtime variables.
+/// Backtrack limit for patterns that don't know what they'll search.
 /// Cursor's minified JS files are large enough to exceed fancy_regex's
 /// default 1M limit on patterns with look-around.
+const BACKTRACK_LIMIT: usize = 10_000_000;
+/// Bounds for `backtrack_limit_for`: never tighter than fancy_regex's own
+/// default, never so loose a runaway pattern spins for minutes.
+const BACKTRACK_FLOOR: usize = 1_000_000;
+const BACKTRACK_CEILING: usize = 200_000_000;
+/// Backtracking steps allowed per byte searched.
+const BACKTRACK_PER_BYTE: usize = 4;
+
+/// Backtrack limit scaled to a `len`-byte input: small inputs stay tightly
+/// bounded, so a runaway pattern fails fast, while huge files get room in
+/// proportion to their size.
+pub fn backtrack_limit_for(len: usize) -> usize {
+    len.saturating_mul(BACKTRACK_PER_BYTE).clamp(BACKTRACK_FLOOR, BACKTRACK_CEILING)
+}
+
+/// Build a Regex with a generous backtrack limit (fallible).
+/// Use for dynamic patterns that interpolate runtime variables.
+///
+/// Compiled regexes are cached for the rest of the run, keyed by the full
+/// pattern: files from the same build share their minified names, so a
+/// batch recompiles nothing after the first file.
 pub fn re(pattern: &str) -> Result<Regex, fancy_regex::Error> {
-    RegexBuilder::new(pattern)
-        .backtrack_limit(10_000_000)
-        .build()
+    cached(pattern, BACKTRACK_LIMIT)
+}
+
+/// `re` with the backtrack limit scaled to the `len` bytes it will search
+/// (`backtrack_limit_for`).
+pub fn re_for(pattern: &str, len: usize) -> Result<Regex, fancy_regex::Error> {
+    cached(pattern, backtrack_limit_for(len))
+}
+
+fn cached(pattern: &str, limit: usize) -> Result<Regex, fancy_regex::Error> {
+    static CACHE: LazyLock<Mutex<HashMap<(String, usize), Regex>>> = LazyLock::new(Default::default);
+    let key = (pattern.to_string(), limit);
+    if let Some(cached) = CACHE.lock().unwrap_or_else(|e| e.into_inner()).get(&key) {
+        return Ok(cached.clone());
+    }
+    let compiled = build(pattern, limit)?;
+    CACHE.lock().unwrap_or_else(|e| e.into_inner()).insert(key, compiled.clone());
+    Ok(compiled)
+}
+
+fn build(pattern: &str, limit: usize) -> Result<Regex, fancy_regex::Error> {
+    RegexBuilder::new(pattern).backtrack_limit(limit).build()
+}
+
+/// Whether `e` is a search giving up at its backtrack limit (rather than
+/// finding no match).
+pub fn is_backtrack_limit(e: &fancy_regex::Error) -> bool {
+    matches!(
+        e,
+        fancy_regex::Error::RuntimeError(fancy_regex::RuntimeError::BacktrackLimitExceeded)
+    )
 }
 
 /// Build a Regex with a generous backtrack limit (panics on invalid pattern).
-/// Only for compile-time constant patterns used in `lazy_re!`.
+/// Only for compile-time constant patterns used in `lazy_re!`, which caches
+/// them itself.
 #[doc(hidden)]
 pub fn re_unchecked(pattern: &str) -> Regex {
-    re(pattern).expect("invalid constant regex pattern")
+    build(pattern, BACKTRACK_LIMIT).expect("invalid constant regex pattern")
 }
 
 /// Compile a regex once and cache it in a `LazyLock` static.
@@ -29,4 +86,102 @@ macro_rules! lazy_re {
         &*RE
     }};
 }
-pub(crate) use lazy_re;
\ No newline at end of file
+pub(crate) use lazy_re;
+
+/// `fs::read_to_string`, except that invalid UTF-8 comes back as an
+/// `InvalidData` error wrapping the `FromUtf8Error`, so the caller can say
+/// where (see `Error::read_text`).
+pub fn read_text(path: &Path) -> io::Result<String> {
+    String::from_utf8(fs::read(path)?).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+}
+
+/// Bytes read per window by `scan_file`.
+const SCAN_CHUNK: usize = 256 * 1024;
+
+/// Stream the text of `path` through `f` in overlapping windows, without
+/// holding the whole file in memory. Each window repeats the last `overlap`
+/// bytes of the previous one, so anything up to `overlap + 1` bytes long is
+/// seen whole in at least one window. `f` returns true to stop early.
+/// Invalid UTF-8 is an error, as with `fs::read_to_string`.
+pub fn scan_file(path: &Path, overlap: usize, mut f: impl FnMut(&str) -> bool) -> io::Result<()> {
+    let mut file = File::open(path)?;
+    let mut buf: Vec<u8> = Vec::with_capacity(SCAN_CHUNK + overlap);
+    let mut chunk = vec![0; SCAN_CHUNK];
+    loop {
+        let n = file.read(&mut chunk)?;
+        buf.extend_from_slice(&chunk[..n]);
+        // A multi-byte char split across reads waits for the next one.
+        let valid = match std::str::from_utf8(&buf) {
+            Ok(text) => text.len(),
+            Err(e) if e.error_len().is_none() && n > 0 => e.valid_up_to(),
+            Err(e) => return Err(io::Error::new(io::ErrorKind::InvalidData, e)),
+        };
+        let text = std::str::from_utf8(&buf[..valid]).expect("checked above");
+        if f(text) || n == 0 {
+            return Ok(());
+        }
+        let mut keep = valid.saturating_sub(overlap);
+        while !text.is_char_boundary(keep) {
+            keep -= 1;
+        }
+        buf.drain(..keep);
+    }
+}
+
+/// Which of `needles` occur in the file at `path`, searched by `scan_file`
+/// rather than reading it whole.
+pub fn contains_in_file(path: &Path, needles: &[&str]) -> io::Result<Vec<bool>> {
+    let mut found = vec![false; needles.len()];
+    let overlap = needles.iter().map(|n| n.len()).max().unwrap_or(0).saturating_sub(1);
+    scan_file(path, overlap, |text| {
+        for (hit, needle) in found.iter_mut().zip(needles) {
+            *hit = *hit || text.contains(needle);
+        }
+        found.iter().all(|&hit| hit)
+    })?;
+    Ok(found)
+}
+
+/// Attempts made by `write_retrying` before giving up.
+const WRITE_ATTEMPTS: u32 = 3;
+
+/// Whether a write error is likely to clear on its own: another process
+/// (antivirus, sync client, indexer) briefly holding the file.
+fn is_transient(e: &io::Error) -> bool {
+    // ERROR_ACCESS_DENIED, ERROR_SHARING_VIOLATION, ERROR_LOCK_VIOLATION.
+    // Access denied is retried on Windows only, where a pending scan
+    // reports it; on Unix it's a real permission problem.
+    if cfg!(windows) && matches!(e.raw_os_error(), Some(5 | 32 | 33)) {
+        return true;
+    }
+    matches!(
+        e.kind(),
+        io::ErrorKind::Interrupted | io::ErrorKind::WouldBlock | io::ErrorKind::ResourceBusy
+    )
+}
+
+/// `fs::write` with a short bounded retry on transient errors. Returns the
+/// number of retries needed (0 when the first attempt succeeded).
+pub fn write_retrying(path: &Path, contents: impl AsRef<[u8]>) -> io::Result<u32> {
+    let contents = contents.as_ref();
+    let mut retries = 0;
+    loop {
+        match fs::write(path, contents) {
+            Ok(()) => return Ok(retries),
+            Err(e) if retries + 1 < WRITE_ATTEMPTS && is_transient(&e) => {
+                retries += 1;
+                thread::sleep(Duration::from_millis(100 << retries));
+            }
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+/// Suffix noting retries for a step message, e.g. ` (retried 2 times)`.
+pub fn retried_note(retries: u32) -> String {
+    match retries {
+        0 => String::new(),
+        1 => " (retried once)".to_string(),
+        n => format!(" (retried {n} times)"),
+    }
+}
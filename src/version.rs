@@ -0,0 +1,117 @@
+//! Cursor version parsing and compatibility checks.
+
+use std::fmt;
+
+/// Highest Cursor IDE version nupatch has been validated against.
+pub const LAST_TESTED_CURSOR: &str = "2.5.26";
+
+/// A lenient `major.minor.patch` version. Missing components are zero and
+/// any non-numeric suffix (`-insider`, `+build`) is ignored.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Version {
+    pub major: u64,
+    pub minor: u64,
+    pub patch: u64,
+}
+
+impl Version {
+    /// Parse a version string such as `2.5.26`, `v2.5`, or `2.6.0-nightly`.
+    /// Returns `None` if there is no leading numeric component.
+    pub fn parse(s: &str) -> Option<Self> {
+        let s = s.trim().trim_start_matches('v');
+        let mut parts = [0u64; 3];
+        let mut found = false;
+        for (slot, part) in parts.iter_mut().zip(s.split('.')) {
+            let digits: String = part.chars().take_while(char::is_ascii_digit).collect();
+            if digits.is_empty() {
+                break;
+            }
+            *slot = digits.parse().ok()?;
+            found = true;
+            if digits.len() != part.len() {
+                break;
+            }
+        }
+        found.then_some(Self {
+            major: parts[0],
+            minor: parts[1],
+            patch: parts[2],
+        })
+    }
+}
+
+impl std::str::FromStr for Version {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Self::parse(s).ok_or_else(|| format!("'{s}' is not a version (expected e.g. 2.5.26)"))
+    }
+}
+
+impl fmt::Display for Version {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}.{}.{}", self.major, self.minor, self.patch)
+    }
+}
+
+/// Whether `detected` is newer than the last tested Cursor version.
+/// Returns `None` when the detected version can't be parsed.
+pub fn is_newer_than_tested(detected: &str) -> Option<bool> {
+    let detected = Version::parse(detected)?;
+    let tested = Version::parse(LAST_TESTED_CURSOR)?;
+    Some(detected > tested)
+}
+
+// ---------------------------------------------------------------------------
+//  Compatibility table
+// ---------------------------------------------------------------------------
+
+/// Known patch compatibility for a range of Cursor versions.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Compat {
+    /// All patches apply and nushell works end to end.
+    Works,
+    /// Patches apply but with a known limitation.
+    Partial(&'static str),
+    /// Patterns changed; this nupatch release cannot patch it.
+    #[allow(dead_code)] // no known-broken release yet
+    Broken(&'static str),
+}
+
+/// Inclusive `min` .. inclusive `max` range of Cursor versions.
+pub struct VersionReq {
+    pub min: &'static str,
+    pub max: &'static str,
+}
+
+impl VersionReq {
+    fn matches(&self, v: Version) -> bool {
+        match (Version::parse(self.min), Version::parse(self.max)) {
+            (Some(min), Some(max)) => min <= v && v <= max,
+            _ => false,
+        }
+    }
+}
+
+/// Known-good / known-broken IDE versions, oldest first. Append a row per
+/// verified release.
+const COMPAT_TABLE: &[(VersionReq, Compat)] = &[
+    (
+        VersionReq { min: "2.4.37", max: "2.5.25" },
+        Compat::Partial(
+            "system nu detection is unreachable on Windows; \
+             relies on the configured default terminal",
+        ),
+    ),
+    (VersionReq { min: "2.5.26", max: "2.5.26" }, Compat::Works),
+];
+
+/// Look up the known compatibility of a Cursor version. Returns `None` for
+/// unparseable or untested versions.
+pub fn compat_for(detected: &str) -> Option<Compat> {
+    let v = Version::parse(detected)?;
+    COMPAT_TABLE
+        .iter()
+        .find(|(req, _)| req.matches(v))
+        .map(|&(_, compat)| compat)
+}